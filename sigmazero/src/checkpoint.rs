@@ -0,0 +1,269 @@
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "tch")]
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    game::{FeatureEncoder, Game},
+    learning::Losses,
+};
+#[cfg(feature = "tch")]
+use crate::policy::{restore_config, NNAgent, CONFIG_VAR};
+#[cfg(feature = "tch")]
+use tch::{nn, TchError, Tensor};
+
+/// Name of the manifest among the tensors of a checkpoint
+pub const MANIFEST_VAR: &str = "manifest";
+
+/// Why a manifest couldn't be read, or doesn't fit the network it's meant for
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestError {
+    Format(String),
+    Mismatch(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Format(message) | Self::Mismatch(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+#[cfg(feature = "tch")]
+impl From<ManifestError> for TchError {
+    fn from(error: ManifestError) -> Self {
+        match error {
+            ManifestError::Format(message) => TchError::FileFormat(message),
+            ManifestError::Mismatch(message) => TchError::Shape(message),
+        }
+    }
+}
+
+/// What a checkpoint's weights are for and where they came from. Saved as text next to
+/// the weights, one `key: value` per line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// `NNAgent::architecture` of the network
+    pub architecture: String,
+    /// The numbers under `CONFIG_VAR`, empty for networks without a config
+    pub config: Vec<f32>,
+    /// `FeatureEncoder::NAME` and `SHAPE` of the input
+    pub encoding: String,
+    pub features_shape: Vec<i64>,
+    /// `N`, the size of the policy
+    pub policy_size: usize,
+    pub wdl_head: bool,
+    /// How many rounds of training led here, 0 for a network trained from scratch
+    pub generation: usize,
+    /// Checkpoint this one was trained on from
+    pub parent: Option<String>,
+    /// Replay files it was trained on
+    pub replay_sources: Vec<String>,
+    /// Losses of the last epoch of training
+    pub losses: Option<Losses>,
+}
+
+impl Manifest {
+    /// Describes the network `A` builds in `vs`, as a first generation without a
+    /// training history
+    #[cfg(feature = "tch")]
+    pub fn new<A: NNAgent<G, N>, G: Game<N>, const N: usize>(vs: &nn::VarStore) -> Self {
+        let config = match vs.variables().remove(CONFIG_VAR) {
+            Some(config) => Vec::<f32>::try_from(&config).expect("Config conversion from tensor to vec failed!"),
+            None => Vec::new(),
+        };
+        Self {
+            architecture: A::architecture(vs),
+            config,
+            encoding: A::Encoder::NAME.to_string(),
+            features_shape: A::Encoder::SHAPE.to_vec(),
+            policy_size: N,
+            wdl_head: A::WDL_HEAD,
+            generation: 0,
+            parent: None,
+            replay_sources: Vec::new(),
+            losses: None,
+        }
+    }
+
+    /// Checks that `A` reads the same features, gives the same outputs and builds the
+    /// same architecture as the network the manifest describes
+    #[cfg(feature = "tch")]
+    pub fn check<A: NNAgent<G, N>, G: Game<N>, const N: usize>(&self) -> Result<(), TchError> {
+        self.check_features::<A::Encoder, G, N>()?;
+        if self.wdl_head != A::WDL_HEAD {
+            return Err(TchError::Shape(format!(
+                "checkpoint {} a WDL value head",
+                if self.wdl_head { "has" } else { "doesn't have" }
+            )));
+        }
+        if !A::builds_architecture(&self.architecture) {
+            return Err(TchError::Shape(format!(
+                "checkpoint holds a {:?} network, which {} doesn't build",
+                self.architecture,
+                std::any::type_name::<A>()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that the network reads features made by `E` and gives a policy for `G`
+    pub fn check_features<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(&self) -> Result<(), ManifestError> {
+        if self.policy_size != N {
+            return Err(ManifestError::Mismatch(format!(
+                "checkpoint has a policy of {} moves, expected {}",
+                self.policy_size, N
+            )));
+        }
+        if self.encoding != E::NAME || self.features_shape != E::SHAPE {
+            return Err(ManifestError::Mismatch(format!(
+                "checkpoint reads {:?} features of shape {:?}, expected {:?} of shape {:?}",
+                self.encoding,
+                self.features_shape,
+                E::NAME,
+                E::SHAPE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads the manifest of the checkpoint at `path`
+    #[cfg(feature = "tch")]
+    pub fn load(path: &Path) -> Result<Self, TchError> {
+        let tensors = Tensor::load_multi(path)?;
+        let (_, bytes) = tensors
+            .iter()
+            .find(|(name, _)| name == MANIFEST_VAR)
+            .ok_or_else(|| TchError::FileFormat(format!("{:?} has no manifest", path)))?;
+        String::from_utf8(Vec::<u8>::try_from(bytes)?)
+            .map_err(|e| TchError::Convert(e.to_string()))?
+            .parse()
+            .map_err(TchError::from)
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values.iter().map(T::to_string).collect::<Vec<_>>().join(" ")
+}
+
+fn split<T: FromStr>(value: &str) -> Result<Vec<T>, ManifestError> {
+    value
+        .split_whitespace()
+        .map(|v| v.parse().map_err(|_| ManifestError::Format(format!("invalid number {:?} in manifest", v))))
+        .collect()
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "architecture: {}", self.architecture)?;
+        writeln!(f, "config: {}", join(&self.config))?;
+        writeln!(f, "encoding: {}", self.encoding)?;
+        writeln!(f, "features_shape: {}", join(&self.features_shape))?;
+        writeln!(f, "policy_size: {}", self.policy_size)?;
+        writeln!(f, "wdl_head: {}", self.wdl_head)?;
+        writeln!(f, "generation: {}", self.generation)?;
+        if let Some(parent) = &self.parent {
+            writeln!(f, "parent: {}", parent)?;
+        }
+        for source in &self.replay_sources {
+            writeln!(f, "replay_source: {}", source)?;
+        }
+        if let Some(losses) = &self.losses {
+            writeln!(
+                f,
+                "losses: {}",
+                join(&[losses.train_policy, losses.train_value, losses.test_policy, losses.test_value])
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = ManifestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |key: &str, value: &str| ManifestError::Format(format!("invalid {} {:?} in manifest", key, value));
+        let mut architecture = None;
+        let mut config = Vec::new();
+        let mut encoding = None;
+        let mut features_shape = None;
+        let mut policy_size = None;
+        let mut wdl_head = false;
+        let mut generation = 0;
+        let mut parent = None;
+        let mut replay_sources = Vec::new();
+        let mut losses = None;
+        for line in s.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line
+                .split_once(": ")
+                .or_else(|| line.split_once(':'))
+                .ok_or_else(|| ManifestError::Format(format!("invalid manifest line {:?}", line)))?;
+            match key {
+                "architecture" => architecture = Some(value.to_string()),
+                "config" => config = split(value)?,
+                "encoding" => encoding = Some(value.to_string()),
+                "features_shape" => features_shape = Some(split(value)?),
+                "policy_size" => policy_size = Some(value.parse().map_err(|_| invalid(key, value))?),
+                "wdl_head" => wdl_head = value.parse().map_err(|_| invalid(key, value))?,
+                "generation" => generation = value.parse().map_err(|_| invalid(key, value))?,
+                "parent" => parent = Some(value.to_string()),
+                "replay_source" => replay_sources.push(value.to_string()),
+                "losses" => match split::<f64>(value)?[..] {
+                    [train_policy, train_value, test_policy, test_value] => {
+                        losses = Some(Losses { train_policy, train_value, test_policy, test_value })
+                    }
+                    _ => return Err(invalid(key, value)),
+                },
+                // Left for newer versions to add to
+                _ => (),
+            }
+        }
+        let missing = |key: &str| ManifestError::Format(format!("manifest has no {}", key));
+        Ok(Self {
+            architecture: architecture.ok_or_else(|| missing("architecture"))?,
+            config,
+            encoding: encoding.ok_or_else(|| missing("encoding"))?,
+            features_shape: features_shape.ok_or_else(|| missing("features_shape"))?,
+            policy_size: policy_size.ok_or_else(|| missing("policy_size"))?,
+            wdl_head,
+            generation,
+            parent,
+            replay_sources,
+            losses,
+        })
+    }
+}
+
+/// Saves the weights in `vs` together with `manifest`
+#[cfg(feature = "tch")]
+pub fn save_checkpoint(vs: &nn::VarStore, manifest: &Manifest, path: &Path) -> Result<(), TchError> {
+    let manifest = Tensor::from_slice(manifest.to_string().as_bytes());
+    let variables = vs.variables();
+    let mut named_tensors: Vec<(&str, &Tensor)> = variables
+        .iter()
+        .map(|(name, tensor)| (name.as_str(), tensor))
+        .collect();
+    named_tensors.push((MANIFEST_VAR, &manifest));
+    Tensor::save_multi(&named_tensors, path)
+}
+
+/// Builds `A` from the checkpoint at `path` once its manifest says `A` can read it,
+/// returning the agent with the `VarStore` holding its weights
+#[cfg(feature = "tch")]
+pub fn load_checkpoint<A: NNAgent<G, N>, G: Game<N>, const N: usize>(
+    path: &Path,
+    device: tch::Device,
+) -> Result<(A, nn::VarStore, Manifest), TchError> {
+    let manifest = Manifest::load(path)?;
+    manifest.check::<A, G, N>()?;
+    let mut vs = nn::VarStore::new(device);
+    restore_config(&vs, path)?;
+    let agent = A::new(&vs);
+    vs.load(path)?;
+    Ok((agent, vs, manifest))
+}
@@ -1,31 +1,41 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+#[cfg(feature = "tch")]
 use std::path::Path;
 
 use crate::{
     game::Game,
-    policy::{self, RawPolicy},
+    policy::{RawPolicy, Wdl},
 };
+#[cfg(feature = "tch")]
+use crate::game::{DefaultFeatures, FeatureEncoder};
+#[cfg(feature = "tch")]
 use tch::{Device, IndexOp, Kind, TchError, Tensor};
 
 #[derive(Clone, Debug)]
 pub struct ReplayBuffer<G: Game<N>, const N: usize> {
     pub games: Vec<G>,
     pub values: Vec<f32>,
+    /// How each game ended for the player to move, the targets of WDL value heads
+    pub outcomes: Vec<Wdl>,
     pub policies: Vec<RawPolicy<N>>,
 }
 
 impl<G: Game<N>, const N: usize> ReplayBuffer<G, N> {
-    pub fn new(games: Vec<G>, values: Vec<f32>, policies: Vec<RawPolicy<N>>) -> Self {
-        if !(games.len() == values.len() && values.len() == policies.len()) {
+    pub fn new(games: Vec<G>, values: Vec<f32>, outcomes: Vec<Wdl>, policies: Vec<RawPolicy<N>>) -> Self {
+        if !(games.len() == values.len() && values.len() == outcomes.len() && values.len() == policies.len()) {
             panic!(
-                "Game, Value and Policy counts don't equal: {}, {}, {}",
+                "Game, Value, Outcome and Policy counts don't equal: {}, {}, {}, {}",
                 games.len(),
                 values.len(),
+                outcomes.len(),
                 policies.len()
             )
         }
         Self {
             games,
             values,
+            outcomes,
             policies,
         }
     }
@@ -42,18 +52,21 @@ impl<G: Game<N>, const N: usize> ReplayBuffer<G, N> {
         &mut self,
         games: &mut Vec<G>,
         values: &mut Vec<f32>,
+        outcomes: &mut Vec<Wdl>,
         policies: &mut Vec<RawPolicy<N>>,
     ) {
-        if !(games.len() == values.len() && values.len() == policies.len()) {
+        if !(games.len() == values.len() && values.len() == outcomes.len() && values.len() == policies.len()) {
             panic!(
-                "Game, Value and Policy counts don't equal: {}, {}, {}",
+                "Game, Value, Outcome and Policy counts don't equal: {}, {}, {}, {}",
                 games.len(),
                 values.len(),
+                outcomes.len(),
                 policies.len()
             )
         }
         self.games.append(games);
         self.values.append(values);
+        self.outcomes.append(outcomes);
         self.policies.append(policies);
     }
 
@@ -61,35 +74,109 @@ impl<G: Game<N>, const N: usize> ReplayBuffer<G, N> {
         self.games.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
     pub fn augmented(&self) -> Self {
         let mut augmented = Self::default();
         for i in 0..self.games.len() {
             let (mut aug_games, mut aug_policies) = self.games[i].augmented_with_raw_policy(&self.policies[i]);
             let mut aug_values = vec![self.values[i]; aug_games.len()];
-            augmented.append(&mut aug_games, &mut aug_values, &mut aug_policies);
+            let mut aug_outcomes = vec![self.outcomes[i]; aug_games.len()];
+            augmented.append(&mut aug_games, &mut aug_values, &mut aug_outcomes, &mut aug_policies);
         }
         augmented
     }
 }
 
+impl<G: Game<N> + Eq + Hash, const N: usize> ReplayBuffer<G, N> {
+    /// Merges positions that are symmetric images of each other into their canonical
+    /// form, averaging values, outcomes and policies over the duplicates.
+    pub fn deduplicated(&self) -> Self {
+        let mut indices = HashMap::<G, usize>::new();
+        let mut games = Vec::new();
+        let mut value_sums = Vec::<f32>::new();
+        let mut outcome_sums = Vec::<[f32; 3]>::new();
+        let mut policy_sums = Vec::<[f32; N]>::new();
+        let mut counts = Vec::<usize>::new();
+        for ((game, value, policy), outcome) in self.iter().zip(&self.outcomes) {
+            let (canonical, symmetry) = game.canonical();
+            let policy = G::transform_raw_policy(policy, symmetry);
+            let i = *indices.entry(canonical).or_insert_with(|| {
+                games.push(canonical);
+                value_sums.push(0.0);
+                outcome_sums.push([0.0; 3]);
+                policy_sums.push([0.0; N]);
+                counts.push(0);
+                games.len() - 1
+            });
+            value_sums[i] += value;
+            for (sum, p) in outcome_sums[i].iter_mut().zip(outcome.to_array()) {
+                *sum += p;
+            }
+            for (sum, p) in policy_sums[i].iter_mut().zip(policy.iter()) {
+                *sum += p;
+            }
+            counts[i] += 1;
+        }
+        let values = value_sums
+            .iter()
+            .zip(&counts)
+            .map(|(v, &n)| v / n as f32)
+            .collect();
+        let outcomes = outcome_sums
+            .into_iter()
+            .zip(&counts)
+            .map(|([win, draw, loss], &n)| Wdl {
+                win: win / n as f32,
+                draw: draw / n as f32,
+                loss: loss / n as f32,
+            })
+            .collect();
+        let policies = policy_sums
+            .into_iter()
+            .zip(&counts)
+            .map(|(p, &n)| RawPolicy::new(p.map(|x| x / n as f32)))
+            .collect();
+        Self::new(games, values, outcomes, policies)
+    }
+}
+
 impl<G: Game<N>, const N: usize> Default for ReplayBuffer<G, N> {
     fn default() -> Self {
         Self {
             games: Vec::new(),
             values: Vec::new(),
+            outcomes: Vec::new(),
             policies: Vec::new(),
         }
     }
 }
 
+#[cfg(feature = "tch")]
 pub struct ReplayBufferTensorData {
     pub features: tch::Tensor,
     pub policy_value: tch::Tensor,
+    /// The legal moves of each position, `[B, N]` bools
+    pub legal_mask: tch::Tensor,
+    /// Win, draw and loss one-hots for the player to move, `[B, 3]`
+    pub outcomes: tch::Tensor,
+    /// `FeatureEncoder::NAME` of the encoding that made `features`
+    encoding: String,
     device: tch::Device,
 }
 
+#[cfg(feature = "tch")]
 impl<G: Game<N>, const N: usize> From<ReplayBuffer<G, N>> for ReplayBufferTensorData {
     fn from(buffer: ReplayBuffer<G, N>) -> Self {
+        Self::encode::<DefaultFeatures, G, N>(buffer)
+    }
+}
+
+#[cfg(feature = "tch")]
+impl ReplayBufferTensorData {
+    pub fn encode<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(buffer: ReplayBuffer<G, N>) -> Self {
         let policies = tch::Tensor::stack(
             &buffer
                 .policies
@@ -100,43 +187,51 @@ impl<G: Game<N>, const N: usize> From<ReplayBuffer<G, N>> for ReplayBufferTensor
         )
         .to_dtype(tch::Kind::Float, false, false);
         let values = tch::Tensor::from_slice(&buffer.values)
-            .reshape(&[buffer.values.len() as i64, 1])
+            .reshape([buffer.values.len() as i64, 1])
             .to_dtype(tch::Kind::Float, false, false);
-        assert_eq!(policies.size()[1], 81);
-        Self {
-            features: tch::Tensor::stack(
-                &buffer
-                    .games
-                    .into_iter()
-                    .map(|g| g.features())
-                    .collect::<Vec<_>>(),
-                0,
-            )
-            .to_dtype(tch::Kind::Float, false, false),
+        assert_eq!(policies.size()[1], N as i64);
+        let outcomes: Vec<f32> = buffer.outcomes.iter().flat_map(Wdl::to_array).collect();
+        let data = Self {
+            features: E::encode_batch(&buffer.games),
             policy_value: tch::Tensor::cat(&[policies, values], 1),
+            legal_mask: G::legal_masks(&buffer.games),
+            outcomes: tch::Tensor::from_slice(&outcomes).reshape([buffer.outcomes.len() as i64, 3]),
+            encoding: E::NAME.to_string(),
             device: Device::Cpu,
+        };
+        if let Err(e) = data.check_encoding::<E, G, N>() {
+            panic!("Features don't match the encoding's declared shape: {}", e);
         }
+        data
     }
-}
 
-impl ReplayBufferTensorData {
     pub fn random_split(&self, fraction: f32) -> (Self, Self) {
         let n = self.features.size()[0];
         let left_split_length = (n as f32 * fraction).ceil() as i64;
         let index = Tensor::randperm(n, (Kind::Int64, self.device));
         let mut features = self.features.detach_copy();
         let mut policy_value = self.policy_value.detach_copy();
+        let mut legal_mask = self.legal_mask.detach_copy();
+        let mut outcomes = self.outcomes.detach_copy();
         features = features.index_select(0, &index);
         policy_value = policy_value.index_select(0, &index);
+        legal_mask = legal_mask.index_select(0, &index);
+        outcomes = outcomes.index_select(0, &index);
         (
             Self {
                 features: features.i(..left_split_length),
                 policy_value: policy_value.i(..left_split_length),
+                legal_mask: legal_mask.i(..left_split_length),
+                outcomes: outcomes.i(..left_split_length),
+                encoding: self.encoding.clone(),
                 device: self.device,
             },
             Self {
                 features: features.i(left_split_length..),
                 policy_value: policy_value.i(left_split_length..),
+                legal_mask: legal_mask.i(left_split_length..),
+                outcomes: outcomes.i(left_split_length..),
+                encoding: self.encoding.clone(),
                 device: self.device,
             },
         )
@@ -147,45 +242,134 @@ impl ReplayBufferTensorData {
             &[
                 ("features", &self.features),
                 ("policy_value", &self.policy_value),
+                ("legal_mask", &self.legal_mask),
+                ("outcomes", &self.outcomes),
+                ("encoding", &Tensor::from_slice(self.encoding.as_bytes())),
             ],
             path,
         )
     }
 
-    pub fn load_from_file(path: &Path, device: tch::Device) -> Result<Self, TchError> {
+    /// Checks that the tensors hold features made by `E` and policies for `G`
+    pub fn check_encoding<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(&self) -> Result<(), TchError> {
+        if self.encoding != E::NAME {
+            return Err(TchError::Shape(format!(
+                "features were encoded with {:?}, expected {:?}",
+                self.encoding,
+                E::NAME
+            )));
+        }
+        if G::FEATURES_SHAPE.iter().product::<i64>() != G::FEATURES_SIZE {
+            return Err(TchError::Shape(format!(
+                "game features of shape {:?} don't have size {}",
+                G::FEATURES_SHAPE,
+                G::FEATURES_SIZE
+            )));
+        }
+        let features_shape = self.features.size();
+        if features_shape.get(1..) != Some(E::SHAPE) {
+            return Err(TchError::Shape(format!(
+                "features have shape {:?}, expected [_, {:?}]",
+                features_shape,
+                E::SHAPE
+            )));
+        }
+        let policy_value_shape = self.policy_value.size();
+        if policy_value_shape.get(1..) != Some(&[N as i64 + 1][..]) {
+            return Err(TchError::Shape(format!(
+                "policy_value has shape {:?}, expected [_, {}]",
+                policy_value_shape,
+                N + 1
+            )));
+        }
+        let legal_mask_shape = self.legal_mask.size();
+        if legal_mask_shape.get(1..) != Some(&[N as i64][..]) {
+            return Err(TchError::Shape(format!(
+                "legal_mask has shape {:?}, expected [_, {}]",
+                legal_mask_shape,
+                N
+            )));
+        }
+        let outcomes_shape = self.outcomes.size();
+        if outcomes_shape.get(1..) != Some(&[3][..]) {
+            return Err(TchError::Shape(format!(
+                "outcomes have shape {:?}, expected [_, 3]",
+                outcomes_shape
+            )));
+        }
+        Ok(())
+    }
+
+    /// Loads data saved for `G` with the encoding `E`, failing if it was made for
+    /// another game or encoding
+    pub fn load_from_file<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(path: &Path, device: tch::Device) -> Result<Self, TchError> {
         let tensors = Tensor::load_multi(path)?;
         let features = tensors
             .iter()
             .find(|(name, _)| name == "features")
-            .expect(&format!("`features` tensor not found in {:?}", path))
+            .unwrap_or_else(|| panic!("`features` tensor not found in {:?}", path))
             .1
             .to_device(device)
             .shallow_clone();
         let policy_value = tensors
             .iter()
             .find(|(name, _)| name == "policy_value")
-            .expect(&format!("`policy_value` tensor not found in {:?}", path))
+            .unwrap_or_else(|| panic!("`policy_value` tensor not found in {:?}", path))
             .1
             .to_device(device)
             .shallow_clone();
-        Ok(Self {
+        // Files from before encodings were recorded hold the default one
+        let encoding = match tensors.iter().find(|(name, _)| name == "encoding") {
+            Some((_, bytes)) => String::from_utf8(Vec::<u8>::try_from(bytes)?)
+                .map_err(|e| TchError::Convert(e.to_string()))?,
+            None => <DefaultFeatures as FeatureEncoder<G, N>>::NAME.to_string(),
+        };
+        // Files from before masks were recorded allow every move
+        let legal_mask = match tensors.iter().find(|(name, _)| name == "legal_mask") {
+            Some((_, mask)) => mask.to_device(device).shallow_clone(),
+            None => Tensor::ones([policy_value.size()[0], N as i64], (Kind::Bool, device)),
+        };
+        // Files from before outcomes were recorded only have the values, see `Wdl::from_score`
+        let outcomes = match tensors.iter().find(|(name, _)| name == "outcomes") {
+            Some((_, outcomes)) => outcomes.to_device(device).shallow_clone(),
+            None => {
+                let values = policy_value.narrow(1, N as i64, 1);
+                Tensor::cat(&[values.clamp_min(0.0), 1.0 - values.abs(), (-&values).clamp_min(0.0)], 1)
+            }
+        };
+        let data = Self {
             features,
             policy_value,
+            legal_mask,
+            outcomes,
+            encoding,
             device,
-        })
+        };
+        data.check_encoding::<E, G, N>()?;
+        Ok(data)
     }
 
     pub fn len(&self) -> usize {
         self.features.size()[0] as usize
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn to_device(&mut self, device: tch::Device) {
         self.features = self.features.to(device);
         self.policy_value = self.policy_value.to(device);
+        self.legal_mask = self.legal_mask.to(device);
+        self.outcomes = self.outcomes.to(device);
         self.device = device;
     }
 
     pub fn device(&self) -> tch::Device {
         self.device
     }
+
+    pub fn encoding(&self) -> &str {
+        &self.encoding
+    }
 }
@@ -1,9 +1,5 @@
-use std::ops::Index;
-
-use crate::{game::{Game, GameStatus, Player}, mcts::MCTS, policy::Agent};
+use crate::{game::{Game, GameStatus, Player}, mcts::{resolve_chance, MCTS}, policy::Agent};
 use indicatif::{ProgressIterator, ProgressStyle};
-use ego_tree::NodeId;
-use tch::display::PrinterOptions;
 
 #[derive(Debug, Default)]
 pub struct EvaluationResults {
@@ -12,55 +8,71 @@ pub struct EvaluationResults {
     pub draws: usize,
 }
 
-pub fn evaluate_agents<G: Game<N>, const N: usize, A1: Agent<G, N>, A2: Agent<G, N>>(agent1: &mut A1, agent2: &mut A2, n_games: usize, search_steps: usize, verbose: bool) -> EvaluationResults {
-    let mut results = EvaluationResults::default();
+pub fn evaluate_agents<G: Game<N>, const N: usize, A1: Agent<G, N>, A2: Agent<G, N>>(agent1: &mut A1, agent2: &mut A2, n_games: usize, search_steps: usize, draw_contempt: f32, verbose: bool) -> EvaluationResults {
+    evaluate_agents_from(G::default(), agent1, agent2, n_games, search_steps, draw_contempt, verbose)
+}
+
+/// Like `evaluate_agents`, with every game starting from `start`
+pub fn evaluate_agents_from<G: Game<N>, const N: usize, A1: Agent<G, N>, A2: Agent<G, N>>(start: G, agent1: &mut A1, agent2: &mut A2, n_games: usize, search_steps: usize, draw_contempt: f32, verbose: bool) -> EvaluationResults {
+    let results = evaluate_seats_from::<G, N>(start, &mut [agent1, agent2], n_games, search_steps, draw_contempt, verbose);
+    EvaluationResults {
+        agent1_wins: results.wins[0],
+        agent2_wins: results.wins[1],
+        draws: results.draws,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SeatResults {
+    /// Indexed like the agents, each seat's wins
+    pub wins: Vec<usize>,
+    pub draws: usize,
+}
+
+/// Plays games with `agents[i]` moving for `Player::PLAYERS[i]`, for any number of players.
+/// Every agent searches with `draw_contempt` for its own draws.
+pub fn evaluate_seats<G: Game<N>, const N: usize>(agents: &mut [&mut dyn Agent<G, N>], n_games: usize, search_steps: usize, draw_contempt: f32, verbose: bool) -> SeatResults {
+    evaluate_seats_from(G::default(), agents, n_games, search_steps, draw_contempt, verbose)
+}
+
+/// Like `evaluate_seats`, with every game starting from `start`
+pub fn evaluate_seats_from<G: Game<N>, const N: usize>(start: G, agents: &mut [&mut dyn Agent<G, N>], n_games: usize, search_steps: usize, draw_contempt: f32, verbose: bool) -> SeatResults {
+    if agents.len() != G::Player::PLAYERS.len() {
+        panic!("{} agents for {} players", agents.len(), G::Player::PLAYERS.len());
+    }
+    let mut results = SeatResults {
+        wins: vec![0; agents.len()],
+        draws: 0,
+    };
 
+    let mut rng = rand::thread_rng();
     let progress_style = ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} games").unwrap();
     for _ in (0..n_games).progress_with_style(progress_style).with_finish(indicatif::ProgressFinish::Abandon) {
-        let mut game = G::default();
+        let mut game = start;
 
         loop {
+            game = resolve_chance(&game, &mut rng);
             match game.status() {
                 GameStatus::InProgress { player } => {
-                    if player == &G::Player::PLAYERS[0] {
-                        // Agent 1's turn
-                        let mut mcts1 = MCTS::<G, A1, N>::from_root_game_state(game.clone(), agent1);
-                        
-                        // Perform search steps
-                        for _ in 0..search_steps {
-                            let node_chain = mcts1.select();
-                            if let Some(last_node) = node_chain.last() {
-                                let value = mcts1.expand(*last_node);
-                                mcts1.backup(node_chain, value);
-                            }
-                        }
-                        
-                        
-                        game = mcts1.select_best_child().0.game_state.clone();
-                    } else {
-                        // Agent 2's turn
-                        let mut mcts2 = MCTS::<G, A2, N>::from_root_game_state(game.clone(), agent2);
-                        
-                        // Perform search steps
-                        for _ in 0..search_steps {
-                            let node_chain = mcts2.select();
-                            if let Some(last_node) = node_chain.last() {
-                                let value = mcts2.expand(*last_node);
-                                mcts2.backup(node_chain, value);
-                            }
+                    let agent = &mut *agents[player.index()];
+                    let mut mcts = MCTS::<G, dyn Agent<G, N>, N>::from_root_game_state(game, agent)
+                        .with_draw_contempt(draw_contempt);
+
+                    // Perform search steps
+                    for _ in 0..search_steps {
+                        let node_chain = mcts.select();
+                        if let Some(last_node) = node_chain.last() {
+                            let value = mcts.expand(*last_node);
+                            mcts.backup(node_chain, value);
                         }
-                        
-                        let (best_child, policy) = mcts2.select_best_child();
-                        // println!("{}", game);
-                        // println!("Agent 2 policy\n{}", G::displays(policy.format_to_print()));
-                        game = best_child.game_state.clone();
-                    };
+                    }
+
+                    game = mcts.select_best_child().0.game_state;
 
                     if verbose {
                         print!("{esc}c", esc = 27 as char);
                         println!("{}", game);
                     }
-
                 },
                 GameStatus::Draw => {
                     results.draws += 1;
@@ -70,11 +82,7 @@ pub fn evaluate_agents<G: Game<N>, const N: usize, A1: Agent<G, N>, A2: Agent<G,
                     break;
                 }
                 GameStatus::Won { player } => {
-                    if player == &G::Player::PLAYERS[0] {
-                        results.agent1_wins += 1;
-                    } else {
-                        results.agent2_wins += 1;
-                    }
+                    results.wins[player.index()] += 1;
                     if verbose {
                         println!("Result: Player {:?} won", player);
                     }
@@ -84,4 +92,4 @@ pub fn evaluate_agents<G: Game<N>, const N: usize, A1: Agent<G, N>, A2: Agent<G,
         }
     }
     results
-}
\ No newline at end of file
+}
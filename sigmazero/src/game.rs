@@ -6,6 +6,7 @@ use crate::policy::RawPolicy;
 pub trait Position:
     PartialEq + Clone + Copy + fmt::Debug + fmt::Display + From<usize> + Into<usize>
 {
+    /// From board coordinates. Games whose actions only pick a column may ignore `y`.
     fn new(x: u8, y: u8) -> Self;
     fn is_valid(&self) -> bool;
 }
@@ -32,35 +33,62 @@ impl<P: Position> DerefMut for PositionList<P> {
     }
 }
 
-pub trait Player: fmt::Debug + Clone + Copy + PartialEq + Default + fmt::Display {
-    const PLAYERS: [Self; 2];
+pub trait Player: fmt::Debug + Clone + Copy + PartialEq + Default + fmt::Display + 'static {
+    /// Every player, in turn order. A single player is fine for puzzles.
+    const PLAYERS: &'static [Self];
 
-    fn other_player(&self) -> Self;
+    /// Position in `PLAYERS`, which also indexes per-player value vectors
+    fn index(&self) -> usize {
+        Self::PLAYERS
+            .iter()
+            .position(|p| p == self)
+            .expect("Player missing from PLAYERS")
+    }
+
+    /// Whose turn it is next when turns simply go round
+    fn next_player(&self) -> Self {
+        Self::PLAYERS[(self.index() + 1) % Self::PLAYERS.len()]
+    }
+
+    /// `value` for this player, with the opposite shared equally among the others
+    fn zero_sum_values(&self, value: f32) -> Vec<f32> {
+        let n = Self::PLAYERS.len();
+        let others = if n > 1 { -value / (n - 1) as f32 } else { 0.0 };
+        (0..n)
+            .map(|i| if i == self.index() { value } else { others })
+            .collect()
+    }
+}
+
+/// An element of a game's symmetry group, acting on positions and policies.
+pub trait Symmetry: fmt::Debug + Clone + Copy + PartialEq {
+    const IDENTITY: Self;
+
+    fn inverse(&self) -> Self;
 }
 
-#[derive(Debug, Clone, Copy)]
+/// For games without symmetries
+impl Symmetry for () {
+    const IDENTITY: Self = ();
+
+    fn inverse(&self) -> Self {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GameStatus<P: Player> {
     InProgress { player: P },
     Won { player: P },
     Draw,
 }
 
-impl<P: Player> From<GameStatus<P>> for f32 {
-    fn from(value: GameStatus<P>) -> Self {
-        match value {
+impl<P: Player> GameStatus<P> {
+    /// 1 if `player` won, -1 if they lost, and 0 for a draw or a game still in progress
+    pub fn value_for(&self, player: P) -> f32 {
+        match self {
             GameStatus::InProgress { player: _ } => 0.0,
             GameStatus::Draw => 0.0,
-            GameStatus::Won { player: _ } => 1.0,
-        }
-    }
-}
-
-impl<P: Player> From<&GameStatus<P>> for f32 {
-    fn from(value: &GameStatus<P>) -> Self {
-        match value {
-            GameStatus::InProgress { player: _ } => 0.0,
-            GameStatus::Draw => 0.0,
-            GameStatus::Won { player: _ } => 1.0,
+            GameStatus::Won { player: winner } if *winner == player => 1.0,
+            GameStatus::Won { player: _ } => -1.0,
         }
     }
 }
@@ -86,6 +114,7 @@ pub trait Game<const N: usize>: Default + Clone + Copy + Display {
 
     type Player: Player;
     type Position: Position;
+    type Symmetry: Symmetry;
 
     fn take_turn(
         &mut self,
@@ -94,6 +123,156 @@ pub trait Game<const N: usize>: Default + Clone + Copy + Display {
     fn valid_moves(&self) -> PositionList<Self::Position>;
     fn status(&self) -> &GameStatus<Self::Player>;
     fn displays(items: Vec<String>) -> impl Display;
-    fn features(&self) -> tch::Tensor;
+    /// Writes the network inputs seen from `player`'s side into `out`, which has room for
+    /// `FEATURES_SIZE` floats laid out as `FEATURES_SHAPE`. Defined for finished games too.
+    fn write_features_for(&self, player: Self::Player, out: &mut [f32]);
+
+    /// `write_features_for(player)` as a tensor of shape `FEATURES_SHAPE`
+    #[cfg(feature = "tch")]
+    fn features_for(&self, player: Self::Player) -> tch::Tensor {
+        let mut features = vec![0.0f32; Self::FEATURES_SIZE as usize];
+        self.write_features_for(player, &mut features);
+        tch::Tensor::from_slice(&features).reshape(Self::FEATURES_SHAPE)
+    }
     fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<N>) -> (Vec<Self>, Vec<RawPolicy<N>>);
+
+    /// The player whose turn it is, or None once the game is over.
+    fn to_move(&self) -> Option<Self::Player> {
+        match self.status() {
+            GameStatus::InProgress { player } => Some(*player),
+            _ => None,
+        }
+    }
+
+    /// Whose side `features` takes: the player to move, or once the game is over whoever
+    /// would have been next. By default that's the one after the winner, and the first
+    /// player after a draw; games where that's not who moved last should override it.
+    fn perspective(&self) -> Self::Player {
+        match self.status() {
+            GameStatus::InProgress { player } => *player,
+            GameStatus::Won { player } => player.next_player(),
+            GameStatus::Draw => Self::Player::PLAYERS[0],
+        }
+    }
+
+    /// Network inputs from the side of `perspective`, so every position of a record can
+    /// be encoded, the final one included.
+    #[cfg(feature = "tch")]
+    fn features(&self) -> tch::Tensor {
+        self.features_for(self.perspective())
+    }
+
+    /// The result of the game for `player`, see `GameStatus::value_for`.
+    fn outcome_for(&self, player: Self::Player) -> f32 {
+        self.status().value_for(player)
+    }
+
+    /// The result of the game for every player, indexed like `Player::PLAYERS`.
+    fn outcomes(&self) -> Vec<f32> {
+        Self::Player::PLAYERS
+            .iter()
+            .map(|&player| self.outcome_for(player))
+            .collect()
+    }
+
+    /// Which of the `N` actions are legal here. None are once the game is over.
+    fn legal_mask(&self) -> [bool; N] {
+        let mut mask = [false; N];
+        if self.to_move().is_some() {
+            for position in self.valid_moves().iter() {
+                mask[(*position).into()] = true;
+            }
+        }
+        mask
+    }
+
+    /// `legal_mask` as a bool tensor of shape `[N]`
+    #[cfg(feature = "tch")]
+    fn legal_mask_tensor(&self) -> tch::Tensor {
+        tch::Tensor::from_slice(&self.legal_mask())
+    }
+
+    /// The legal masks of all `games` as one `[B, N]` bool tensor
+    #[cfg(feature = "tch")]
+    fn legal_masks(games: &[Self]) -> tch::Tensor {
+        let masks: Vec<bool> = games.iter().flat_map(|game| game.legal_mask()).collect();
+        tch::Tensor::from_slice(&masks).reshape([games.len() as i64, N as i64])
+    }
+
+    /// For stochastic games, the positions chance can lead to from here and their
+    /// probabilities. Positions waiting on chance have no moves. None for decisions.
+    fn chance_outcomes(&self) -> Option<Vec<(Self, f32)>> {
+        None
+    }
+
+    /// The canonical image of this position under the game's symmetries, and the
+    /// symmetry that maps this position onto it.
+    fn canonical(&self) -> (Self, Self::Symmetry) {
+        (*self, Self::Symmetry::IDENTITY)
+    }
+
+    /// Moves each action's probability to where `symmetry` sends that action.
+    fn transform_raw_policy(raw_policy: &RawPolicy<N>, _symmetry: Self::Symmetry) -> RawPolicy<N> {
+        raw_policy.clone()
+    }
+}
+
+/// Turns positions into network inputs. A game can offer several encodings, and each
+/// model picks the one it was built for.
+pub trait FeatureEncoder<G: Game<N>, const N: usize> {
+    /// Stored with datasets, so they're only read with the encoding that wrote them
+    const NAME: &'static str;
+    const SHAPE: &'static [i64];
+
+    /// Writes the features of `game` into `out`, which has room for the product of `SHAPE`
+    fn encode_into(game: &G, out: &mut [f32]);
+
+    /// `encode_into` as a tensor of shape `SHAPE`
+    #[cfg(feature = "tch")]
+    fn encode(game: &G) -> tch::Tensor {
+        Self::encode_batch(std::slice::from_ref(game)).squeeze_dim(0)
+    }
+
+    /// The features of all `games` in one `[B, ..SHAPE]` buffer
+    fn encode_batch_into(games: &[G]) -> Vec<f32> {
+        let size = Self::SHAPE.iter().product::<i64>() as usize;
+        let mut buffer = vec![0.0f32; games.len() * size];
+        for (game, out) in games.iter().zip(buffer.chunks_exact_mut(size)) {
+            Self::encode_into(game, out);
+        }
+        buffer
+    }
+
+    /// The features of all `games` as one `[B, ..SHAPE]` tensor, written straight into
+    /// its storage rather than stacked or copied over
+    #[cfg(feature = "tch")]
+    fn encode_batch(games: &[G]) -> tch::Tensor {
+        let size = Self::SHAPE.iter().product::<i64>() as usize;
+        let shape: Vec<i64> = std::iter::once(games.len() as i64)
+            .chain(Self::SHAPE.iter().copied())
+            .collect();
+        let tensor = tch::Tensor::zeros(shape, (tch::Kind::Float, tch::Device::Cpu));
+        if tensor.numel() == 0 {
+            return tensor;
+        }
+        // SAFETY: a new contiguous float tensor on the CPU holds `numel` floats, and
+        // nothing else can see it until it's returned
+        let buffer = unsafe { std::slice::from_raw_parts_mut(tensor.data_ptr() as *mut f32, tensor.numel()) };
+        for (game, out) in games.iter().zip(buffer.chunks_exact_mut(size)) {
+            Self::encode_into(game, out);
+        }
+        tensor
+    }
+}
+
+/// The game's own `Game::features`
+pub struct DefaultFeatures;
+
+impl<G: Game<N>, const N: usize> FeatureEncoder<G, N> for DefaultFeatures {
+    const NAME: &'static str = "default";
+    const SHAPE: &'static [i64] = G::FEATURES_SHAPE;
+
+    fn encode_into(game: &G, out: &mut [f32]) {
+        game.write_features_for(game.perspective(), out)
+    }
 }
@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::cell::RefCell;
+use std::ops::AddAssign;
+use std::path::Path;
+
+use crate::checkpoint::Manifest;
+use crate::quantize::{dot, InputRanges, Int8Weights, Observer, Quantize};
+#[cfg(feature = "tch")]
+use tch::{nn, Kind, TchError};
+
+/// Starts every file written by `Weights::save`
+const MAGIC: &[u8; 8] = b"SZWTS001";
+
+/// What `nn::BatchNorm` and `nn::LayerNorm` add to the variance by default
+const NORM_EPS: f32 = 1e-5;
+
+thread_local! {
+    /// The int8 inputs of a layer and, for convolutions, their patches, kept between
+    /// layers and positions so the int8 kernels don't allocate
+    static INT8_SCRATCH: RefCell<(Vec<i8>, Vec<i8>)> = RefCell::default();
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Floats in row-major order, for running a single position through a network on the
+/// CPU without libtorch
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuTensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl CpuTensor {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Self {
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            data.len(),
+            "{} numbers don't fill shape {:?}",
+            data.len(),
+            shape
+        );
+        Self { shape, data }
+    }
+
+    pub fn zeros(shape: Vec<usize>) -> Self {
+        let size = shape.iter().product();
+        Self { shape, data: vec![0.0; size] }
+    }
+
+    pub fn reshape(self, shape: Vec<usize>) -> Self {
+        Self::new(shape, self.data)
+    }
+
+    /// Reorders the axes like `Tensor::permute`
+    pub fn permute(&self, dims: &[usize]) -> Self {
+        assert_eq!(dims.len(), self.shape.len(), "Permuting {:?} with {:?}", self.shape, dims);
+        let mut strides = vec![1; self.shape.len()];
+        for i in (0..self.shape.len().saturating_sub(1)).rev() {
+            strides[i] = strides[i + 1] * self.shape[i + 1];
+        }
+        let shape: Vec<usize> = dims.iter().map(|&d| self.shape[d]).collect();
+        let mut data = Vec::with_capacity(self.data.len());
+        let mut index = vec![0; shape.len()];
+        for _ in 0..self.data.len() {
+            let offset: usize = index.iter().zip(dims).map(|(&i, &d)| i * strides[d]).sum();
+            data.push(self.data[offset]);
+            // Count up through the output indices, last axis fastest
+            for axis in (0..shape.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Self { shape, data }
+    }
+
+    /// The rows `start..start + len` of the first axis
+    pub fn narrow(&self, start: usize, len: usize) -> Self {
+        let row: usize = self.shape[1..].iter().product();
+        let mut shape = self.shape.clone();
+        shape[0] = len;
+        Self::new(shape, self.data[start * row..(start + len) * row].to_vec())
+    }
+
+    pub fn map(mut self, f: impl Fn(f32) -> f32) -> Self {
+        self.data.iter_mut().for_each(|x| *x = f(*x));
+        self
+    }
+
+    pub fn relu(self) -> Self {
+        self.map(|x| x.max(0.0))
+    }
+
+    pub fn tanh(self) -> Self {
+        self.map(f32::tanh)
+    }
+
+    pub fn sigmoid(self) -> Self {
+        self.map(|x| 1.0 / (1.0 + (-x).exp()))
+    }
+
+    /// The exact GELU, like `Tensor::gelu("none")`
+    pub fn gelu(self) -> Self {
+        self.map(|x| 0.5 * x * (1.0 + erf(x / std::f32::consts::SQRT_2)))
+    }
+}
+
+impl AddAssign<&CpuTensor> for CpuTensor {
+    fn add_assign(&mut self, other: &CpuTensor) {
+        assert_eq!(self.shape, other.shape, "Adding tensors of different shapes");
+        self.data.iter_mut().zip(&other.data).for_each(|(x, y)| *x += y);
+    }
+}
+
+/// erf to within 1.2e-7, from the Chebyshev fit to erfc in Numerical Recipes
+fn erf(x: f32) -> f32 {
+    let z = f64::from(x.abs());
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let polynomial = [
+        -1.26551223,
+        1.00002368,
+        0.37409196,
+        0.09678418,
+        -0.18628806,
+        0.27886807,
+        -1.13520398,
+        1.48851587,
+        -0.82215223,
+        0.17087277,
+    ]
+    .iter()
+    .rev()
+    .fold(0.0, |acc, c| c + t * acc);
+    let erfc = t * (-z * z + polynomial).exp();
+    let erf = (1.0 - erfc) as f32;
+    if x < 0.0 {
+        -erf
+    } else {
+        erf
+    }
+}
+
+/// Softmax in place
+pub fn softmax(xs: &mut [f32]) {
+    let max = xs.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for x in xs.iter_mut() {
+        *x = (*x - max).exp();
+        sum += *x;
+    }
+    xs.iter_mut().for_each(|x| *x /= sum);
+}
+
+/// `policy::masked_softmax` for one position
+pub fn masked_softmax(logits: &[f32], legal_mask: Option<&[bool]>) -> Vec<f32> {
+    let mut probabilities = logits.to_vec();
+    if let Some(mask) = legal_mask {
+        for (p, legal) in probabilities.iter_mut().zip(mask) {
+            if !legal {
+                *p = f32::NEG_INFINITY;
+            }
+        }
+    }
+    softmax(&mut probabilities);
+    probabilities
+}
+
+/// `nn::Linear` over the last axis, [.., in] to [.., out]
+#[derive(Debug, Clone)]
+pub struct Linear {
+    /// [out, in]
+    pub weight: CpuTensor,
+    pub bias: Option<Vec<f32>>,
+    path: String,
+    int8: Option<Int8Weights>,
+}
+
+impl Linear {
+    pub fn load(weights: &Weights, path: &str) -> io::Result<Self> {
+        let weight = weights.get_with_rank(&format!("{}.weight", path), 2)?.clone();
+        let bias = weights.get_optional(&format!("{}.bias", path), weight.shape[0])?;
+        Ok(Self { weight, bias, path: path.to_string(), int8: None })
+    }
+
+    pub fn in_features(&self) -> usize {
+        self.weight.shape[1]
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.weight.shape[0]
+    }
+
+    pub fn forward(&self, xs: &CpuTensor) -> CpuTensor {
+        self.forward_observed(xs, &mut ())
+    }
+
+    /// `forward`, showing the inputs to `observer` while still in float
+    pub fn forward_observed(&self, xs: &CpuTensor, observer: &mut impl Observer) -> CpuTensor {
+        let (out_features, in_features) = (self.out_features(), self.in_features());
+        assert_eq!(xs.shape.last(), Some(&in_features), "Linear layer of {} inputs given {:?}", in_features, xs.shape);
+        let mut shape = xs.shape.clone();
+        *shape.last_mut().unwrap() = out_features;
+        let bias = |o: usize| self.bias.as_ref().map_or(0.0, |bias| bias[o]);
+        let mut data = Vec::with_capacity(xs.data.len() / in_features * out_features);
+        match &self.int8 {
+            None => {
+                observer.observe(self, &xs.data);
+                for row in xs.data.chunks_exact(in_features) {
+                    for (o, weights) in self.weight.data.chunks_exact(in_features).enumerate() {
+                        data.push(bias(o) + weights.iter().zip(row).map(|(w, x)| w * x).sum::<f32>());
+                    }
+                }
+            }
+            Some(int8) => INT8_SCRATCH.with_borrow_mut(|(inputs, _)| {
+                int8.quantize_inputs(&xs.data, inputs);
+                for row in inputs.chunks_exact(in_features) {
+                    for (o, weights) in int8.values.chunks_exact(in_features).enumerate() {
+                        data.push(bias(o) + int8.dequantize(o, dot(weights, row)));
+                    }
+                }
+            }),
+        }
+        CpuTensor::new(shape, data)
+    }
+}
+
+impl Quantize for Linear {
+    fn reset_calibration(&mut self) {
+        self.int8 = None;
+    }
+
+    fn quantize(&mut self, ranges: &InputRanges) {
+        let range = ranges
+            .range_of(self)
+            .unwrap_or_else(|| panic!("Layer {} missed by the calibration pass", self.path));
+        self.int8 = Some(Int8Weights::new(&self.weight, range));
+    }
+
+    fn is_quantized(&self) -> bool {
+        self.int8.is_some()
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// `nn::Conv2D` with a square kernel and stride 1, [C, H, W] to [out, H', W']
+#[derive(Debug, Clone)]
+pub struct Conv2d {
+    /// [out, C, k, k]
+    pub weight: CpuTensor,
+    pub bias: Option<Vec<f32>>,
+    pub padding: usize,
+    path: String,
+    int8: Option<Int8Weights>,
+}
+
+impl Conv2d {
+    pub fn load(weights: &Weights, path: &str, padding: usize) -> io::Result<Self> {
+        let weight = weights.get_with_rank(&format!("{}.weight", path), 4)?.clone();
+        let bias = weights.get_optional(&format!("{}.bias", path), weight.shape[0])?;
+        Ok(Self { weight, bias, padding, path: path.to_string(), int8: None })
+    }
+
+    pub fn forward(&self, xs: &CpuTensor) -> CpuTensor {
+        self.forward_observed(xs, &mut ())
+    }
+
+    /// `forward`, showing the inputs to `observer` while still in float
+    pub fn forward_observed(&self, xs: &CpuTensor, observer: &mut impl Observer) -> CpuTensor {
+        let (out_channels, in_channels, kernel) = (self.weight.shape[0], self.weight.shape[1], self.weight.shape[2]);
+        let (height, width) = match xs.shape[..] {
+            [c, h, w] if c == in_channels => (h, w),
+            _ => panic!("Convolution of {} channels given {:?}", in_channels, xs.shape),
+        };
+        let out_height = height + 2 * self.padding + 1 - kernel;
+        let out_width = width + 2 * self.padding + 1 - kernel;
+        let filter = in_channels * kernel * kernel;
+        let bias = |o: usize| self.bias.as_ref().map_or(0.0, |bias| bias[o]);
+        let mut ys = CpuTensor::zeros(vec![out_channels, out_height, out_width]);
+        match &self.int8 {
+            None => {
+                observer.observe(self, &xs.data);
+                for (o, out) in ys.data.chunks_exact_mut(out_height * out_width).enumerate() {
+                    out.fill(bias(o));
+                    self.convolve(&xs.data, &self.weight.data[o * filter..][..filter], out, height, width);
+                }
+            }
+            Some(int8) => INT8_SCRATCH.with_borrow_mut(|(inputs, patches)| {
+                int8.quantize_inputs(&xs.data, inputs);
+                self.im2col(inputs, patches, height, width);
+                let rows = int8.values.chunks_exact(filter);
+                for (o, (out, weights)) in ys.data.chunks_exact_mut(out_height * out_width).zip(rows).enumerate() {
+                    for (y, patch) in out.iter_mut().zip(patches.chunks_exact(filter)) {
+                        *y = bias(o) + int8.dequantize(o, dot(weights, patch));
+                    }
+                }
+            }),
+        }
+        ys
+    }
+
+    /// Overwrites `patches` with the [C, k, k] patch of `xs`, [C, H, W], under each output,
+    /// [H', W', C, k, k], so each output sums over one row. The padding reads as 0.
+    fn im2col(&self, xs: &[i8], patches: &mut Vec<i8>, height: usize, width: usize) {
+        let kernel = self.weight.shape[2];
+        let out_height = height + 2 * self.padding + 1 - kernel;
+        let out_width = width + 2 * self.padding + 1 - kernel;
+        patches.clear();
+        for y in 0..out_height {
+            for x in 0..out_width {
+                for plane in xs.chunks_exact(height * width) {
+                    for ky in 0..kernel {
+                        let Some(in_y) = (y + ky).checked_sub(self.padding).filter(|&in_y| in_y < height) else {
+                            patches.extend(std::iter::repeat_n(0, kernel));
+                            continue;
+                        };
+                        patches.extend((x..x + kernel).map(|x| {
+                            x.checked_sub(self.padding)
+                                .filter(|&in_x| in_x < width)
+                                .map_or(0, |in_x| plane[in_y * width + in_x])
+                        }));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the convolution of `xs`, [C, H, W], with the `filter` of one output, [C, k, k],
+    /// to `out`
+    fn convolve(&self, xs: &[f32], filter: &[f32], out: &mut [f32], height: usize, width: usize) {
+        let kernel = self.weight.shape[2];
+        let out_width = width + 2 * self.padding + 1 - kernel;
+        let out_height = out.len() / out_width;
+        for (plane, weights) in xs.chunks_exact(height * width).zip(filter.chunks_exact(kernel * kernel)) {
+            for ky in 0..kernel {
+                for kx in 0..kernel {
+                    let w = weights[ky * kernel + kx];
+                    for y in 0..out_height {
+                        // Rows and columns falling in the padding add nothing
+                        let Some(in_y) = (y + ky).checked_sub(self.padding).filter(|&in_y| in_y < height) else {
+                            continue;
+                        };
+                        for x in 0..out_width {
+                            if let Some(in_x) = (x + kx).checked_sub(self.padding).filter(|&in_x| in_x < width) {
+                                out[y * out_width + x] += w * plane[in_y * width + in_x];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Quantize for Conv2d {
+    fn reset_calibration(&mut self) {
+        self.int8 = None;
+    }
+
+    fn quantize(&mut self, ranges: &InputRanges) {
+        let range = ranges
+            .range_of(self)
+            .unwrap_or_else(|| panic!("Layer {} missed by the calibration pass", self.path));
+        self.int8 = Some(Int8Weights::new(&self.weight, range));
+    }
+
+    fn is_quantized(&self) -> bool {
+        self.int8.is_some()
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// `nn::BatchNorm` in evaluation mode, folded into a scale and shift per channel of a
+/// [C, ..] tensor
+#[derive(Debug, Clone)]
+pub struct BatchNorm {
+    pub scale: Vec<f32>,
+    pub shift: Vec<f32>,
+}
+
+impl BatchNorm {
+    pub fn load(weights: &Weights, path: &str) -> io::Result<Self> {
+        let running_mean = &weights.get_with_rank(&format!("{}.running_mean", path), 1)?.data;
+        let channels = running_mean.len();
+        let running_var = &weights.get_sized(&format!("{}.running_var", path), channels)?.data;
+        let weight = weights.get_optional(&format!("{}.weight", path), channels)?;
+        let bias = weights.get_optional(&format!("{}.bias", path), channels)?;
+        let scale: Vec<f32> = (0..channels)
+            .map(|c| weight.as_ref().map_or(1.0, |w| w[c]) / (running_var[c] + NORM_EPS).sqrt())
+            .collect();
+        let shift = (0..channels)
+            .map(|c| bias.as_ref().map_or(0.0, |b| b[c]) - running_mean[c] * scale[c])
+            .collect();
+        Ok(Self { scale, shift })
+    }
+
+    pub fn forward(&self, mut xs: CpuTensor) -> CpuTensor {
+        let plane = xs.data.len() / self.scale.len();
+        for (c, channel) in xs.data.chunks_exact_mut(plane).enumerate() {
+            channel.iter_mut().for_each(|x| *x = *x * self.scale[c] + self.shift[c]);
+        }
+        xs
+    }
+}
+
+/// `nn::LayerNorm` over the last axis
+#[derive(Debug, Clone)]
+pub struct LayerNorm {
+    pub weight: Option<Vec<f32>>,
+    pub bias: Option<Vec<f32>>,
+    pub size: usize,
+}
+
+impl LayerNorm {
+    pub fn load(weights: &Weights, path: &str, size: usize) -> io::Result<Self> {
+        Ok(Self {
+            weight: weights.get_optional(&format!("{}.weight", path), size)?,
+            bias: weights.get_optional(&format!("{}.bias", path), size)?,
+            size,
+        })
+    }
+
+    pub fn forward(&self, mut xs: CpuTensor) -> CpuTensor {
+        for row in xs.data.chunks_exact_mut(self.size) {
+            let mean = row.iter().sum::<f32>() / self.size as f32;
+            let variance = row.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / self.size as f32;
+            let inv_std = 1.0 / (variance + NORM_EPS).sqrt();
+            for (i, x) in row.iter_mut().enumerate() {
+                *x = (*x - mean) * inv_std * self.weight.as_ref().map_or(1.0, |w| w[i])
+                    + self.bias.as_ref().map_or(0.0, |b| b[i]);
+            }
+        }
+        xs
+    }
+}
+
+/// A network's weights by their `VarStore` names, with the manifest of the checkpoint
+/// they came from. What the CPU backend loads in place of a checkpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weights {
+    pub manifest: Manifest,
+    tensors: HashMap<String, CpuTensor>,
+}
+
+impl Weights {
+    pub fn new(manifest: Manifest) -> Self {
+        Self { manifest, tensors: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, tensor: CpuTensor) {
+        self.tensors.insert(name.into(), tensor);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tensors.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> io::Result<&CpuTensor> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| invalid_data(format!("weights have no {:?}", name)))
+    }
+
+    /// `get`, checking the number of axes
+    pub fn get_with_rank(&self, name: &str, rank: usize) -> io::Result<&CpuTensor> {
+        let tensor = self.get(name)?;
+        if tensor.shape.len() != rank {
+            return Err(invalid_data(format!("{:?} has shape {:?}, expected {} axes", name, tensor.shape, rank)));
+        }
+        Ok(tensor)
+    }
+
+    /// `get`, checking the shape
+    pub fn get_shaped(&self, name: &str, shape: &[usize]) -> io::Result<&CpuTensor> {
+        let tensor = self.get(name)?;
+        if tensor.shape != shape {
+            return Err(invalid_data(format!("{:?} has shape {:?}, expected {:?}", name, tensor.shape, shape)));
+        }
+        Ok(tensor)
+    }
+
+    fn get_sized(&self, name: &str, size: usize) -> io::Result<&CpuTensor> {
+        self.get_shaped(name, &[size])
+    }
+
+    /// The numbers of a vector of `size` that layers may leave out, like biases
+    fn get_optional(&self, name: &str, size: usize) -> io::Result<Option<Vec<f32>>> {
+        match self.tensors.contains_key(name) {
+            true => Ok(Some(self.get_sized(name, size)?.data.clone())),
+            false => Ok(None),
+        }
+    }
+
+    /// Copies every variable of `vs`, trainable or not, next to `manifest`
+    #[cfg(feature = "tch")]
+    pub fn from_var_store(vs: &nn::VarStore, manifest: &Manifest) -> Result<Self, TchError> {
+        let mut weights = Self::new(manifest.clone());
+        for (name, tensor) in vs.variables() {
+            let shape = tensor.size().iter().map(|&d| d as usize).collect();
+            let data = Vec::<f32>::try_from(&tensor.to_kind(Kind::Float).reshape([-1]))?;
+            weights.insert(name, CpuTensor::new(shape, data));
+        }
+        Ok(weights)
+    }
+
+    /// Writes the magic bytes, then the manifest and every tensor, each after its length,
+    /// with all numbers little-endian
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        write_bytes(&mut file, self.manifest.to_string().as_bytes())?;
+        // Sorted so the same weights always make the same file
+        let mut names: Vec<&String> = self.tensors.keys().collect();
+        names.sort();
+        write_u64(&mut file, names.len())?;
+        for name in names {
+            let tensor = &self.tensors[name];
+            write_bytes(&mut file, name.as_bytes())?;
+            write_u64(&mut file, tensor.shape.len())?;
+            for &dim in &tensor.shape {
+                write_u64(&mut file, dim)?;
+            }
+            for x in &tensor.data {
+                file.write_all(&x.to_le_bytes())?;
+            }
+        }
+        file.flush()
+    }
+
+    /// Reads what `save` wrote. Lengths and shapes that need more bytes than the file
+    /// holds are `InvalidData`, before anything is allocated for them.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let limit = usize::try_from(file.metadata()?.len()).map_err(|e| invalid_data(e.to_string()))?;
+        let mut file = BufReader::new(file);
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data(format!("{:?} holds no exported weights", path)));
+        }
+        let manifest = String::from_utf8(read_bytes(&mut file, limit)?)
+            .map_err(|e| invalid_data(e.to_string()))?
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut weights = Self::new(manifest);
+        for _ in 0..read_len(&mut file, limit)? {
+            let name = String::from_utf8(read_bytes(&mut file, limit)?).map_err(|e| invalid_data(e.to_string()))?;
+            let shape = (0..read_len(&mut file, limit / 8)?)
+                .map(|_| read_u64(&mut file))
+                .collect::<io::Result<Vec<_>>>()?;
+            let size = shape
+                .iter()
+                .try_fold(4usize, |size, &dim| size.checked_mul(dim))
+                .filter(|&size| size <= limit)
+                .ok_or_else(|| invalid_data(format!("{:?} has shape {:?}, more than the file holds", name, shape)))?;
+            let mut bytes = vec![0; size];
+            file.read_exact(&mut bytes)?;
+            let data = bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            weights.insert(name, CpuTensor::new(shape, data));
+        }
+        Ok(weights)
+    }
+}
+
+fn write_u64(out: &mut impl Write, value: usize) -> io::Result<()> {
+    out.write_all(&(value as u64).to_le_bytes())
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(out, bytes.len())?;
+    out.write_all(bytes)
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<usize> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    usize::try_from(u64::from_le_bytes(bytes)).map_err(|e| invalid_data(e.to_string()))
+}
+
+/// A length, which can't be more than `limit`
+fn read_len(input: &mut impl Read, limit: usize) -> io::Result<usize> {
+    let len = read_u64(input)?;
+    if len > limit {
+        return Err(invalid_data(format!("length {} is more than the file holds", len)));
+    }
+    Ok(len)
+}
+
+fn read_bytes(input: &mut impl Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; read_len(input, limit)?];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Saves the weights in `vs` and `manifest` for the CPU backend, see `Weights`
+#[cfg(feature = "tch")]
+pub fn export_weights(vs: &nn::VarStore, manifest: &Manifest, path: &Path) -> Result<(), TchError> {
+    Weights::from_var_store(vs, manifest)?.save(path)?;
+    Ok(())
+}
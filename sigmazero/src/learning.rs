@@ -1,32 +1,57 @@
+#[cfg(feature = "tch")]
 use std::time::Instant;
 
+#[cfg(feature = "tch")]
 use crate::{
-    data::{ReplayBuffer, ReplayBufferTensorData},
+    data::ReplayBufferTensorData,
     game::Game,
     policy::NNAgent,
 };
+#[cfg(feature = "tch")]
 use indicatif::{ProgressBar, ProgressStyle};
-use itertools::Itertools;
+#[cfg(feature = "tch")]
 use tch::nn::{self, OptimizerConfig, VarStore};
+#[cfg(feature = "tch")]
+use tch::{Kind, Tensor};
 
+/// Mean losses per batch over an epoch
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Losses {
+    pub train_policy: f64,
+    pub train_value: f64,
+    pub test_policy: f64,
+    pub test_value: f64,
+}
+
+/// Trains the network `A` builds in `vs`, returning the losses of the last epoch
+#[cfg(feature = "tch")]
 pub fn train_on_replay<A: NNAgent<G, N>, G: Game<N>, const N: usize>(
     vs: &VarStore,
     replay_data: &ReplayBufferTensorData,
     batch_size: usize,
     epochs: usize,
     train_fraction: f32,
-) {
+) -> Losses {
     // Start training NN
-    let mut nn_agent = A::new(&vs);
+    let nn_agent = A::new(vs);
     let device= vs.device();
     if device != replay_data.device() {
         panic!("Agent device ({:?}) and replay device ({:?}) mismatch.", device, replay_data.device());
     }
+    if let Err(e) = replay_data.check_encoding::<A::Encoder, G, N>() {
+        panic!("Replay data doesn't match the game: {}", e);
+    }
     let mut opt = nn::Adam::default()
-        .build(&vs, 1e-3)
+        .build(vs, 1e-3)
         .expect("Optimiser initialisation failed!");
 
     let (train_data, test_data) = replay_data.random_split(train_fraction);
+    // The masks and outcomes ride along with the targets, as batches only pair two tensors
+    let stack_targets = |data: &ReplayBufferTensorData| {
+        Tensor::cat(&[&data.policy_value, &data.legal_mask.to_kind(Kind::Float), &data.outcomes], 1)
+    };
+    let train_targets = stack_targets(&train_data);
+    let test_targets = stack_targets(&test_data);
     let progress_bar = ProgressBar::new(epochs as u64);
     progress_bar.set_style(
         ProgressStyle::with_template("{msg}\n[{elapsed_precise}] {bar:40} {pos}/{len} epochs")
@@ -47,32 +72,38 @@ pub fn train_on_replay<A: NNAgent<G, N>, G: Game<N>, const N: usize>(
     let test_batches = temp_test_loader.collect::<Vec<_>>().len();
     println!("Training for {} epochs on {} batches of {}...", epochs, train_batches, batch_size);
     let start = Instant::now();
-    for epoch in 0..epochs {
+    let mut losses = Losses::default();
+    for _ in 0..epochs {
         progress_bar.inc(1);
         let mut total_epoch_loss: [f64; 2] = [0.0, 0.0];
         let mut train_data_iterator = tch::data::Iter2::new(
             &train_data.features,
-            &train_data.policy_value,
+            &train_targets,
             batch_size as i64,
         );
         train_data_iterator.to_device(device);
         train_data_iterator.shuffle();
-        for (features, policy_values) in train_data_iterator {
-            let mut pv_split = policy_values.split_with_sizes(&[81, 1], -1);
-            let value_target = pv_split.pop().unwrap();
-            let policy_target = pv_split.pop().unwrap();
-            let (policy_est, value_est) = nn_agent.forward(&features, true);
+        for (features, targets) in train_data_iterator {
+            let mut split = targets.split_with_sizes([N as i64, 1, N as i64, 3], -1);
+            let outcome_target = split.pop().unwrap();
+            let legal_mask = split.pop().unwrap().to_kind(Kind::Bool);
+            let value_target = split.pop().unwrap();
+            let policy_target = split.pop().unwrap();
+            let (policy_est, value_est) = nn_agent.forward(&features, Some(&legal_mask), true);
 
-            let value_loss = value_est.mse_loss(&value_target, tch::Reduction::Mean);
-            // KL-divergence for prob distributions
+            let value_loss = value_loss(A::WDL_HEAD, &value_est, &value_target, &outcome_target);
+            // KL-divergence for prob distributions, with the log of masked moves kept
+            // finite so their zero targets add nothing
             let policy_loss = policy_est
+                .clamp_min(f64::from(f32::MIN_POSITIVE))
                 .log()
                 .kl_div(&policy_target, tch::Reduction::Mean, false);
 
-            total_epoch_loss[0] += policy_loss.double_value(&[]) as f64;
-            total_epoch_loss[1] += value_loss.double_value(&[]) as f64;
+            total_epoch_loss[0] += policy_loss.double_value(&[]);
+            total_epoch_loss[1] += value_loss.double_value(&[]);
 
-            let loss = value_loss * policy_loss;
+            // Summed, so neither head's gradient is scaled by how far the other has come
+            let loss = value_loss + policy_loss;
 
             opt.backward_step(&loss);
         }
@@ -80,36 +111,59 @@ pub fn train_on_replay<A: NNAgent<G, N>, G: Game<N>, const N: usize>(
         let mut total_epoch_loss_test: [f64; 2] = [0.0, 0.0];
         let mut test_data_iterator = tch::data::Iter2::new(
             &test_data.features,
-            &test_data.policy_value,
+            &test_targets,
             batch_size as i64,
         );
         test_data_iterator.to_device(device);
         test_data_iterator.return_smaller_last_batch();
         test_data_iterator.shuffle();
-        for (features, policy_values) in test_data_iterator {
-            let mut pv_split = policy_values.split_with_sizes(&[81, 1], -1);
-            let value_target = pv_split.pop().unwrap();
-            let policy_target = pv_split.pop().unwrap();
-            let (policy_est, value_est) = tch::no_grad(|| nn_agent.forward(&features, false));
+        for (features, targets) in test_data_iterator {
+            let mut split = targets.split_with_sizes([N as i64, 1, N as i64, 3], -1);
+            let outcome_target = split.pop().unwrap();
+            let legal_mask = split.pop().unwrap().to_kind(Kind::Bool);
+            let value_target = split.pop().unwrap();
+            let policy_target = split.pop().unwrap();
+            let (policy_est, value_est) = tch::no_grad(|| nn_agent.forward(&features, Some(&legal_mask), false));
 
-            let value_loss = value_est.mse_loss(&value_target, tch::Reduction::Mean);
-            // KL-divergence for prob distributions
+            let value_loss = value_loss(A::WDL_HEAD, &value_est, &value_target, &outcome_target);
+            // KL-divergence for prob distributions, with the log of masked moves kept
+            // finite so their zero targets add nothing
             let policy_loss = policy_est
+                .clamp_min(f64::from(f32::MIN_POSITIVE))
                 .log()
                 .kl_div(&policy_target, tch::Reduction::Mean, false);
 
-            total_epoch_loss_test[0] += policy_loss.double_value(&[]) as f64;
-            total_epoch_loss_test[1] += value_loss.double_value(&[]) as f64;
+            total_epoch_loss_test[0] += policy_loss.double_value(&[]);
+            total_epoch_loss_test[1] += value_loss.double_value(&[]);
         }
+        losses = Losses {
+            train_policy: total_epoch_loss[0] / (train_batches as f64),
+            train_value: total_epoch_loss[1] / (train_batches as f64),
+            test_policy: total_epoch_loss_test[0] / (test_batches as f64),
+            test_value: total_epoch_loss_test[1] / (test_batches as f64),
+        };
         progress_bar.set_message(format!(
             "Train - Policy loss: {:.4e}, Value loss: {:.4e} | Test - Policy loss: {:.4e}, Value loss: {:.4e}",
-            total_epoch_loss[0] / (train_batches as f64),
-            total_epoch_loss[1] / (train_batches as f64),
-            total_epoch_loss_test[0] / (test_batches as f64),
-            total_epoch_loss_test[1] / (test_batches as f64),
+            losses.train_policy,
+            losses.train_value,
+            losses.test_policy,
+            losses.test_value,
         ));
     };
     progress_bar.finish();
     let duration = start.elapsed();
     println!("Completed training in {:2}", duration.as_secs_f32());
+    losses
+}
+
+/// Cross-entropy against the outcomes for WDL heads, MSE against the values otherwise
+#[cfg(feature = "tch")]
+fn value_loss(wdl_head: bool, value_est: &Tensor, value_target: &Tensor, outcome_target: &Tensor) -> Tensor {
+    if wdl_head {
+        -(outcome_target * value_est.clamp_min(f64::from(f32::MIN_POSITIVE)).log())
+            .sum_dim_intlist(-1, false, Kind::Float)
+            .mean(Kind::Float)
+    } else {
+        value_est.mse_loss(value_target, tch::Reduction::Mean)
+    }
 }
@@ -3,4 +3,7 @@ pub mod policy;
 pub mod data;
 pub mod mcts;
 pub mod evaluate;
-pub mod learning;
\ No newline at end of file
+pub mod learning;
+pub mod checkpoint;
+pub mod inference;
+pub mod quantize;
\ No newline at end of file
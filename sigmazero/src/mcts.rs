@@ -2,20 +2,23 @@ use core::fmt;
 use std::time::Instant;
 
 use crate::data::ReplayBuffer;
-use crate::game::{Game, GameStatus};
-use crate::policy::{Agent, RawPolicy};
+use crate::game::{Game, GameStatus, Player};
+use crate::policy::{Agent, RawPolicy, Wdl};
 use ego_tree::{NodeId, NodeMut, NodeRef, Tree};
-use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use indicatif::{ProgressIterator, ProgressStyle};
 use itertools::izip;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
 
 pub struct GameNode<G: Game<N>, const N: usize> {
     num_visits: u32,
     prior_prob: f32,
-    total_value: f32,
-    action_value: f32,
+    /// Summed over visits, one per player
+    total_values: Vec<f32>,
+    action_values: Vec<f32>,
     pub game_state: G,
     node_state: GameNodeState,
-    previous_action: Option<G::Position>, // Only None for root
+    previous_action: Option<G::Position>, // None for root and chance outcomes
 }
 
 impl<G: Game<N>, const N: usize> GameNode<G, N> {
@@ -28,8 +31,8 @@ impl<G: Game<N>, const N: usize> GameNode<G, N> {
         Self {
             num_visits: 0,
             prior_prob,
-            total_value: 0.0,
-            action_value: 0.0,
+            total_values: vec![0.0; G::Player::PLAYERS.len()],
+            action_values: vec![0.0; G::Player::PLAYERS.len()],
             game_state,
             node_state,
             previous_action,
@@ -43,8 +46,10 @@ impl<G: Game<N>, const N: usize> GameNode<G, N> {
         )
     }
 
-    pub fn update_action_value(&mut self) {
-        self.action_value = self.total_value / self.num_visits as f32;
+    pub fn update_action_values(&mut self) {
+        for (action_value, total_value) in self.action_values.iter_mut().zip(&self.total_values) {
+            *action_value = total_value / self.num_visits as f32;
+        }
     }
 }
 
@@ -67,33 +72,63 @@ impl<G: Game<N>, const N: usize> fmt::Display for GameNode<G, N> {
 #[derive(Debug)]
 pub enum GameNodeState {
     Expanded { is_terminal: bool },
+    /// Expanded, with a child for each chance outcome
+    Chance,
     NotExpanded,
 }
 
+/// A value estimate for every player, indexed like `Player::PLAYERS`
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub values: Vec<f32>,
+}
+
+impl Evaluation {
+    pub fn value_for<P: Player>(&self, player: P) -> f32 {
+        self.values[player.index()]
+    }
+}
+
 type MCTSTree<G, const N: usize> = Tree<GameNode<G, N>>;
 
-pub struct MCTS<'a, G: Game<N>, A: Agent<G, N>, const N: usize> {
+pub struct MCTS<'a, G: Game<N>, A: Agent<G, N> + ?Sized, const N: usize> {
     tree: MCTSTree<G, N>,
     c_puct: f32,
+    /// Who the search is for, the only player that draws cost
+    root_player: G::Player,
+    /// What a draw costs the root player, see `Wdl::expected_score`
+    draw_contempt: f32,
     agent: &'a mut A,
 }
 
-impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
-    pub fn expand(&mut self, leaf_node_id: NodeId) -> f32 {
+impl<'a, G: Game<N>, A: Agent<G, N> + ?Sized, const N: usize> MCTS<'a, G, A, N> {
+    pub fn expand(&mut self, leaf_node_id: NodeId) -> Evaluation {
         let mut leaf_node: NodeMut<'_, GameNode<G, N>> = self.tree.get_mut(leaf_node_id).unwrap();
         if leaf_node.value().is_terminal() {
             leaf_node.value().node_state = GameNodeState::Expanded { is_terminal: true };
 
-            let status = leaf_node.value().game_state.status();
-            // Because can only win on your own move
-            return status.into();
+            let game_state = &leaf_node.value().game_state;
+            let mut values = game_state.outcomes();
+            if matches!(game_state.status(), GameStatus::Draw) {
+                values[self.root_player.index()] -= self.draw_contempt;
+            }
+            Evaluation { values }
         } else {
             if leaf_node.has_children() {
                 panic!("leaf node already has children! (Probably already expanded)")
             };
+            if let Some(outcomes) = leaf_node.value().game_state.chance_outcomes() {
+                leaf_node.value().node_state = GameNodeState::Chance;
+                // Valued before chance has its say, like an afterstate
+                let (_, values) = Self::evaluate(self.agent, self.root_player, self.draw_contempt, &leaf_node.value().game_state);
+                for (outcome, probability) in outcomes {
+                    leaf_node.append(GameNode::new(probability, outcome, GameNodeState::NotExpanded, None));
+                }
+                return Evaluation { values };
+            }
             leaf_node.value().node_state = GameNodeState::Expanded { is_terminal: false };
 
-            let (policy, value) = tch::no_grad(|| self.agent.eval_game(&leaf_node.value().game_state));
+            let (policy, values) = Self::evaluate(self.agent, self.root_player, self.draw_contempt, &leaf_node.value().game_state);
 
             for (valid_move, prior_prob) in policy.mask_policy(&leaf_node.value().game_state) {
                 let mut child_state = leaf_node.value().game_state;
@@ -109,7 +144,23 @@ impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
                 leaf_node.append(child_node);
             }
 
-            return value;
+            Evaluation { values }
+        }
+    }
+
+    /// The agent's policy and values, from its WDL head when it has one so the root
+    /// player's draws can be weighed with the contempt
+    fn evaluate(agent: &mut A, root_player: G::Player, draw_contempt: f32, game: &G) -> (RawPolicy<N>, Vec<f32>) {
+        #[cfg(feature = "tch")]
+        let _no_grad = tch::no_grad_guard();
+        match agent.eval_game_wdl(game) {
+            Some((policy, wdl)) => {
+                let player = game.to_move().expect("Evaluating a finished game");
+                let mut values = wdl.values_for(player);
+                values[root_player.index()] -= draw_contempt * wdl.draw;
+                (policy, values)
+            }
+            None => agent.eval_game_values(game),
         }
     }
 
@@ -120,20 +171,24 @@ impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
         loop {
             node_chain.push(base_node.id());
 
-            if !matches!(
-                base_node.value().node_state,
-                GameNodeState::Expanded { is_terminal: false }
-            ) {
-                return node_chain;
+            match base_node.value().node_state {
+                GameNodeState::Expanded { is_terminal: false } => (),
+                GameNodeState::Chance => {
+                    base_node = Self::select_chance_outcome(base_node);
+                    continue;
+                }
+                _ => return node_chain,
             }
 
+            // Each player picks the move that's best for themselves (max^n)
+            let mover = base_node.value().game_state.to_move().unwrap().index();
             let mut n_vals = Vec::<u32>::new();
             let mut q_vals = Vec::<f32>::new();
             let mut p_vals = Vec::<f32>::new();
             for child_node in base_node.children() {
                 let game_node: &GameNode<G, N> = child_node.value();
                 n_vals.push(game_node.num_visits);
-                q_vals.push(game_node.action_value);
+                q_vals.push(game_node.action_values[mover]);
                 p_vals.push(game_node.prior_prob);
             }
             let sum_sqrt: f32 = (n_vals.iter().sum::<u32>() as f32).sqrt();
@@ -150,16 +205,29 @@ impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
         }
     }
 
-    pub fn backup(&mut self, node_chain: Vec<NodeId>, value: f32) {
-        let mut value = value;
+    /// The outcome furthest behind its share of visits, so that visits follow the
+    /// probabilities and the node's value tends to the expectation
+    fn select_chance_outcome<'t>(node: NodeRef<'t, GameNode<G, N>>) -> NodeRef<'t, GameNode<G, N>> {
+        let total_visits = node.children().map(|child| child.value().num_visits).sum::<u32>() as f32;
+        node.children()
+            .max_by(|a, b| {
+                let deficit = |child: &NodeRef<'t, GameNode<G, N>>| {
+                    child.value().prior_prob - child.value().num_visits as f32 / (1. + total_visits)
+                };
+                deficit(a).total_cmp(&deficit(b))
+            })
+            .expect("Chance node has no outcomes!")
+    }
+
+    pub fn backup(&mut self, node_chain: Vec<NodeId>, evaluation: Evaluation) {
         for node_id in node_chain.into_iter().rev() {
             let mut node = self.tree.get_mut(node_id).expect("No node found");
             let game_node: &mut GameNode<G, N> = node.value();
             game_node.num_visits += 1;
-            game_node.total_value += value;
-            game_node.update_action_value();
-
-            value = value * -1.;
+            for (total_value, value) in game_node.total_values.iter_mut().zip(&evaluation.values) {
+                *total_value += value;
+            }
+            game_node.update_action_values();
         }
     }
 
@@ -185,8 +253,10 @@ impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
         )
     }
 
+    /// The root has to be a decision, see `resolve_chance`
     pub fn from_root_game_state(root_game_state: G, agent: &'a mut A) -> Self {
         Self {
+            root_player: root_game_state.perspective(),
             tree: MCTSTree::new(GameNode::new(
                 0.0,
                 root_game_state,
@@ -194,28 +264,63 @@ impl<'a, G: Game<N>, A: Agent<G, N>, const N: usize> MCTS<'a, G, A, N> {
                 None,
             )),
             c_puct: 1.,
+            draw_contempt: 0.,
             agent,
         }
     }
+
+    /// Values draws at `-draw_contempt` instead of 0 for the player to move at the root.
+    /// The opponents are still modelled as indifferent to draws.
+    pub fn with_draw_contempt(mut self, draw_contempt: f32) -> Self {
+        self.draw_contempt = draw_contempt;
+        self
+    }
+}
+
+/// Lets chance play out until someone has to decide what to do
+pub fn resolve_chance<G: Game<N>, R: Rng, const N: usize>(game: &G, rng: &mut R) -> G {
+    let mut game = *game;
+    while let Some(outcomes) = game.chance_outcomes() {
+        let distribution = WeightedIndex::new(outcomes.iter().map(|(_, p)| *p))
+            .expect("Invalid chance probabilities");
+        game = outcomes[distribution.sample(rng)].0;
+    }
+    game
+}
+
+pub fn self_play<G: Game<N>, A: Agent<G, N> + ?Sized, const N: usize>(
+    agent: &mut A,
+    n_games: usize,
+    search_steps: usize,
+    draw_contempt: f32,
+    show_games: bool,
+) -> ReplayBuffer<G, N> {
+    self_play_from(G::default(), agent, n_games, search_steps, draw_contempt, show_games)
 }
 
-pub fn self_play<G: Game<N>, A: Agent<G, N>, const N: usize>(
+/// Like `self_play`, with every game starting from `start`, e.g. a variant's empty board
+pub fn self_play_from<G: Game<N>, A: Agent<G, N> + ?Sized, const N: usize>(
+    start: G,
     agent: &mut A,
     n_games: usize,
     search_steps: usize,
+    draw_contempt: f32,
     show_games: bool,
 ) -> ReplayBuffer<G, N> {
     let mut buffer = ReplayBuffer::default();
+    let mut rng = rand::thread_rng();
     let progress_style = ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} games").unwrap();
     println!("Playing {} self-play games", n_games);
-    let start = Instant::now();
+    let started = Instant::now();
     for _ in (0..n_games).progress_with_style(progress_style).with_finish(indicatif::ProgressFinish::Abandon) {
-        let mut games = vec![G::default()];
+        let mut games = vec![start];
         let mut values = Vec::<f32>::new();
+        let mut outcomes = Vec::<Wdl>::new();
         let mut policies = Vec::<RawPolicy<N>>::new();
         loop {
-            let mut mcts =
-                MCTS::<G, A, N>::from_root_game_state(games.last().unwrap().clone(), agent);
+            let root = resolve_chance(games.last().unwrap(), &mut rng);
+            *games.last_mut().unwrap() = root;
+            let mut mcts = MCTS::<G, A, N>::from_root_game_state(root, agent).with_draw_contempt(draw_contempt);
             // println!("{}", mcts.tree);
             for _ in 0..search_steps {
                 let node_chain: Vec<NodeId> = mcts.select();
@@ -240,22 +345,19 @@ pub fn self_play<G: Game<N>, A: Agent<G, N>, const N: usize>(
                 if show_games {
                     println!("Result: {:?}", best_child.game_state.status());
                 }
-                let final_value: f32 = best_child.game_state.status().into();
-                for i in 0..games.len() {
-                    if i % 2 == 0 {
-                        values.push(final_value);
-                    } else {
-                        values.push(-final_value);
-                    }
+                // Each position is valued for the player to move in it
+                for game in &games {
+                    let player = game.to_move().unwrap();
+                    values.push(best_child.game_state.outcome_for(player));
+                    outcomes.push(Wdl::from_status(best_child.game_state.status(), player));
                 }
-                values.reverse();
                 break;
             }
             games.push(best_child.game_state);
         }
-        buffer.append(&mut games, &mut values, &mut policies);
+        buffer.append(&mut games, &mut values, &mut outcomes, &mut policies);
     }
-    let duration = start.elapsed();
+    let duration = started.elapsed();
     println!(
         "generated {} Games with {} states in {:.2} seconds",
         n_games,
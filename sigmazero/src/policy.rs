@@ -1,10 +1,17 @@
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::ops::Deref;
 
+#[cfg(feature = "tch")]
 use std::path::Path;
-use tch::{nn, Tensor, Device, TchError};
+#[cfg(feature = "tch")]
+use tch::{nn, Tensor, TchError};
 use colored::Colorize;
 
-use crate::game::{Game, PositionList};
+#[cfg(feature = "tch")]
+use crate::game::FeatureEncoder;
+use crate::game::{Game, GameStatus, Player, PositionList, Symmetry};
 
 pub struct Policy<G: Game<N>, const N: usize> {
     positions: PositionList<G::Position>,
@@ -17,8 +24,8 @@ impl<G: Game<N>, const N: usize> IntoIterator for Policy<G, N> {
 
     fn into_iter(self) -> Self::IntoIter {
         self.positions
-            .to_owned()
-            .into_iter()
+            .iter()
+            .copied()
             .zip(self.probabilities)
             .collect::<Vec<_>>()
             .into_iter()
@@ -40,13 +47,19 @@ impl<const N: usize> RawPolicy<{ N }> {
             .map(|p| self.0[Into::<usize>::into(*p)])
             .collect();
         let sum: f32 = probabilities.iter().sum();
-        probabilities = probabilities.into_iter().map(|p| p / sum).collect();
+        probabilities = if sum > 0.0 {
+            probabilities.into_iter().map(|p| p / sum).collect()
+        } else {
+            // Nothing on the legal moves, so don't favour any of them
+            vec![1.0 / positions.len() as f32; positions.len()]
+        };
         Policy {
             positions,
             probabilities,
         }
     }
 
+    #[cfg(feature = "tch")]
     pub fn to_tensor(&self, shape: &[i64]) -> Tensor {
         Tensor::from_slice(&self.0).reshape(shape)
     }
@@ -60,12 +73,11 @@ impl<const N: usize> RawPolicy<{ N }> {
     }
 
     fn colour_number(number: f32) -> String {
-        let mut s = String::new();
-        if number == 1.0 {
-            s = "1.0".to_string();
+        let mut s = if number == 1.0 {
+            "1.0".to_string()
         } else {
-            s = format!("{number:3.2}")[1..].to_string();
-        }
+            format!("{number:3.2}")[1..].to_string()
+        };
         if number > 0.25 {
             s = s.red().to_string()
         } else if number > 0.005 {
@@ -82,12 +94,267 @@ impl<const N: usize> Deref for RawPolicy<N> {
     }
 }
 
+/// Win, draw and loss probabilities for one player
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Wdl {
+    pub win: f32,
+    pub draw: f32,
+    pub loss: f32,
+}
+
+impl Wdl {
+    /// The one-hot of how the game ended for `player`
+    pub fn from_status<P: Player>(status: &GameStatus<P>, player: P) -> Self {
+        match status {
+            GameStatus::Won { player: winner } if *winner == player => Self { win: 1.0, ..Default::default() },
+            GameStatus::Won { .. } => Self { loss: 1.0, ..Default::default() },
+            _ => Self { draw: 1.0, ..Default::default() },
+        }
+    }
+
+    /// Splits a score in [-1, 1] between a win or a loss and a draw, for data that
+    /// only kept the score
+    pub fn from_score(score: f32) -> Self {
+        Self {
+            win: score.max(0.0),
+            draw: 1.0 - score.abs(),
+            loss: (-score).max(0.0),
+        }
+    }
+
+    /// Wins minus losses, with draws costing `draw_contempt`. Positive contempt plays
+    /// for the win, negative settles for draws.
+    pub fn expected_score(&self, draw_contempt: f32) -> f32 {
+        self.win - self.loss - draw_contempt * self.draw
+    }
+
+    /// Zero-sum values for every player when these are `player`'s chances
+    pub fn values_for<P: Player>(&self, player: P) -> Vec<f32> {
+        player.zero_sum_values(self.win - self.loss)
+    }
+
+    pub fn to_array(&self) -> [f32; 3] {
+        [self.win, self.draw, self.loss]
+    }
+}
+
 pub trait Agent<G: Game<N>, const N: usize> {
+    /// The policy and the value for the player to move
     fn eval_game(&mut self, game: &G) -> (RawPolicy<N>, f32);
+    #[cfg(feature = "tch")]
     fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<N>, f32);
+
+    /// The policy and a value for every player, indexed like `Player::PLAYERS`. Agents
+    /// that only value the player to move have it spread by `Player::zero_sum_values`.
+    fn eval_game_values(&mut self, game: &G) -> (RawPolicy<N>, Vec<f32>) {
+        let (policy, value) = self.eval_game(game);
+        let player = game.to_move().expect("Evaluating a finished game");
+        (policy, player.zero_sum_values(value))
+    }
+
+    /// The policy and the win/draw/loss chances of the player to move, for agents with
+    /// a WDL value head. Search prefers this to `eval_game_values` when it's there.
+    /// `eval_game` gives the same policy, and `Wdl::expected_score(0.0)` as the value.
+    fn eval_game_wdl(&mut self, _game: &G) -> Option<(RawPolicy<N>, Wdl)> {
+        None
+    }
+
+    /// `eval_game` for each of `games`. Network agents should override this to run
+    /// them as one batch.
+    fn eval_games(&mut self, games: &[G]) -> Vec<(RawPolicy<N>, f32)> {
+        games.iter().map(|game| self.eval_game(game)).collect()
+    }
 }
 
+#[cfg(feature = "tch")]
 pub trait NNAgent<G: Game<N>, const N:usize>: Agent<G, N> {
+    /// How the network wants its input
+    type Encoder: FeatureEncoder<G, N>;
+    /// Whether `forward` gives win/draw/loss probabilities, `[B, 3]`, instead of a
+    /// `[B, 1]` score. They're trained on the recorded outcomes rather than the values.
+    const WDL_HEAD: bool = false;
+
+    /// Builds the network in `vs`, following the config stored there if the network
+    /// has one, see `restore_config`
     fn new(vs: &nn::VarStore) -> Self;
-    fn forward(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor);
-}
\ No newline at end of file
+    /// The policy and value for a batch of features. A `[B, N]` bool `legal_mask`
+    /// takes illegal moves out of the policy before the softmax, see `masked_softmax`.
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, train: bool) -> (Tensor, Tensor);
+
+    /// What checkpoint manifests call the network `new` builds from `vs`
+    fn architecture(_vs: &nn::VarStore) -> String {
+        std::any::type_name::<Self>().to_string()
+    }
+
+    /// Whether `new` can build the network a manifest calls `architecture`
+    fn builds_architecture(architecture: &str) -> bool {
+        architecture == std::any::type_name::<Self>()
+    }
+}
+
+/// Where a network keeps the config it was built from, next to its weights
+pub const CONFIG_VAR: &str = "config";
+
+/// Where a game with several networks to choose from keeps which one a checkpoint
+/// holds, next to its `CONFIG_VAR`
+pub const ARCHITECTURE_VAR: &str = "architecture";
+
+/// Copies the config saved with the weights at `path` into `vs`, so that
+/// `NNAgent::new` builds the graph those weights are for. Load the weights after.
+#[cfg(feature = "tch")]
+pub fn restore_config(vs: &nn::VarStore, path: &Path) -> Result<(), TchError> {
+    let tensors = Tensor::load_multi(path)?;
+    for (name, config) in tensors.iter().filter(|(name, _)| [CONFIG_VAR, ARCHITECTURE_VAR].contains(&name.as_str())) {
+        let mut var = vs.root().zeros_no_train(name, &config.size());
+        tch::no_grad(|| var.copy_(config));
+    }
+    Ok(())
+}
+
+/// A network's shape, kept in its `VarStore` under `VAR` as a few numbers
+pub trait NetworkConfig: Sized + PartialEq + fmt::Debug {
+    const VAR: &'static str = CONFIG_VAR;
+
+    fn to_vec(&self) -> Vec<f32>;
+    fn from_slice(values: &[f32]) -> Self;
+
+    /// The config `vs` holds, if any, e.g. after `restore_config`
+    #[cfg(feature = "tch")]
+    fn from_var_store(vs: &nn::VarStore) -> Option<Self> {
+        let config = vs.variables().remove(Self::VAR)?;
+        let values = Vec::<f32>::try_from(&config).expect("Config conversion from tensor to vec failed!");
+        Some(Self::from_slice(&values))
+    }
+
+    /// Records the config in `vs`, which mustn't hold a different one already
+    #[cfg(feature = "tch")]
+    fn store(&self, vs: &nn::VarStore) {
+        match Self::from_var_store(vs) {
+            Some(stored) if stored != *self => {
+                panic!("VarStore already holds {:?}, not {:?}", stored, self)
+            }
+            Some(_) => (),
+            None => {
+                let values = self.to_vec();
+                let mut config = vs.root().zeros_no_train(Self::VAR, &[values.len() as i64]);
+                tch::no_grad(|| config.copy_(&Tensor::from_slice(&values)));
+            }
+        }
+    }
+}
+
+/// How many numbers training can change in `vs`
+#[cfg(feature = "tch")]
+pub fn parameter_count(vs: &nn::VarStore) -> usize {
+    vs.trainable_variables().iter().map(|t| t.numel()).sum()
+}
+
+/// Softmax over the last dimension, with the logits where `legal_mask` is false
+/// given no probability at all
+#[cfg(feature = "tch")]
+pub fn masked_softmax(logits: &Tensor, legal_mask: Option<&Tensor>) -> Tensor {
+    match legal_mask {
+        Some(mask) => logits
+            .masked_fill(&mask.logical_not(), f64::NEG_INFINITY)
+            .softmax(-1, tch::Kind::Float),
+        None => logits.softmax(-1, tch::Kind::Float),
+    }
+}
+
+/// Evaluates every position in its canonical form and caches the result, so
+/// transpositions and symmetric positions share a single evaluation.
+pub struct CachedAgent<G: Game<N>, A: Agent<G, N>, const N: usize> {
+    agent: A,
+    /// The policy, the value and, from agents with a WDL head, the win/draw/loss chances
+    cache: HashMap<G, (RawPolicy<N>, f32, Option<Wdl>)>,
+}
+
+impl<G: Game<N> + Eq + Hash, A: Agent<G, N>, const N: usize> CachedAgent<G, A, N> {
+    pub fn new(agent: A) -> Self {
+        Self {
+            agent,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+
+    pub fn into_inner(self) -> A {
+        self.agent
+    }
+
+    /// The evaluation of `game`'s canonical form, with the policy mapped back onto `game`.
+    /// Batches cache no win/draw/loss chances, so with `wdl_wanted` those are asked for
+    /// again.
+    fn lookup(&mut self, game: &G, wdl_wanted: bool) -> (RawPolicy<N>, f32, Option<Wdl>) {
+        let (canonical, symmetry) = game.canonical();
+        let (policy, value, wdl) = match self.cache.get(&canonical) {
+            Some(cached) if cached.2.is_some() || !wdl_wanted => cached.clone(),
+            cached => {
+                let cached = cached.cloned();
+                // A WDL head gives the value too, so one evaluation covers both
+                let evaluation = match (self.agent.eval_game_wdl(&canonical), cached) {
+                    (Some((policy, wdl)), _) => (policy, wdl.expected_score(0.0), Some(wdl)),
+                    (None, Some(cached)) => cached,
+                    (None, None) => {
+                        let (policy, value) = self.agent.eval_game(&canonical);
+                        (policy, value, None)
+                    }
+                };
+                self.cache.insert(canonical, evaluation.clone());
+                evaluation
+            }
+        };
+        (G::transform_raw_policy(&policy, symmetry.inverse()), value, wdl)
+    }
+}
+
+impl<G: Game<N> + Eq + Hash, A: Agent<G, N>, const N: usize> Agent<G, N> for CachedAgent<G, A, N> {
+    fn eval_game(&mut self, game: &G) -> (RawPolicy<N>, f32) {
+        let (policy, value, _) = self.lookup(game, false);
+        (policy, value)
+    }
+
+    fn eval_game_wdl(&mut self, game: &G) -> Option<(RawPolicy<N>, Wdl)> {
+        let (policy, _, wdl) = self.lookup(game, true);
+        wdl.map(|wdl| (policy, wdl))
+    }
+
+    /// Positions missing from the cache are evaluated as one batch, once for each
+    /// canonical form, and cached without win/draw/loss chances, see `lookup`.
+    fn eval_games(&mut self, games: &[G]) -> Vec<(RawPolicy<N>, f32)> {
+        let canonicals: Vec<(G, G::Symmetry)> = games.iter().map(|game| game.canonical()).collect();
+        let mut queued = HashSet::new();
+        let misses: Vec<G> = canonicals
+            .iter()
+            .map(|(canonical, _)| *canonical)
+            .filter(|canonical| !self.cache.contains_key(canonical) && queued.insert(*canonical))
+            .collect();
+        let evaluated = self.agent.eval_games(&misses);
+        for (canonical, (policy, value)) in misses.into_iter().zip(evaluated) {
+            self.cache.insert(canonical, (policy, value, None));
+        }
+        canonicals
+            .iter()
+            .map(|(canonical, symmetry)| {
+                let (policy, value, _) = &self.cache[canonical];
+                (G::transform_raw_policy(policy, symmetry.inverse()), *value)
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "tch")]
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<N>, f32) {
+        self.agent.eval_features(features)
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::data::ReplayBuffer;
+#[cfg(feature = "tch")]
+use crate::data::ReplayBufferTensorData;
+use crate::game::{FeatureEncoder, Game};
+use crate::inference::CpuTensor;
+use crate::policy::RawPolicy;
+#[cfg(feature = "tch")]
+use tch::TchError;
+
+/// The largest magnitude of a rounded weight or input
+const INT8_MAX: f32 = i8::MAX as f32;
+
+/// Layers of the CPU backend that can run in int8 after a calibration pass, a float
+/// forward pass that shows their inputs to an `InputRanges`
+pub trait Quantize {
+    /// Goes back to float, before a calibration pass
+    fn reset_calibration(&mut self);
+
+    /// Rounds the weights to int8, and from then on the inputs too, scaled so the largest
+    /// input `ranges` saw for this layer just fits
+    fn quantize(&mut self, ranges: &InputRanges);
+
+    fn is_quantized(&self) -> bool;
+
+    /// Where the weights were loaded from, which the layer's input range is kept under
+    fn path(&self) -> &str;
+}
+
+/// Sees the inputs of each `Quantize` layer on a float forward pass
+pub trait Observer {
+    fn observe(&mut self, layer: &dyn Quantize, xs: &[f32]);
+}
+
+/// Forward passes that aren't calibrating look at nothing
+impl Observer for () {
+    fn observe(&mut self, _: &dyn Quantize, _: &[f32]) {}
+}
+
+/// The largest input magnitude each layer saw over a calibration pass. Layers are told
+/// apart by their weight paths, so the ranges fit any copy of the calibrated network.
+#[derive(Debug, Default)]
+pub struct InputRanges {
+    ranges: HashMap<String, f32>,
+}
+
+impl InputRanges {
+    pub fn range_of(&self, layer: &dyn Quantize) -> Option<f32> {
+        self.ranges.get(layer.path()).copied()
+    }
+}
+
+impl Observer for InputRanges {
+    fn observe(&mut self, layer: &dyn Quantize, xs: &[f32]) {
+        let range = max_abs(xs);
+        match self.ranges.get_mut(layer.path()) {
+            Some(max) => *max = max.max(range),
+            None => {
+                self.ranges.insert(layer.path().to_string(), range);
+            }
+        }
+    }
+}
+
+/// Weights rounded to int8 with one scale per output, with the scale inputs are rounded
+/// with. Outputs are summed in i32 and scaled back to floats.
+#[derive(Debug, Clone)]
+pub struct Int8Weights {
+    /// Laid out like the float weights, [out, ..], so each output sums over one row
+    pub values: Vec<i8>,
+    pub scales: Vec<f32>,
+    pub input_scale: f32,
+    /// The weight and input scales of each output multiplied together
+    sum_scales: Vec<f32>,
+}
+
+impl Int8Weights {
+    /// Rounds `weight`, of shape [out, ..], for inputs up to `input_range` in magnitude
+    pub fn new(weight: &CpuTensor, input_range: f32) -> Self {
+        let row = weight.data.len() / weight.shape[0];
+        let mut values = Vec::with_capacity(weight.data.len());
+        let mut scales = Vec::with_capacity(weight.shape[0]);
+        for weights in weight.data.chunks_exact(row) {
+            let scale = scale_for(weights.iter().fold(0.0, |max, w| f32::max(max, w.abs())));
+            values.extend(weights.iter().map(|w| round(w / scale)));
+            scales.push(scale);
+        }
+        let input_scale = scale_for(input_range);
+        let sum_scales = scales.iter().map(|scale| scale * input_scale).collect();
+        Self { values, scales, input_scale, sum_scales }
+    }
+
+    /// Overwrites `out` with `xs` rounded to int8, those beyond the calibrated range
+    /// saturating
+    pub fn quantize_inputs(&self, xs: &[f32], out: &mut Vec<i8>) {
+        let inverse_scale = self.input_scale.recip();
+        out.clear();
+        out.extend(xs.iter().map(|x| round(x * inverse_scale)));
+    }
+
+    /// The float an i32 sum of output `o` stands for
+    pub fn dequantize(&self, o: usize, sum: i32) -> f32 {
+        sum as f32 * self.sum_scales[o]
+    }
+}
+
+/// The i32 sum of the products of two int8 rows, which the compiler turns into SIMD
+/// multiply-adds
+pub fn dot(xs: &[i8], ys: &[i8]) -> i32 {
+    xs.iter().zip(ys).map(|(&x, &y)| i32::from(x) * i32::from(y)).sum()
+}
+
+/// The scale mapping `range` onto int8, 1 for an all-zero range so nothing divides by 0
+fn scale_for(range: f32) -> f32 {
+    match range > 0.0 {
+        true => range / INT8_MAX,
+        false => 1.0,
+    }
+}
+
+fn round(x: f32) -> i8 {
+    x.round().clamp(-INT8_MAX, INT8_MAX) as i8
+}
+
+/// Largest magnitude in `xs`
+pub fn max_abs(xs: &[f32]) -> f32 {
+    xs.iter().fold(0.0, |max, x| f32::max(max, x.abs()))
+}
+
+/// Encoded positions with their legal moves, which quantized networks are calibrated and
+/// measured on
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationSet {
+    features: Vec<f32>,
+    legal_masks: Vec<bool>,
+    feature_size: usize,
+    policy_size: usize,
+}
+
+impl CalibrationSet {
+    /// The positions of `buffer`, encoded by `E`
+    pub fn from_replay<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(buffer: &ReplayBuffer<G, N>) -> Self {
+        Self {
+            features: E::encode_batch_into(&buffer.games),
+            legal_masks: buffer.games.iter().flat_map(|game| game.legal_mask()).collect(),
+            feature_size: E::SHAPE.iter().product::<i64>() as usize,
+            policy_size: N,
+        }
+    }
+
+    /// The positions of a replay dataset, once it's checked to be encoded by `E`
+    #[cfg(feature = "tch")]
+    pub fn from_tensor_data<E: FeatureEncoder<G, N>, G: Game<N>, const N: usize>(
+        data: &ReplayBufferTensorData,
+    ) -> Result<Self, TchError> {
+        data.check_encoding::<E, G, N>()?;
+        Ok(Self {
+            features: Vec::<f32>::try_from(&data.features.to_kind(tch::Kind::Float).reshape([-1]))?,
+            legal_masks: Vec::<bool>::try_from(&data.legal_mask.reshape([-1]))?,
+            feature_size: E::SHAPE.iter().product::<i64>() as usize,
+            policy_size: N,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.legal_masks.len() / self.policy_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.legal_masks.is_empty()
+    }
+
+    /// The features and legal moves of each position
+    pub fn iter(&self) -> impl Iterator<Item = (&[f32], &[bool])> {
+        self.features
+            .chunks_exact(self.feature_size)
+            .zip(self.legal_masks.chunks_exact(self.policy_size))
+    }
+}
+
+/// How far a quantized network's outputs are from the float network's, averaged over a
+/// `CalibrationSet`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct QuantizationReport {
+    pub positions: usize,
+    /// Mean KL divergence of the quantized policy from the float one, in nats
+    pub policy_kl: f32,
+    /// Mean squared difference of the value scores
+    pub value_mse: f32,
+}
+
+impl QuantizationReport {
+    /// Averages over pairs of the float and the quantized policy and value of a position
+    pub fn measure<const N: usize>(outputs: impl IntoIterator<Item = ((RawPolicy<N>, f32), (RawPolicy<N>, f32))>) -> Self {
+        let mut report = Self::default();
+        for ((policy, value), (quantized_policy, quantized_value)) in outputs {
+            report.positions += 1;
+            report.policy_kl += kl_divergence(&policy[..], &quantized_policy[..]);
+            report.value_mse += (value - quantized_value).powi(2);
+        }
+        if report.positions > 0 {
+            report.policy_kl /= report.positions as f32;
+            report.value_mse /= report.positions as f32;
+        }
+        report
+    }
+}
+
+impl fmt::Display for QuantizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "policy KL {:.2e}, value MSE {:.2e} over {} positions",
+            self.policy_kl, self.value_mse, self.positions
+        )
+    }
+}
+
+/// KL(p || q) of two distributions over the same moves, moves `p` rules out adding nothing
+pub fn kl_divergence(p: &[f32], q: &[f32]) -> f32 {
+    p.iter()
+        .zip(q)
+        .filter(|(&p, _)| p > 0.0)
+        .map(|(&p, &q)| p * (p / q.max(f32::MIN_POSITIVE)).ln())
+        .sum()
+}
@@ -1,4 +1,8 @@
 fn main() {
+    // Nothing to link when playing on the pure-Rust backend
+    if std::env::var_os("CARGO_FEATURE_TCH").is_none() {
+        return;
+    }
     if let Some(lib_path) = std::env::var_os("DEP_TCH_LIBTORCH_LIB") {
         println!("cargo:rustc-link-arg=-Wl,-rpath={}", lib_path.to_string_lossy());
     }
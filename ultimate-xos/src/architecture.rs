@@ -0,0 +1,285 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "tch")]
+use std::path::Path;
+
+#[cfg(feature = "tch")]
+use crate::features::{XOEncoding, XORichFeatures};
+#[cfg(feature = "tch")]
+use crate::game::XOGame;
+#[cfg(feature = "tch")]
+use crate::policies::XONNAgent;
+#[cfg(feature = "tch")]
+use crate::transformer::XOTransformerAgent;
+#[cfg(feature = "tch")]
+use crate::two_level::XOTwoLevelAgent;
+#[cfg(feature = "tch")]
+use sigmazero::checkpoint::{load_checkpoint, Manifest, ManifestError};
+#[cfg(feature = "tch")]
+use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+use sigmazero::policy::{NetworkConfig, ARCHITECTURE_VAR};
+#[cfg(feature = "tch")]
+use sigmazero::policy::{Agent, NNAgent, RawPolicy, Wdl};
+#[cfg(feature = "tch")]
+use tch::{nn, TchError, Tensor};
+
+/// Which XO network a `VarStore` holds. Stored next to the network's own config, and
+/// checkpoints from before there was a choice are read as `ResNet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XOArchitecture {
+    #[default]
+    ResNet,
+    TwoLevel,
+    Transformer,
+}
+
+impl XOArchitecture {
+    pub const ALL: [XOArchitecture; 3] = [Self::ResNet, Self::TwoLevel, Self::Transformer];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::ResNet => "resnet",
+            Self::TwoLevel => "two-level",
+            Self::Transformer => "transformer",
+        }
+    }
+}
+
+impl NetworkConfig for XOArchitecture {
+    const VAR: &'static str = ARCHITECTURE_VAR;
+
+    fn to_vec(&self) -> Vec<f32> {
+        vec![*self as u8 as f32]
+    }
+
+    fn from_slice(values: &[f32]) -> Self {
+        match values {
+            [tag] => *Self::ALL
+                .get(*tag as usize)
+                .unwrap_or_else(|| panic!("Unknown XO architecture {}", tag)),
+            _ => panic!("Expected a single architecture tag, got {:?}", values),
+        }
+    }
+}
+
+impl fmt::Display for XOArchitecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for XOArchitecture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|architecture| architecture.name() == s)
+            .ok_or_else(|| format!("Unknown XO architecture {:?}, expected one of {:?}", s, Self::ALL.map(|a| a.name())))
+    }
+}
+
+/// Any of the XO networks, picked by the `XOArchitecture` its `VarStore` holds. Store
+/// one before `new` to choose, e.g. ahead of `train_on_replay`.
+#[cfg(feature = "tch")]
+pub enum XOModel<E = DefaultFeatures, const WDL: bool = false> {
+    ResNet(XONNAgent<E, WDL>),
+    TwoLevel(XOTwoLevelAgent<E, WDL>),
+    Transformer(XOTransformerAgent<E, WDL>),
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> XOModel<E, WDL> {
+    pub fn architecture(&self) -> XOArchitecture {
+        match self {
+            Self::ResNet(_) => XOArchitecture::ResNet,
+            Self::TwoLevel(_) => XOArchitecture::TwoLevel,
+            Self::Transformer(_) => XOArchitecture::Transformer,
+        }
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> NNAgent<XOGame, 81> for XOModel<E, WDL> {
+    type Encoder = E;
+    const WDL_HEAD: bool = WDL;
+
+    fn new(vs: &nn::VarStore) -> Self {
+        let architecture = XOArchitecture::from_var_store(vs).unwrap_or_default();
+        architecture.store(vs);
+        match architecture {
+            XOArchitecture::ResNet => Self::ResNet(XONNAgent::new(vs)),
+            XOArchitecture::TwoLevel => Self::TwoLevel(XOTwoLevelAgent::new(vs)),
+            XOArchitecture::Transformer => Self::Transformer(XOTransformerAgent::new(vs)),
+        }
+    }
+
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, train: bool) -> (Tensor, Tensor) {
+        match self {
+            Self::ResNet(agent) => agent.forward(xs, legal_mask, train),
+            Self::TwoLevel(agent) => agent.forward(xs, legal_mask, train),
+            Self::Transformer(agent) => agent.forward(xs, legal_mask, train),
+        }
+    }
+
+    fn architecture(vs: &nn::VarStore) -> String {
+        XOArchitecture::from_var_store(vs).unwrap_or_default().name().to_string()
+    }
+
+    fn builds_architecture(architecture: &str) -> bool {
+        architecture.parse::<XOArchitecture>().is_ok()
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> Agent<XOGame, 81> for XOModel<E, WDL> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        match self {
+            Self::ResNet(agent) => agent.eval_game(game),
+            Self::TwoLevel(agent) => agent.eval_game(game),
+            Self::Transformer(agent) => agent.eval_game(game),
+        }
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        match self {
+            Self::ResNet(agent) => agent.eval_features(features),
+            Self::TwoLevel(agent) => agent.eval_features(features),
+            Self::Transformer(agent) => agent.eval_features(features),
+        }
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        match self {
+            Self::ResNet(agent) => agent.eval_game_wdl(game),
+            Self::TwoLevel(agent) => agent.eval_game_wdl(game),
+            Self::Transformer(agent) => agent.eval_game_wdl(game),
+        }
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        match self {
+            Self::ResNet(agent) => agent.eval_games(games),
+            Self::TwoLevel(agent) => agent.eval_games(games),
+            Self::Transformer(agent) => agent.eval_games(games),
+        }
+    }
+}
+
+/// Any of the XO networks with either encoder, picked by the `XOEncoding` the manifest
+/// of its checkpoint records
+#[cfg(feature = "tch")]
+pub enum XOEncodedModel<const WDL: bool = false> {
+    Default(XOModel<DefaultFeatures, WDL>),
+    Rich(XOModel<XORichFeatures, WDL>),
+}
+
+#[cfg(feature = "tch")]
+impl<const WDL: bool> XOEncodedModel<WDL> {
+    /// Builds the checkpoint at `path` with the encoder and architecture it records
+    pub fn load(path: &Path, device: tch::Device) -> Result<(Self, nn::VarStore, Manifest), TchError> {
+        let manifest = Manifest::load(path)?;
+        let encoding = manifest.encoding.parse().map_err(ManifestError::Mismatch)?;
+        Ok(match encoding {
+            XOEncoding::Default => {
+                let (model, vs, manifest) = load_checkpoint::<XOModel<_, WDL>, XOGame, 81>(path, device)?;
+                (Self::Default(model), vs, manifest)
+            }
+            XOEncoding::Rich => {
+                let (model, vs, manifest) = load_checkpoint::<XOModel<_, WDL>, XOGame, 81>(path, device)?;
+                (Self::Rich(model), vs, manifest)
+            }
+        })
+    }
+
+    pub fn encoding(&self) -> XOEncoding {
+        match self {
+            Self::Default(_) => XOEncoding::Default,
+            Self::Rich(_) => XOEncoding::Rich,
+        }
+    }
+
+    pub fn architecture(&self) -> XOArchitecture {
+        match self {
+            Self::Default(model) => model.architecture(),
+            Self::Rich(model) => model.architecture(),
+        }
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<const WDL: bool> Agent<XOGame, 81> for XOEncodedModel<WDL> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        match self {
+            Self::Default(model) => model.eval_game(game),
+            Self::Rich(model) => model.eval_game(game),
+        }
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        match self {
+            Self::Default(model) => model.eval_features(features),
+            Self::Rich(model) => model.eval_features(features),
+        }
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        match self {
+            Self::Default(model) => model.eval_game_wdl(game),
+            Self::Rich(model) => model.eval_game_wdl(game),
+        }
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        match self {
+            Self::Default(model) => model.eval_games(games),
+            Self::Rich(model) => model.eval_games(games),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tch")]
+    use crate::transformer::TransformerConfig;
+    #[cfg(feature = "tch")]
+    use sigmazero::policy::restore_config;
+
+    #[test]
+    fn test_architecture_names() {
+        for architecture in XOArchitecture::ALL {
+            assert_eq!(architecture.name().parse(), Ok(architecture));
+            assert_eq!(XOArchitecture::from_slice(&architecture.to_vec()), architecture);
+        }
+        assert!("mlp".parse::<XOArchitecture>().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_model_follows_stored_architecture() {
+        let device = tch::Device::Cpu;
+        let vs = nn::VarStore::new(device);
+        assert_eq!(<XOModel>::new(&vs).architecture(), XOArchitecture::ResNet);
+
+        let config = TransformerConfig { layers: 1, dim: 16, heads: 2, mlp_dim: 32 };
+        let vs1 = nn::VarStore::new(device);
+        XOArchitecture::Transformer.store(&vs1);
+        config.store(&vs1);
+        let mut model1 = <XOModel>::new(&vs1);
+        assert_eq!(model1.architecture(), XOArchitecture::Transformer);
+        let game = XOGame::default();
+        let eval1 = model1.eval_game(&game);
+        let path = std::env::temp_dir().join("xo_architecture_test.ot");
+        vs1.save(&path).expect("Save Failed");
+
+        let mut vs2 = nn::VarStore::new(device);
+        restore_config(&vs2, &path).expect("Config load failed");
+        let mut model2 = <XOModel>::new(&vs2);
+        vs2.load(&path).expect("Model load failed");
+        assert_eq!(model2.architecture(), XOArchitecture::Transformer);
+        assert_eq!(TransformerConfig::from_var_store(&vs2), Some(config));
+        assert_eq!(model2.eval_game(&game), eval1);
+    }
+}
@@ -1,13 +1,14 @@
 use colored::Colorize;
-use rand::seq::SliceRandom;
 use sigmazero::game::{Position, PositionList};
 use std::fmt;
 
+use crate::rules::{FullBoardRule, Ruleset, WonBoardRule};
 use crate::small_board::Board as SmallBoard;
-use crate::small_board::Position3;
+use crate::small_board::{Position3, WINNING};
+pub use crate::symmetry::XOSymmetry;
 pub use crate::small_board::XOPlayer;
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct XOPosition {
     x: u8,
     y: u8,
@@ -15,7 +16,7 @@ pub struct XOPosition {
 
 impl Position for XOPosition {
     fn new(x: u8, y: u8) -> Self {
-        Self { x: x, y: y }
+        Self { x, y }
     }
 
     fn is_valid(&self) -> bool {
@@ -27,28 +28,23 @@ impl Position for XOPosition {
 }
 
 impl XOPosition {
-    fn large_pos(&self) -> Position3 {
+    pub fn large_pos(&self) -> Position3 {
         Position3::new(self.x / 3, self.y / 3)
     }
 
-    fn small_pos(&self) -> Position3 {
+    pub fn small_pos(&self) -> Position3 {
         Position3::new(self.x % 3, self.y % 3)
     }
 
-    fn from_subpos(large_pos: Position3, small_pos: Position3) -> Self {
+    pub fn from_subpos(large_pos: Position3, small_pos: Position3) -> Self {
         Self {
             x: small_pos.x + 3 * large_pos.x,
             y: small_pos.y + 3 * large_pos.y,
         }
     }
-    
-    fn rot_90(&self) -> Self {
-        Self::new(8-self.y, self.x)
-    }
-    
-    /// Reflects the x coordinate, y coordinate is unchanged
-    fn reflect_vertical(&self) -> Self {
-        Self::new(8-self.x, self.y)
+
+    pub fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        Self::from(symmetry.permute_cell((*self).into()))
     }
 }
 
@@ -105,14 +101,28 @@ pub type XOPositionList = PositionList<XOPosition>;
 //     }
 // }
 
-#[derive(Clone, Copy)]
+/// The classic 9x9 board, kept on bitboards for speed. `NestedBoard` plays the same
+/// rules on other sizes and depths.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct MainBoard {
     small_boards: [SmallBoard; 9],
     board: SmallBoard,
     last_move: Option<XOPosition>,
+    ruleset: Ruleset,
 }
 
 impl MainBoard {
+    pub fn with_ruleset(ruleset: Ruleset) -> Self {
+        Self {
+            ruleset,
+            ..Default::default()
+        }
+    }
+
+    pub fn ruleset(&self) -> Ruleset {
+        self.ruleset
+    }
+
     pub fn get_cell(&self, position: &XOPosition) -> Option<XOPlayer> {
         self.small_boards[position.large_pos().flat() as usize].get_cell(&position.small_pos())
     }
@@ -120,46 +130,90 @@ impl MainBoard {
     pub fn set_cell(&mut self, position: &XOPosition, player: XOPlayer) {
         let small_board = &mut self.small_boards[position.large_pos().flat() as usize];
         small_board.set_cell(&position.small_pos(), player);
-        match small_board.winner() {
-            Some(winner) => self.board.set_cell(&position.large_pos(), winner),
-            None => (),
-        };
+        // A small board belongs to whoever won it first, even if play continues there
+        if self.board.get_cell(&position.large_pos()).is_none() {
+            if let Some(winner) = small_board.winner() {
+                self.board.set_cell(&position.large_pos(), winner)
+            }
+        }
         self.last_move = Some(*position);
     }
 
+    /// Who won the small board at `large_pos`, if anyone has
+    pub fn board_winner(&self, large_pos: &Position3) -> Option<XOPlayer> {
+        self.board.get_cell(large_pos)
+    }
+
+    /// Whether the small board at `large_pos` filled up without a winner
+    pub fn is_board_drawn(&self, large_pos: &Position3) -> bool {
+        self.board_winner(large_pos).is_none() && self.small_boards[large_pos.flat() as usize].is_full()
+    }
+
+    pub fn last_move(&self) -> Option<XOPosition> {
+        self.last_move
+    }
+
+    /// The player after whoever made the last move, X on an empty board
+    pub fn next_player(&self) -> XOPlayer {
+        self.last_move
+            .and_then(|last_move| self.get_cell(&last_move))
+            .map_or(XOPlayer::X, |player| player.other_player())
+    }
+
     pub fn winner(&self) -> Option<XOPlayer> {
-        self.board.winner()
+        if let Some(winner) = self.board.winner() {
+            return Some(winner);
+        }
+        match self.ruleset.full_board {
+            FullBoardRule::Draw => None,
+            FullBoardRule::MostBoards => {
+                if !self.available_cells().is_empty() {
+                    return None;
+                }
+                let x_boards = self.board.count_player(XOPlayer::X);
+                let o_boards = self.board.count_player(XOPlayer::O);
+                if x_boards > o_boards {
+                    Some(XOPlayer::X)
+                } else if o_boards > x_boards {
+                    Some(XOPlayer::O)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Cells of a small board that can still be played in under the ruleset
+    pub fn open_cells(&self, large_pos: &Position3) -> Vec<Position3> {
+        let small_board = &self.small_boards[large_pos.flat() as usize];
+        match self.ruleset.won_board {
+            WonBoardRule::FreeMove => small_board.valid_moves(),
+            WonBoardRule::PlayOn => small_board.empty_cells(),
+        }
     }
 
     pub fn is_valid_move(&self, position: &XOPosition) -> bool {
         if !position.is_valid() {
             return false;
         }
-        // If not first move
-        match &self.last_move {
-            Some(last_move) => {
-                let target_small_board = &self.small_boards[last_move.small_pos().flat() as usize];
-                if position.large_pos() != last_move.small_pos() {
-                    if !(target_small_board.valid_moves().len() == 0) {
-                        return false;
-                    };
-                } else {
-                    return target_small_board.get_cell(&position.small_pos()).is_none();
-                }
+        // If not first move and target small board is still open
+        if let Some(last_move) = &self.last_move {
+            let target_pos = last_move.small_pos();
+            if position.large_pos() != target_pos && !self.open_cells(&target_pos).is_empty() {
+                return false;
             }
-            None => (),
         }
-        // If first move or target small board is full
-        self.get_cell(&position).is_none()
+        self.open_cells(&position.large_pos())
+            .contains(&position.small_pos())
     }
 
-    fn available_cells(&self) -> XOPositionList {
+    pub fn available_cells(&self) -> XOPositionList {
         let mut available_cells = Vec::new();
         for i in 0..9 {
-            let small_board_valid_moves = self.small_boards[i].valid_moves();
             let large_pos = Position3::from_flat(i as u8);
+            let small_board_valid_moves = self.open_cells(&large_pos);
             for small_pos in small_board_valid_moves {
-                available_cells.push(XOPosition::from_subpos(large_pos.clone(), small_pos))
+                available_cells.push(XOPosition::from_subpos(large_pos, small_pos))
             }
         }
         XOPositionList::new(available_cells)
@@ -167,16 +221,14 @@ impl MainBoard {
 
     pub fn valid_moves(&self) -> XOPositionList {
         match &self.last_move {
-            None => {
-                return self.available_cells();
-            }
+            None => self.available_cells(),
             Some(last_move) => {
-                let target_small_board = &self.small_boards[last_move.small_pos().flat() as usize];
-                if target_small_board.valid_moves().len() == 0 {
-                    return self.available_cells();
+                let target_valid_moves = self.open_cells(&last_move.small_pos());
+                if target_valid_moves.is_empty() {
+                    self.available_cells()
                 } else {
                     let mut cells = Vec::new();
-                    for p_small in target_small_board.valid_moves() {
+                    for p_small in target_valid_moves {
                         cells.push(XOPosition::from_subpos(last_move.small_pos(), p_small))
                     }
                     XOPositionList::new(cells)
@@ -186,8 +238,30 @@ impl MainBoard {
     }
 
     pub fn is_draw(&self) -> bool {
-        // FIXME If the only move available leads to draw (i.e. fills in the target small board) then this doesn't seem to work??
-        self.available_cells().len() == 0
+        if self.winner().is_some() {
+            return false;
+        }
+        if self.available_cells().is_empty() {
+            return true;
+        }
+        // Adjudicate early when no meta line is reachable. Won board counts can
+        // still decide the game under `MostBoards`, so play those out.
+        self.ruleset.full_board == FullBoardRule::Draw
+            && !self.can_complete_line(XOPlayer::X)
+            && !self.can_complete_line(XOPlayer::O)
+    }
+
+    /// Whether `player` can still make a line on the meta board, given which
+    /// small boards are won, drawn or still winnable
+    pub fn can_complete_line(&self, player: XOPlayer) -> bool {
+        WINNING.iter().any(|win_case| {
+            (0..9u8)
+                .filter(|i| (win_case >> i) & 1 == 1)
+                .all(|i| match self.board.get_cell(&Position3::from_flat(i)) {
+                    Some(owner) => owner == player,
+                    None => self.small_boards[i as usize].can_still_win(player),
+                })
+        })
     }
 
     pub fn features_for_player(&self, player: XOPlayer) -> [[[i64; 9]; 9]; 3] {
@@ -195,16 +269,12 @@ impl MainBoard {
         let mut arr: [[[i64; 9]; 9]; 3] = [[[0; 9]; 9]; 3];
         for y in 0..9 {
             for x in 0..9 {
-                let cell = self.get_cell(&XOPosition::new(x, y));
-                match cell {
-                    Some(p) => {
-                        if p == player {
-                            arr[0][y as usize][x as usize] = 1
-                        } else {
-                            arr[1][y as usize][x as usize] = 1
-                        }
+                if let Some(p) = self.get_cell(&XOPosition::new(x, y)) {
+                    if p == player {
+                        arr[0][y as usize][x as usize] = 1
+                    } else {
+                        arr[1][y as usize][x as usize] = 1
                     }
-                    None => (),
                 }
             }
         }
@@ -214,51 +284,43 @@ impl MainBoard {
         arr
     }
 
-    fn rotated_90(&self) -> Self {
-        let mut rotated = Self::default();
-        // Temporary
-        for x in 0..9 {
-            for y in 0..9 {
-                let pos = XOPosition::new(x, y);
-                if let Some(player) = self.get_cell(&pos) {
-                    rotated.set_cell(&pos.rot_90(), player);
-                }
-            }
+    pub fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        let mut small_boards = [SmallBoard::default(); 9];
+        for (i, small_board) in self.small_boards.iter().enumerate() {
+            small_boards[symmetry.permute_small_cell(i)] = small_board.transformed(symmetry);
         }
-        rotated.last_move = self.last_move.map(|p| p.rot_90());
-        rotated
-    }
-
-    fn reflected_vertical(&self) -> Self {
-        let mut reflected = Self::default();
-        // Temporary
-        for x in 0..9 {
-            for y in 0..9 {
-                let pos = XOPosition::new(x, y);
-                if let Some(player) = self.get_cell(&pos) {
-                    reflected.set_cell(&pos.reflect_vertical(), player);
-                }
-            }
+        Self {
+            small_boards,
+            board: self.board.transformed(symmetry),
+            last_move: self.last_move.map(|p| p.transformed(symmetry)),
+            ruleset: self.ruleset,
         }
-        reflected.last_move = self.last_move.map(|p| p.reflect_vertical());
-        reflected
     }
 
-    pub fn augmented(&self) -> Vec<Self> {
-        let mut augmented = vec![self.clone()];
-        
-        // add 3 rotations
-        for r in 1..4 {
-            augmented.push(augmented[r-1].rotated_90());
-        }
-        // and their mirrors
-        augmented.push(augmented[0].reflected_vertical());
-        for r in 5..8 {
-            augmented.push(augmented[r-1].rotated_90());
-        }
+    /// Orders boards for picking a canonical one among symmetric images
+    fn symmetry_key(&self) -> ([[u16; 2]; 9], [u16; 2], Option<usize>) {
+        (
+            self.small_boards.map(|b| b.bitboards()),
+            self.board.bitboards(),
+            self.last_move.map(|p| p.into()),
+        )
+    }
+
+    /// The smallest of the 8 symmetric images, and the symmetry that maps this board onto it
+    pub fn canonical(&self) -> (Self, XOSymmetry) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transformed(symmetry), symmetry))
+            .min_by_key(|(board, _)| board.symmetry_key())
+            .unwrap()
+    }
 
+    pub fn augmented(&self) -> Vec<Self> {
         // 8 boards
-        augmented
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| self.transformed(symmetry))
+            .collect()
     }
 }
 
@@ -269,15 +331,9 @@ impl fmt::Display for MainBoard {
                 let pos = XOPosition::new(x as u8, y as u8);
                 let cell = self.get_cell(&pos);
 
-                let last_move_mark = match self.last_move.clone() {
-                    Some(last_move) => {
-                        if last_move == pos {
-                            "-"
-                        } else {
-                            " "
-                        }
-                    }
-                    None => " ",
+                let last_move_mark = match self.last_move() {
+                    Some(last_move) if last_move == pos => "-",
+                    _ => " ",
                 };
                 let p = match cell {
                     Some(player) => {
@@ -311,16 +367,6 @@ impl fmt::Display for MainBoard {
     }
 }
 
-impl Default for MainBoard {
-    fn default() -> Self {
-        Self {
-            small_boards: [SmallBoard::default(); 9],
-            board: SmallBoard::default(),
-            last_move: None,
-        }
-    }
-}
-
 pub struct BoardDisplayer {
     items: Vec<String>,
 }
@@ -360,28 +406,6 @@ impl fmt::Display for BoardDisplayer {
     }
 }
 
-pub fn play_random_game() -> Option<XOPlayer> {
-    let mut board = MainBoard::default();
-    let mut rng = rand::thread_rng();
-    let mut player = XOPlayer::X;
-
-    loop {
-        match board.valid_moves().choose(&mut rng) {
-            Some(mv) => board.set_cell(mv, player),
-            None => break None,
-        }
-        // println!("{board}");
-        match board.winner() {
-            Some(winner) => {
-                println!("Player {winner} wins!");
-                break Some(winner);
-            }
-            None => (),
-        }
-        player = player.other_player();
-    }
-}
-
 #[test]
 fn test_draw_by_filling_last_target_board() {
     let mut board = MainBoard::default();
@@ -392,15 +416,15 @@ fn test_draw_by_filling_last_target_board() {
         let large_pos = Position3::from_flat(board_idx);
         // Create diagonal win for X in each board
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(0, 0)),
+            &XOPosition::from_subpos(large_pos, Position3::new(0, 0)),
             XOPlayer::X,
         );
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(1, 1)),
+            &XOPosition::from_subpos(large_pos, Position3::new(1, 1)),
             XOPlayer::X,
         );
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(2, 2)),
+            &XOPosition::from_subpos(large_pos, Position3::new(2, 2)),
             XOPlayer::X,
         );
     }
@@ -410,15 +434,15 @@ fn test_draw_by_filling_last_target_board() {
         let large_pos = Position3::from_flat(board_idx);
         // Create vertical win for O in each board
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(1, 0)),
+            &XOPosition::from_subpos(large_pos, Position3::new(1, 0)),
             XOPlayer::O,
         );
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(1, 1)),
+            &XOPosition::from_subpos(large_pos, Position3::new(1, 1)),
             XOPlayer::O,
         );
         board.set_cell(
-            &XOPosition::from_subpos(large_pos.clone(), Position3::new(1, 2)),
+            &XOPosition::from_subpos(large_pos, Position3::new(1, 2)),
             XOPlayer::O,
         );
     }
@@ -439,7 +463,7 @@ fn test_draw_by_filling_last_target_board() {
 
     for (x, y, player) in moves {
         board.set_cell(
-            &XOPosition::from_subpos(target_board_pos.clone(), Position3::new(x, y)),
+            &XOPosition::from_subpos(target_board_pos, Position3::new(x, y)),
             player,
         );
     }
@@ -451,8 +475,11 @@ fn test_draw_by_filling_last_target_board() {
     );
     println!("{}", board);
 
-    // Verify setup
-    assert!(!board.is_draw(), "Board should not be in draw state yet");
+    // Verify setup. Neither player can make a meta line through the centre,
+    // so the position is already adjudicated as a draw.
+    assert!(!board.can_complete_line(XOPlayer::X));
+    assert!(!board.can_complete_line(XOPlayer::O));
+    assert!(board.is_draw(), "Dead position should be a draw before the board is full");
     assert!(
         board.winner().is_none(),
         "There should be no winner before final move"
@@ -471,3 +498,157 @@ fn test_draw_by_filling_last_target_board() {
     );
     assert!(board.winner().is_none(), "There should be no winner");
 }
+
+#[test]
+fn test_play_on_in_won_board() {
+    let won_large_pos = Position3::from_flat(0);
+    let mut free_move_board = MainBoard::default();
+    let mut play_on_board =
+        MainBoard::with_ruleset(Ruleset::new(WonBoardRule::PlayOn, FullBoardRule::Draw));
+    for board in [&mut free_move_board, &mut play_on_board] {
+        for x in 0..3 {
+            board.set_cell(
+                &XOPosition::from_subpos(won_large_pos, Position3::new(x, 0)),
+                XOPlayer::X,
+            );
+        }
+        // Send the next player to the won board
+        board.set_cell(
+            &XOPosition::from_subpos(Position3::from_flat(4), Position3::new(0, 0)),
+            XOPlayer::O,
+        );
+    }
+
+    assert_eq!(free_move_board.valid_moves().len(), 81 - 9 - 1);
+    let play_on_moves = play_on_board.valid_moves();
+    assert_eq!(play_on_moves.len(), 6);
+    assert!(play_on_moves
+        .iter()
+        .all(|p| p.large_pos() == won_large_pos));
+    assert!(!play_on_board.is_valid_move(&XOPosition::new(4, 4)));
+
+    // Completing a line for the other player doesn't change the board's owner
+    for x in 0..3 {
+        play_on_board.set_cell(
+            &XOPosition::from_subpos(won_large_pos, Position3::new(x, 1)),
+            XOPlayer::O,
+        );
+    }
+    assert_eq!(play_on_board.board.get_cell(&won_large_pos), Some(XOPlayer::X));
+}
+
+#[test]
+fn test_full_board_decided_by_most_boards() {
+    let mut draw_board = MainBoard::default();
+    let mut most_boards_board =
+        MainBoard::with_ruleset(Ruleset::new(WonBoardRule::FreeMove, FullBoardRule::MostBoards));
+    for board in [&mut draw_board, &mut most_boards_board] {
+        // X takes 5 boards and O takes 4 without either making a line
+        for (board_idx, player) in [
+            (0, XOPlayer::X),
+            (1, XOPlayer::O),
+            (2, XOPlayer::X),
+            (3, XOPlayer::X),
+            (4, XOPlayer::X),
+            (5, XOPlayer::O),
+            (6, XOPlayer::O),
+            (7, XOPlayer::X),
+            (8, XOPlayer::O),
+        ] {
+            let large_pos = Position3::from_flat(board_idx);
+            for i in 0..3 {
+                board.set_cell(
+                    &XOPosition::from_subpos(large_pos, Position3::new(i, i)),
+                    player,
+                );
+            }
+        }
+    }
+
+    assert!(draw_board.winner().is_none());
+    assert!(draw_board.is_draw());
+    assert_eq!(most_boards_board.winner(), Some(XOPlayer::X));
+    assert!(!most_boards_board.is_draw());
+}
+
+#[test]
+fn test_early_draw_when_no_line_reachable() {
+    let mut board = MainBoard::default();
+    // X takes the corners, O takes the edge centres
+    for (board_idx, player) in [
+        (0, XOPlayer::X),
+        (2, XOPlayer::X),
+        (6, XOPlayer::X),
+        (8, XOPlayer::X),
+        (1, XOPlayer::O),
+        (3, XOPlayer::O),
+        (5, XOPlayer::O),
+        (7, XOPlayer::O),
+    ] {
+        let large_pos = Position3::from_flat(board_idx);
+        for i in 0..3 {
+            board.set_cell(
+                &XOPosition::from_subpos(large_pos, Position3::new(i, 0)),
+                player,
+            );
+        }
+    }
+    // Every line still runs through the empty centre board
+    assert!(board.can_complete_line(XOPlayer::X));
+    assert!(board.can_complete_line(XOPlayer::O));
+    assert!(!board.is_draw());
+
+    // Block the centre board for X, which O can still win
+    let centre = Position3::from_flat(4);
+    for (x, y, player) in [
+        (1, 1, XOPlayer::O),
+        (0, 0, XOPlayer::O),
+        (0, 2, XOPlayer::X),
+        (1, 0, XOPlayer::X),
+        (1, 2, XOPlayer::O),
+        (2, 1, XOPlayer::O),
+    ] {
+        board.set_cell(
+            &XOPosition::from_subpos(centre, Position3::new(x, y)),
+            player,
+        );
+    }
+    assert!(!board.can_complete_line(XOPlayer::X));
+    assert!(board.can_complete_line(XOPlayer::O));
+    assert!(!board.is_draw());
+
+    // Block it for O too: a cell remains but the game is dead
+    for (x, y) in [(0, 1), (2, 2)] {
+        board.set_cell(
+            &XOPosition::from_subpos(centre, Position3::new(x, y)),
+            XOPlayer::X,
+        );
+    }
+    assert!(board.winner().is_none());
+    assert_eq!(board.valid_moves().len(), 1);
+    assert!(board.is_draw());
+}
+
+#[test]
+fn test_transformed_follows_permutation_tables() {
+    let mut board = MainBoard::default();
+    // X wins the top left board, then O plays in the centre board
+    for x in 0..3 {
+        board.set_cell(&XOPosition::new(x, 0), XOPlayer::X);
+    }
+    board.set_cell(&XOPosition::new(4, 5), XOPlayer::O);
+    for symmetry in XOSymmetry::ALL {
+        let transformed = board.transformed(symmetry);
+        for i in 0..81 {
+            let position = XOPosition::from(i);
+            let image = XOPosition::from(symmetry.permute_cell(i));
+            assert_eq!(transformed.get_cell(&image), board.get_cell(&position));
+        }
+        for i in 0..9 {
+            let image = Position3::from_flat(symmetry.permute_small_cell(i) as u8);
+            assert_eq!(transformed.board_winner(&image), board.board_winner(&Position3::from_flat(i as u8)));
+        }
+        assert_eq!(transformed.last_move(), Some(XOPosition::from(symmetry.permute_cell(4 + 9 * 5))));
+        assert!(transformed.canonical().0 == board.canonical().0);
+    }
+}
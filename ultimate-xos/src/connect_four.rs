@@ -0,0 +1,447 @@
+use colored::Colorize;
+use std::fmt;
+
+use crate::small_board::XOPlayer;
+use sigmazero::{
+    game::{Game, GameError, GameStatus, Position, PositionList, Symmetry},
+    policy::RawPolicy,
+};
+
+const COLUMNS: usize = 7;
+const ROWS: usize = 6;
+/// Bits per column in the bitboards: one per row and an empty one on top, so that
+/// lines can't wrap from one column into the next
+const COLUMN_BITS: usize = ROWS + 1;
+
+/// An action: the column to drop a stone into
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct ConnectFourPosition {
+    column: u8,
+}
+
+impl Position for ConnectFourPosition {
+    /// Only the column counts, the stone falls to the lowest empty row
+    fn new(x: u8, _y: u8) -> Self {
+        Self { column: x }
+    }
+
+    fn is_valid(&self) -> bool {
+        (self.column as usize) < COLUMNS
+    }
+}
+
+impl ConnectFourPosition {
+    fn mirrored(&self) -> Self {
+        Self {
+            column: (COLUMNS - 1) as u8 - self.column,
+        }
+    }
+}
+
+impl From<usize> for ConnectFourPosition {
+    fn from(index: usize) -> Self {
+        Self { column: index as u8 }
+    }
+}
+
+impl From<ConnectFourPosition> for usize {
+    fn from(pos: ConnectFourPosition) -> Self {
+        pos.column as usize
+    }
+}
+
+impl fmt::Display for ConnectFourPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}]", self.column)?;
+        Ok(())
+    }
+}
+
+/// The left-right mirror, the only symmetry of the board
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct ConnectFourSymmetry {
+    mirrored: bool,
+}
+
+impl ConnectFourSymmetry {
+    pub const ALL: [Self; 2] = [Self { mirrored: false }, Self { mirrored: true }];
+}
+
+impl Symmetry for ConnectFourSymmetry {
+    const IDENTITY: Self = Self { mirrored: false };
+
+    fn inverse(&self) -> Self {
+        *self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ConnectFourBoard {
+    /// Bit `column * COLUMN_BITS + row` is set for each stone, row 0 at the bottom
+    bitboards: [u64; 2],
+    heights: [u8; COLUMNS],
+}
+
+impl ConnectFourBoard {
+    fn bit(column: usize, row: usize) -> u64 {
+        1 << (column * COLUMN_BITS + row)
+    }
+
+    pub fn get_cell(&self, column: usize, row: usize) -> Option<XOPlayer> {
+        let mask = Self::bit(column, row);
+        if self.bitboards[XOPlayer::X as usize] & mask != 0 {
+            Some(XOPlayer::X)
+        } else if self.bitboards[XOPlayer::O as usize] & mask != 0 {
+            Some(XOPlayer::O)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_valid_move(&self, position: &ConnectFourPosition) -> bool {
+        position.is_valid() && (self.heights[position.column as usize] as usize) < ROWS
+    }
+
+    pub fn drop_stone(&mut self, position: &ConnectFourPosition, player: XOPlayer) {
+        let column = position.column as usize;
+        self.bitboards[player as usize] |= Self::bit(column, self.heights[column] as usize);
+        self.heights[column] += 1;
+    }
+
+    pub fn winner(&self) -> Option<XOPlayer> {
+        XOPlayer::PLAYERS
+            .into_iter()
+            .find(|&player| Self::has_four(self.bitboards[player as usize]))
+    }
+
+    fn has_four(bits: u64) -> bool {
+        // Vertical, horizontal and both diagonals
+        [1, COLUMN_BITS, COLUMN_BITS - 1, COLUMN_BITS + 1]
+            .iter()
+            .any(|&shift| {
+                let pairs = bits & (bits >> shift);
+                pairs & (pairs >> (2 * shift)) != 0
+            })
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.heights.iter().all(|&h| h as usize == ROWS)
+    }
+
+    pub fn valid_moves(&self) -> PositionList<ConnectFourPosition> {
+        PositionList::new(
+            (0..COLUMNS)
+                .map(ConnectFourPosition::from)
+                .filter(|p| self.is_valid_move(p))
+                .collect(),
+        )
+    }
+
+    pub fn features_for_player(&self, player: XOPlayer) -> [[[f32; COLUMNS]; ROWS]; 3] {
+        // [current player, other player, where the next stone in each column lands],
+        // with the top row first
+        let mut arr = [[[0.0; COLUMNS]; ROWS]; 3];
+        for (column, &height) in self.heights.iter().enumerate() {
+            for row in 0..ROWS {
+                match self.get_cell(column, row) {
+                    Some(p) if p == player => arr[0][ROWS - 1 - row][column] = 1.0,
+                    Some(_) => arr[1][ROWS - 1 - row][column] = 1.0,
+                    None => (),
+                }
+            }
+            let height = height as usize;
+            if height < ROWS {
+                arr[2][ROWS - 1 - height][column] = 1.0;
+            }
+        }
+        arr
+    }
+
+    pub fn transformed(&self, symmetry: ConnectFourSymmetry) -> Self {
+        if !symmetry.mirrored {
+            return *self;
+        }
+        let mut mirrored = Self::default();
+        let column_mask = (1 << COLUMN_BITS) - 1;
+        for column in 0..COLUMNS {
+            let from = column * COLUMN_BITS;
+            let to = (COLUMNS - 1 - column) * COLUMN_BITS;
+            for player in XOPlayer::PLAYERS {
+                mirrored.bitboards[player as usize] |=
+                    ((self.bitboards[player as usize] >> from) & column_mask) << to;
+            }
+            mirrored.heights[COLUMNS - 1 - column] = self.heights[column];
+        }
+        mirrored
+    }
+}
+
+impl fmt::Display for ConnectFourBoard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in (0..ROWS).rev() {
+            for column in 0..COLUMNS {
+                let p = match self.get_cell(column, row) {
+                    Some(XOPlayer::X) => "X".red().to_string(),
+                    Some(XOPlayer::O) => "O".green().to_string(),
+                    None => " ".to_string(),
+                };
+                write!(f, "|{p}")?;
+            }
+            writeln!(f, "|")?;
+        }
+        write!(f, "{}", "=".repeat(2 * COLUMNS + 1))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ConnectFour {
+    board: ConnectFourBoard,
+    status: GameStatus<XOPlayer>,
+}
+
+impl Game<7> for ConnectFour {
+    const FEATURES_SHAPE: &'static [i64] = &[3, ROWS as i64, COLUMNS as i64];
+    const FEATURES_SIZE: i64 = 3 * (ROWS * COLUMNS) as i64;
+
+    type Player = XOPlayer;
+    type Position = ConnectFourPosition;
+    type Symmetry = ConnectFourSymmetry;
+
+    fn take_turn(
+        &mut self,
+        position: &ConnectFourPosition,
+    ) -> Result<GameStatus<XOPlayer>, GameError<ConnectFourPosition>> {
+        let current_player = match self.status {
+            GameStatus::InProgress { player } => player,
+            _ => return Err(GameError::GameOver),
+        };
+
+        if !self.board.is_valid_move(position) {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.board.drop_stone(position, current_player);
+
+        self.status = if let Some(winner) = self.board.winner() {
+            GameStatus::Won { player: winner }
+        } else if self.board.is_full() {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress {
+                player: current_player.other_player(),
+            }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> PositionList<ConnectFourPosition> {
+        self.board.valid_moves()
+    }
+
+    fn status(&self) -> &GameStatus<XOPlayer> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        ColumnDisplayer::new(items)
+    }
+
+    fn write_features_for(&self, player: XOPlayer, out: &mut [f32]) {
+        out.copy_from_slice(self.board.features_for_player(player).as_flattened().as_flattened());
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<7>) -> (Vec<Self>, Vec<RawPolicy<7>>) {
+        ConnectFourSymmetry::ALL
+            .iter()
+            .map(|&symmetry| {
+                (
+                    Self {
+                        board: self.board.transformed(symmetry),
+                        status: self.status,
+                    },
+                    Self::transform_raw_policy(raw_policy, symmetry),
+                )
+            })
+            .unzip()
+    }
+
+    fn canonical(&self) -> (Self, ConnectFourSymmetry) {
+        ConnectFourSymmetry::ALL
+            .iter()
+            .map(|&symmetry| {
+                (
+                    Self {
+                        board: self.board.transformed(symmetry),
+                        status: self.status,
+                    },
+                    symmetry,
+                )
+            })
+            .min_by_key(|(game, _)| game.board.bitboards)
+            .unwrap()
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<7>, symmetry: ConnectFourSymmetry) -> RawPolicy<7> {
+        let mut transformed = [0.0f32; 7];
+        for (i, p) in raw_policy.iter().enumerate() {
+            let position = ConnectFourPosition::from(i);
+            let position = if symmetry.mirrored { position.mirrored() } else { position };
+            transformed[usize::from(position)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
+}
+
+impl fmt::Display for ConnectFour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.board)
+    }
+}
+
+/// Shows one item above each column
+pub struct ColumnDisplayer {
+    items: Vec<String>,
+}
+
+impl ColumnDisplayer {
+    pub fn new(items: Vec<String>) -> Self {
+        if items.len() != COLUMNS {
+            panic!("Displayer expects {} items, got {}", COLUMNS, items.len())
+        }
+        Self { items }
+    }
+}
+
+impl fmt::Display for ColumnDisplayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for item in &self.items {
+            write!(f, "|{item}")?;
+        }
+        write!(f, "|")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tch")]
+    use crate::policies::{ConnectFourNNAgent, RandomAgent};
+    #[cfg(feature = "tch")]
+    use crate::tictactoe::TicTacToe;
+    #[cfg(feature = "tch")]
+    use rand::{rngs::SmallRng, SeedableRng};
+    #[cfg(feature = "tch")]
+    use sigmazero::{
+        data::ReplayBufferTensorData, evaluate::evaluate_agents, game::DefaultFeatures,
+        learning::train_on_replay, mcts::self_play, policy::NNAgent,
+    };
+    #[cfg(feature = "tch")]
+    use tch::nn;
+
+    fn play(columns: &[usize]) -> (ConnectFour, GameStatus<XOPlayer>) {
+        let mut game = ConnectFour::default();
+        let mut status = *game.status();
+        for &column in columns {
+            status = game.take_turn(&ConnectFourPosition::from(column)).unwrap();
+        }
+        (game, status)
+    }
+
+    #[test]
+    fn test_vertical_win() {
+        let (_, status) = play(&[3, 4, 3, 4, 3, 4, 3]);
+        assert!(matches!(status, GameStatus::Won { player: XOPlayer::X }));
+    }
+
+    #[test]
+    fn test_horizontal_win() {
+        let (_, status) = play(&[0, 0, 1, 1, 2, 2, 6, 3, 5, 3]);
+        assert!(matches!(status, GameStatus::Won { player: XOPlayer::O }));
+        // Four across the top of one column and the bottom of the next don't count
+        let (_, status) = play(&[0, 1, 0, 1, 0, 1, 2, 0, 6, 0, 6, 0, 6]);
+        assert!(matches!(status, GameStatus::InProgress { .. }));
+    }
+
+    #[test]
+    fn test_diagonal_win() {
+        let (_, status) = play(&[0, 1, 1, 2, 2, 3, 2, 3, 3, 6, 3]);
+        assert!(matches!(status, GameStatus::Won { player: XOPlayer::X }));
+    }
+
+    #[test]
+    fn test_full_column() {
+        let (mut game, _) = play(&[2, 2, 2, 2, 2, 2]);
+        assert_eq!(game.valid_moves().len(), 6);
+        assert!(matches!(
+            game.take_turn(&ConnectFourPosition::from(2)),
+            Err(GameError::InvalidMove { .. })
+        ));
+    }
+
+    #[test]
+    fn test_draw() {
+        // Fill neighbouring columns in pairs with three of a colour each, then the last one
+        let mut columns = Vec::new();
+        for (a, b) in [(0, 1), (2, 3), (4, 5)] {
+            columns.extend_from_slice(&[a, b, a, b, a, b, b, a, b, a, b, a]);
+        }
+        columns.extend_from_slice(&[6; 6]);
+        let (game, status) = play(&columns);
+        assert!(game.board.is_full());
+        assert!(matches!(status, GameStatus::Draw));
+    }
+
+    #[test]
+    fn test_mirror_consistent() {
+        let (game, _) = play(&[0, 1, 1, 5, 6, 6]);
+        let policy = RawPolicy::new([0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let (aug_games, aug_policies) = game.augmented_with_raw_policy(&policy);
+        assert_eq!(aug_games.len(), 2);
+        let (mirrored, mirrored_policy) = (aug_games[1], &aug_policies[1]);
+        for column in 0..COLUMNS {
+            for row in 0..ROWS {
+                assert_eq!(
+                    mirrored.board.get_cell(COLUMNS - 1 - column, row),
+                    game.board.get_cell(column, row)
+                );
+            }
+            assert_eq!(mirrored_policy[COLUMNS - 1 - column], policy[column]);
+        }
+        assert!(mirrored.canonical().0 == game.canonical().0);
+        assert_eq!(mirrored.board.transformed(ConnectFourSymmetry::ALL[1]), game.board);
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_load_checks_game() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<ConnectFour, _, 7>(&mut random_agent, 1, 20, 0.0, false);
+        let replay_data: ReplayBufferTensorData = replay.into();
+        let path = std::env::temp_dir().join("connect_four_replay.ot");
+        replay_data.save_to_file(&path).unwrap();
+
+        let device = tch::Device::Cpu;
+        assert!(ReplayBufferTensorData::load_from_file::<DefaultFeatures, ConnectFour, 7>(&path, device).is_ok());
+        assert!(ReplayBufferTensorData::load_from_file::<DefaultFeatures, TicTacToe, 9>(&path, device).is_err());
+    }
+
+    /// Runs Connect Four through self-play, training and evaluation
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_pipeline() {
+        let device = tch::Device::Cpu;
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<ConnectFour, _, 7>(&mut random_agent, 4, 20, 0.0, false);
+        let replay_data: ReplayBufferTensorData = replay.augmented().into();
+
+        let vs = nn::VarStore::new(device);
+        train_on_replay::<ConnectFourNNAgent, ConnectFour, 7>(&vs, &replay_data, 16, 1, 0.8);
+
+        let mut agent = ConnectFourNNAgent::new(&vs);
+        let results = evaluate_agents(&mut agent, &mut random_agent, 2, 20, 0.0, false);
+        assert_eq!(results.agent1_wins + results.agent2_wins + results.draws, 2);
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::board::{XOPlayer, XOPosition};
+use crate::game::XOGame;
+use crate::rules::{FullBoardRule, WonBoardRule};
+use crate::small_board::Position3;
+use sigmazero::game::{DefaultFeatures, FeatureEncoder, Game};
+
+/// Spells out what the default three planes leave the network to work out:
+/// [current player, other player, last move, legal moves, boards won by the current
+/// player, boards won by the other player, drawn boards, X to move, won boards are
+/// played on, full boards go to whoever won most boards]
+pub struct XORichFeatures;
+
+impl XORichFeatures {
+    pub fn planes(game: &XOGame) -> [[[f32; 9]; 9]; 10] {
+        let current_player = game.perspective();
+        let board = game.board();
+        let mut planes = [[[0.0f32; 9]; 9]; 10];
+        let basic = board.features_for_player(current_player);
+        for (plane, basic_plane) in planes.iter_mut().zip(basic.iter()) {
+            for (row, basic_row) in plane.iter_mut().zip(basic_plane.iter()) {
+                for (cell, basic_cell) in row.iter_mut().zip(basic_row.iter()) {
+                    *cell = *basic_cell as f32;
+                }
+            }
+        }
+        // A finished game has no legal moves, whatever the board still has open
+        let legal_moves = if game.to_move().is_some() { game.valid_moves().to_vec() } else { Vec::new() };
+        for position in legal_moves.iter() {
+            let index = usize::from(*position);
+            planes[3][index / 9][index % 9] = 1.0;
+        }
+        for large in 0..9 {
+            let large_pos = Position3::from(large);
+            let plane = match board.board_winner(&large_pos) {
+                Some(winner) if winner == current_player => 4,
+                Some(_) => 5,
+                None if board.is_board_drawn(&large_pos) => 6,
+                None => continue,
+            };
+            for small in 0..9 {
+                let position = XOPosition::from_subpos(large_pos, Position3::from(small));
+                let index = usize::from(position);
+                planes[plane][index / 9][index % 9] = 1.0;
+            }
+        }
+        if current_player == XOPlayer::X {
+            planes[7] = [[1.0; 9]; 9];
+        }
+        // The same position plays out differently under other rules
+        let ruleset = game.ruleset();
+        if ruleset.won_board == WonBoardRule::PlayOn {
+            planes[8] = [[1.0; 9]; 9];
+        }
+        if ruleset.full_board == FullBoardRule::MostBoards {
+            planes[9] = [[1.0; 9]; 9];
+        }
+        planes
+    }
+}
+
+impl FeatureEncoder<XOGame, 81> for XORichFeatures {
+    const NAME: &'static str = "xo-rich-v2";
+    const SHAPE: &'static [i64] = &[10, 9, 9];
+
+    fn encode_into(game: &XOGame, out: &mut [f32]) {
+        out.copy_from_slice(Self::planes(game).as_flattened().as_flattened());
+    }
+}
+
+/// Which of the XO encoders a network reads, picked at runtime from the name its
+/// manifest or dataset records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XOEncoding {
+    #[default]
+    Default,
+    Rich,
+}
+
+impl XOEncoding {
+    pub const ALL: [XOEncoding; 2] = [Self::Default, Self::Rich];
+
+    /// `FeatureEncoder::NAME` of the encoder
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Default => <DefaultFeatures as FeatureEncoder<XOGame, 81>>::NAME,
+            Self::Rich => XORichFeatures::NAME,
+        }
+    }
+
+    pub const fn shape(&self) -> &'static [i64] {
+        match self {
+            Self::Default => <DefaultFeatures as FeatureEncoder<XOGame, 81>>::SHAPE,
+            Self::Rich => XORichFeatures::SHAPE,
+        }
+    }
+
+    /// Floats the encoder writes per position
+    pub fn size(&self) -> usize {
+        self.shape().iter().product::<i64>() as usize
+    }
+
+    pub fn encode_into(&self, game: &XOGame, out: &mut [f32]) {
+        match self {
+            Self::Default => DefaultFeatures::encode_into(game, out),
+            Self::Rich => XORichFeatures::encode_into(game, out),
+        }
+    }
+}
+
+impl fmt::Display for XOEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for XOEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|encoding| encoding.name() == s)
+            .ok_or_else(|| format!("Unknown XO encoding {:?}, expected one of {:?}", s, Self::ALL.map(|e| e.name())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tch")]
+    use crate::policies::RandomAgent;
+    use crate::rules::Ruleset;
+    use itertools::Itertools;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    #[cfg(feature = "tch")]
+    use sigmazero::data::ReplayBufferTensorData;
+    use sigmazero::game::Position;
+    #[cfg(feature = "tch")]
+    use sigmazero::mcts::self_play;
+
+    #[test]
+    fn test_encodings_by_name() {
+        let mut game = XOGame::default();
+        game.take_turn(&XOPosition::new(4, 4)).unwrap();
+        for encoding in XOEncoding::ALL {
+            assert_eq!(encoding.name().parse(), Ok(encoding));
+            let mut features = vec![0.0; encoding.size()];
+            encoding.encode_into(&game, &mut features);
+            let expected = match encoding {
+                XOEncoding::Default => DefaultFeatures::encode_batch_into(&[game]),
+                XOEncoding::Rich => XORichFeatures::encode_batch_into(&[game]),
+            };
+            assert_eq!(features, expected);
+        }
+        assert!("xo-rich-v1".parse::<XOEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_rich_planes() {
+        let mut game = XOGame::default();
+        // X takes the top left board with its top row
+        for (x, y) in [(1, 0), (3, 0), (0, 0), (0, 1), (2, 3), (6, 0), (2, 0), (7, 0)] {
+            game.take_turn(&XOPosition::new(x, y)).unwrap();
+        }
+        // X to move, sent to the top middle board
+        let planes = XORichFeatures::planes(&game);
+        let count = |plane: usize| planes[plane].iter().flatten().filter(|&&v| v == 1.0).count();
+        assert_eq!(count(0), 4);
+        assert_eq!(count(1), 4);
+        assert_eq!(planes[2][0][7], 1.0);
+        assert_eq!(count(3), 8);
+        assert!(game.valid_moves().iter().all(|p| p.large_pos() == Position3::new(1, 0)));
+        assert!(game.valid_moves().iter().all(|&p| {
+            let index = usize::from(p);
+            planes[3][index / 9][index % 9] == 1.0
+        }));
+        assert_eq!(count(4), 9);
+        assert!((0..3).all(|y| (0..3).all(|x| planes[4][y][x] == 1.0)));
+        assert_eq!(count(5), 0);
+        assert_eq!(count(6), 0);
+        assert_eq!(count(7), 81);
+        assert_eq!(count(8), 0);
+        assert_eq!(count(9), 0);
+
+        // From O's side the won board is the opponent's and the side plane is empty
+        game.take_turn(&XOPosition::new(5, 2)).unwrap();
+        let planes = XORichFeatures::planes(&game);
+        assert_eq!(planes[5][1][1], 1.0);
+        assert!(planes[7].iter().flatten().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn test_rulesets_encode_differently() {
+        let rulesets = [
+            Ruleset::default(),
+            Ruleset::new(WonBoardRule::PlayOn, FullBoardRule::Draw),
+            Ruleset::new(WonBoardRule::FreeMove, FullBoardRule::MostBoards),
+            Ruleset::new(WonBoardRule::PlayOn, FullBoardRule::MostBoards),
+        ];
+        let encodings: Vec<_> = rulesets
+            .iter()
+            .map(|&ruleset| {
+                let mut game = XOGame::with_ruleset(ruleset);
+                game.take_turn(&XOPosition::new(4, 4)).unwrap();
+                XORichFeatures::planes(&game)
+            })
+            .collect();
+        // Only the ruleset planes tell the positions apart
+        for (i, a) in encodings.iter().enumerate() {
+            for b in &encodings[i + 1..] {
+                assert_ne!(a, b);
+                assert_eq!(a[..8], b[..8]);
+            }
+        }
+        assert!(encodings[3][8].iter().flatten().all(|&v| v == 1.0));
+        assert!(encodings[3][9].iter().flatten().all(|&v| v == 1.0));
+    }
+
+    #[test]
+    fn test_planes_for_whole_record() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut record = vec![XOGame::default()];
+        while record.last().unwrap().to_move().is_some() {
+            let mut game = *record.last().unwrap();
+            let moves = game.valid_moves();
+            game.take_turn(&moves[rng.gen_range(0..moves.len())]).unwrap();
+            record.push(game);
+        }
+        for (game, next) in record.iter().tuple_windows() {
+            let planes = XORichFeatures::planes(game);
+            assert_eq!(planes[7][0][0] == 1.0, game.perspective() == XOPlayer::X);
+            assert_ne!(game.perspective(), next.perspective());
+        }
+        // The final position is seen from the side of whoever would move next
+        let last = record.last().unwrap();
+        let planes = XORichFeatures::planes(last);
+        assert_ne!(Some(last.perspective()), last.board().last_move().and_then(|m| last.board().get_cell(&m)));
+        assert!(planes[3].iter().flatten().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_dataset_records_encoding() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<XOGame, _, 81>(&mut random_agent, 1, 10, 0.0, false);
+        let replay_data = ReplayBufferTensorData::encode::<XORichFeatures, _, 81>(replay);
+        assert_eq!(replay_data.encoding(), "xo-rich-v2");
+        assert_eq!(replay_data.features.size()[1..], [10, 9, 9]);
+
+        let path = std::env::temp_dir().join("xo_rich_replay.ot");
+        replay_data.save_to_file(&path).unwrap();
+        let device = tch::Device::Cpu;
+        assert!(ReplayBufferTensorData::load_from_file::<XORichFeatures, XOGame, 81>(&path, device).is_ok());
+        assert!(ReplayBufferTensorData::load_from_file::<DefaultFeatures, XOGame, 81>(&path, device).is_err());
+    }
+}
@@ -1,33 +1,25 @@
-use core::panic;
 use std::fmt;
 
 pub use crate::board::XOPlayer;
-use crate::board::{BoardDisplayer, MainBoard, XOPosition, XOPositionList};
+use crate::board::{BoardDisplayer, MainBoard, XOPosition, XOPositionList, XOSymmetry};
+use crate::rules::Ruleset;
 use sigmazero::{game::{Game, GameError, GameStatus}, policy::RawPolicy};
 
 pub type XOGameStatus = GameStatus<XOPlayer>;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct XOGame {
     board: MainBoard,
     status: GameStatus<XOPlayer>,
 }
 
-impl Default for XOGame {
-    fn default() -> Self {
-        Self {
-            board: MainBoard::default(),
-            status: GameStatus::default(),
-        }
-    }
-}
-
 impl Game<81> for XOGame {
     const FEATURES_SHAPE: &'static [i64] = &[3, 9, 9];
     const FEATURES_SIZE: i64 = 3 * 9 * 9;
 
     type Player = XOPlayer;
     type Position = XOPosition;
+    type Symmetry = XOSymmetry;
 
     fn take_turn(
         &mut self,
@@ -71,23 +63,16 @@ impl Game<81> for XOGame {
         BoardDisplayer::new(items)
     }
 
-    fn features(&self) -> tch::Tensor {
-        let current_player = match self.status {
-            GameStatus::InProgress { player } => player,
-            _ => panic!("Features for completed game? In esta economia?"),
-        };
-        tch::Tensor::from_slice(
-            self.board
-                .features_for_player(current_player)
-                .iter()
-                .flatten()
-                .flatten()
-                .copied()
-                .collect::<Vec<_>>()
-                .as_slice(),
-        )
-        .reshape([3, 9, 9])
-        .to_dtype(tch::Kind::Float, false, false)
+    /// Won boards can decide the game after the other player's move, so ask the board
+    fn perspective(&self) -> XOPlayer {
+        self.to_move().unwrap_or_else(|| self.board.next_player())
+    }
+
+    fn write_features_for(&self, player: XOPlayer, out: &mut [f32]) {
+        let features = self.board.features_for_player(player);
+        for (out, feature) in out.iter_mut().zip(features.as_flattened().as_flattened()) {
+            *out = *feature as f32;
+        }
     }
 
     fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<81>) -> (Vec<Self>, Vec<RawPolicy<81>>) {
@@ -95,44 +80,47 @@ impl Game<81> for XOGame {
         let mut aug_games = Vec::new();
         let aug_policies = Self::augment_raw_policy(raw_policy);
         for board in aug_boards {
-            aug_games.push(Self { board, status: self.status.clone() })
+            aug_games.push(Self { board, status: self.status })
         }
 
         (aug_games, aug_policies)
     }
+
+    fn canonical(&self) -> (Self, XOSymmetry) {
+        let (board, symmetry) = self.board.canonical();
+        (Self { board, status: self.status }, symmetry)
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<81>, symmetry: XOSymmetry) -> RawPolicy<81> {
+        let mut transformed = [0.0f32; 81];
+        for (i, p) in raw_policy.iter().enumerate() {
+            transformed[symmetry.permute_cell(i)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
 }
 
 impl XOGame {
-    fn augment_raw_policy(raw_policy: &RawPolicy<81>) -> Vec<RawPolicy<81>>{
-        let mut aug_policies = vec![raw_policy.clone()];
-        for r in 1..4 {
-            aug_policies.push(Self::rotate_raw_policy_90(&aug_policies[r-1]));
-        }
-        aug_policies.push(Self::reflect_vertical_raw_policy(&aug_policies[0]));
-        for r in 5..8 {
-            aug_policies.push(Self::rotate_raw_policy_90(&aug_policies[r-1]));
+    pub fn with_ruleset(ruleset: Ruleset) -> Self {
+        Self {
+            board: MainBoard::with_ruleset(ruleset),
+            status: GameStatus::default(),
         }
-        aug_policies
     }
 
-    fn rotate_raw_policy_90(raw_policy: &RawPolicy<81>) -> RawPolicy<81> {
-        let mut rot_policy = [0.0f32; 81];
-        for x in 0..9 {
-            for y in 0..9 {
-                rot_policy[x + 9 * y] = raw_policy[y + 9 * (8-x)]
-            }
-        }
-        RawPolicy::new(rot_policy)
+    pub fn ruleset(&self) -> Ruleset {
+        self.board.ruleset()
     }
 
-    fn reflect_vertical_raw_policy(raw_policy: &RawPolicy<81>) -> RawPolicy<81> {
-        let mut ref_policy = [0.0f32; 81];
-        for x in 0..9 {
-            for y in 0..9 {
-                ref_policy[x + 9 * y] = raw_policy[(8-x) + 9 * (y)]
-            }
-        }
-        RawPolicy::new(ref_policy)
+    pub fn board(&self) -> &MainBoard {
+        &self.board
+    }
+
+    fn augment_raw_policy(raw_policy: &RawPolicy<81>) -> Vec<RawPolicy<81>>{
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| Self::transform_raw_policy(raw_policy, symmetry))
+            .collect()
     }
 }
 
@@ -144,22 +132,138 @@ impl fmt::Display for XOGame {
 
 #[cfg(test)]
 mod tests {
-    use crate::policies;
+    use rand::seq::SliceRandom;
+    use sigmazero::data::ReplayBuffer;
+    use sigmazero::policy::Wdl;
+    #[cfg(feature = "tch")]
+    use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+    use sigmazero::game::{Position, Symmetry};
 
     use super::*;
 
+    fn asymmetric_game() -> XOGame {
+        let mut game = XOGame::default();
+        for (x, y) in [(0, 0), (1, 1), (4, 3), (5, 1)] {
+            game.take_turn(&XOPosition::new(x, y)).unwrap();
+        }
+        game
+    }
+
+    fn index_policy() -> RawPolicy<81> {
+        RawPolicy::new(std::array::from_fn(|i| i as f32))
+    }
+
+    #[test]
+    fn test_write_features_follows_board() {
+        let game = asymmetric_game();
+        let mut out = [0.0f32; 3 * 81];
+        game.write_features_for(XOPlayer::X, &mut out);
+        let expected = game.board.features_for_player(XOPlayer::X);
+        assert!(out
+            .iter()
+            .zip(expected.as_flattened().as_flattened())
+            .all(|(&written, &feature)| written == feature as f32));
+        assert_eq!(out.iter().sum::<f32>(), 5.0);
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_encode_batch_matches_single_games() {
+        let games = [XOGame::default(), asymmetric_game()];
+        let batch = <DefaultFeatures as FeatureEncoder<XOGame, 81>>::encode_batch(&games);
+        assert_eq!(batch.size(), [2, 3, 9, 9]);
+        for (i, game) in games.iter().enumerate() {
+            assert!(batch.get(i as i64).allclose(&game.features(), 0.0, 0.0, false));
+        }
+    }
+
+    #[test]
+    fn test_legal_mask_matches_valid_moves() {
+        let game = asymmetric_game();
+        let mask = game.legal_mask();
+        assert_eq!(mask.iter().filter(|&&legal| legal).count(), game.valid_moves().len());
+        assert!(game.valid_moves().iter().all(|&position| mask[usize::from(position)]));
+
+        let mut rng = rand::thread_rng();
+        let mut finished = XOGame::default();
+        while finished.to_move().is_some() {
+            let position = *finished.valid_moves().choose(&mut rng).unwrap();
+            finished.take_turn(&position).unwrap();
+        }
+        assert!(finished.legal_mask().iter().all(|&legal| !legal));
+    }
+
+    #[test]
+    fn test_canonical_shared_by_symmetric_images() {
+        let game = asymmetric_game();
+        let (canonical, symmetry) = game.canonical();
+        assert!(canonical.board == game.board.transformed(symmetry));
+        let (aug_games, _) = game.augmented_with_raw_policy(&index_policy());
+        for aug_game in aug_games {
+            assert!(aug_game.canonical().0 == canonical);
+        }
+    }
+
+    #[test]
+    fn test_transform_raw_policy_inverse() {
+        let policy = index_policy();
+        for symmetry in XOSymmetry::ALL {
+            let transformed = XOGame::transform_raw_policy(&policy, symmetry);
+            assert_eq!(XOGame::transform_raw_policy(&transformed, symmetry.inverse()), policy);
+        }
+    }
+
+    #[test]
+    fn test_board_and_policy_transforms_consistent() {
+        let game = asymmetric_game();
+        let policy = index_policy();
+        let valid_moves = game.valid_moves();
+        let (aug_games, aug_policies) = game.augmented_with_raw_policy(&policy);
+        assert_eq!(aug_games.len(), 8);
+        for ((symmetry, aug_game), aug_policy) in XOSymmetry::ALL.into_iter().zip(aug_games).zip(aug_policies) {
+            assert!(aug_game.board == game.board.transformed(symmetry));
+            for i in 0..81 {
+                let position = XOPosition::from(i);
+                let transformed = position.transformed(symmetry);
+                assert_eq!(aug_game.board.get_cell(&transformed), game.board.get_cell(&position));
+                assert_eq!(aug_policy[usize::from(transformed)], policy[i]);
+            }
+            let aug_valid_moves = aug_game.valid_moves();
+            assert_eq!(aug_valid_moves.len(), valid_moves.len());
+            for valid_move in valid_moves.iter() {
+                assert!(aug_valid_moves.contains(&valid_move.transformed(symmetry)));
+            }
+            // Play the same move in both frames and stay in step
+            let mut next_game = game;
+            let mut next_aug_game = aug_game;
+            next_game.take_turn(&valid_moves[0]).unwrap();
+            next_aug_game.take_turn(&valid_moves[0].transformed(symmetry)).unwrap();
+            assert!(next_aug_game.board == next_game.board.transformed(symmetry));
+        }
+    }
+
+    #[test]
+    fn test_deduplicate_augmented_replay() {
+        let game = asymmetric_game();
+        let replay = ReplayBuffer::new(vec![game], vec![0.5], vec![Wdl::from_score(0.5)], vec![index_policy()]);
+        let deduplicated = replay.augmented().deduplicated();
+        assert_eq!(deduplicated.len(), 1);
+        assert_eq!(deduplicated.values[0], 0.5);
+        assert_eq!(deduplicated.outcomes[0], Wdl::from_score(0.5));
+        let (canonical, symmetry) = game.canonical();
+        assert!(deduplicated.games[0] == canonical);
+        assert_eq!(deduplicated.policies[0], XOGame::transform_raw_policy(&index_policy(), symmetry));
+    }
+
     #[test]
     fn test_rotation() {
         // Create a test grid where each cell contains its index (0 to 80)
-        let mut initial = [0.0f32; 81];
-        for i in 0..81 {
-            initial[i] = i as f32;
-        }
-        let policy = RawPolicy::new(initial);
+        let policy = index_policy();
 
         println!("Original grid:");
 
-        let rotated = XOGame::rotate_raw_policy_90(&policy);
+        let rotate_90 = XOSymmetry::ALL[1];
+        let rotated = XOGame::transform_raw_policy(&policy, rotate_90);
         println!("Rotated grid:");
 
         // Test specific positions
@@ -182,12 +286,15 @@ mod tests {
         assert_eq!(rotated[63], 79.0);  // (7,8) -> (0,7)
         assert_eq!(rotated[10], 64.0);  // (1,7) -> (1,1)
 
-        assert_eq!(policy, XOGame::rotate_raw_policy_90(
-            &XOGame::rotate_raw_policy_90(
-                &XOGame::rotate_raw_policy_90(
-                    &XOGame::rotate_raw_policy_90(&policy)
-                )
-            )
+        assert_eq!(policy, XOGame::transform_raw_policy(
+            &XOGame::transform_raw_policy(
+                &XOGame::transform_raw_policy(
+                    &XOGame::transform_raw_policy(&policy, rotate_90),
+                    rotate_90,
+                ),
+                rotate_90,
+            ),
+            rotate_90,
         ));
     }
 }
\ No newline at end of file
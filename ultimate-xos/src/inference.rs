@@ -0,0 +1,936 @@
+use std::io;
+use std::path::Path;
+
+use crate::architecture::XOArchitecture;
+use crate::features::{XOEncoding, XORichFeatures};
+use crate::game::XOGame;
+use crate::policies::{value_score, wdl_from_value};
+use crate::resnet::ResNetConfig;
+use crate::transformer::TransformerConfig;
+use crate::two_level::TwoLevelConfig;
+use sigmazero::checkpoint::Manifest;
+use sigmazero::data::ReplayBuffer;
+use sigmazero::game::{DefaultFeatures, Game};
+use sigmazero::inference::{masked_softmax, softmax, BatchNorm, Conv2d, CpuTensor, LayerNorm, Linear, Weights};
+use sigmazero::policy::{Agent, NetworkConfig, RawPolicy, Wdl};
+use sigmazero::quantize::{CalibrationSet, InputRanges, Observer, QuantizationReport, Quantize};
+#[cfg(feature = "tch")]
+use tch::Tensor;
+
+fn invalid_data(message: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The config in the manifest, or the default for networks saved before they had one
+fn config<C: NetworkConfig + Default>(weights: &Weights) -> C {
+    match weights.manifest.config.is_empty() {
+        true => C::default(),
+        false => C::from_slice(&weights.manifest.config),
+    }
+}
+
+/// `[rows, a]` and `[rows, b]` side by side, `[rows, a + b]`
+fn concat_rows(left: &CpuTensor, right: &CpuTensor) -> CpuTensor {
+    let (rows, a, b) = (left.shape[0], left.shape[1], right.shape[1]);
+    let data = left
+        .data
+        .chunks_exact(a)
+        .zip(right.data.chunks_exact(b))
+        .flat_map(|(l, r)| l.iter().chain(r).copied())
+        .collect();
+    CpuTensor::new(vec![rows, a + b], data)
+}
+
+/// The CPU version of `resnet::SqueezeExcitation`
+#[derive(Clone)]
+struct SqueezeExcitation {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl SqueezeExcitation {
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        vec![&mut self.fc1, &mut self.fc2]
+    }
+
+    fn forward(&self, mut xs: CpuTensor, observer: &mut impl Observer) -> CpuTensor {
+        let channels = xs.shape[0];
+        let plane = xs.data.len() / channels;
+        let means = xs.data.chunks_exact(plane).map(|c| c.iter().sum::<f32>() / plane as f32).collect();
+        let weights = self
+            .fc2
+            .forward_observed(&self.fc1.forward_observed(&CpuTensor::new(vec![channels], means), observer).relu(), observer)
+            .sigmoid();
+        for (channel, weight) in xs.data.chunks_exact_mut(plane).zip(&weights.data) {
+            channel.iter_mut().for_each(|x| *x *= weight);
+        }
+        xs
+    }
+}
+
+/// The CPU version of `resnet::ResidualBlock`
+#[derive(Clone)]
+struct ResidualBlock {
+    conv1: Conv2d,
+    bn1: BatchNorm,
+    conv2: Conv2d,
+    bn2: BatchNorm,
+    se: Option<SqueezeExcitation>,
+}
+
+impl ResidualBlock {
+    fn load(weights: &Weights, path: &str, config: &ResNetConfig) -> io::Result<Self> {
+        let se = match config.squeeze_excitation {
+            true => Some(SqueezeExcitation {
+                fc1: Linear::load(weights, &format!("{}.se.fc1", path))?,
+                fc2: Linear::load(weights, &format!("{}.se.fc2", path))?,
+            }),
+            false => None,
+        };
+        Ok(Self {
+            conv1: Conv2d::load(weights, &format!("{}.conv1", path), 1)?,
+            bn1: BatchNorm::load(weights, &format!("{}.bn1", path))?,
+            conv2: Conv2d::load(weights, &format!("{}.conv2", path), 1)?,
+            bn2: BatchNorm::load(weights, &format!("{}.bn2", path))?,
+            se,
+        })
+    }
+
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        let mut layers: Vec<&mut dyn Quantize> = vec![&mut self.conv1, &mut self.conv2];
+        if let Some(se) = &mut self.se {
+            layers.extend(se.layers());
+        }
+        layers
+    }
+
+    fn forward(&self, xs: &CpuTensor, observer: &mut impl Observer) -> CpuTensor {
+        let ys = self.bn1.forward(self.conv1.forward_observed(xs, observer)).relu();
+        let mut ys = self.bn2.forward(self.conv2.forward_observed(&ys, observer));
+        if let Some(se) = &self.se {
+            ys = se.forward(ys, observer);
+        }
+        ys += xs;
+        ys.relu()
+    }
+}
+
+/// The CPU version of `policies::XONNAgent`
+#[derive(Clone)]
+struct ResNet {
+    input_conv: Conv2d,
+    input_bn: BatchNorm,
+    blocks: Vec<ResidualBlock>,
+    policy_conv: Conv2d,
+    policy_bn: BatchNorm,
+    policy_fc: Linear,
+    value_conv: Conv2d,
+    value_bn: BatchNorm,
+    value_fc1: Linear,
+    value_fc2: Linear,
+}
+
+impl ResNet {
+    fn load(weights: &Weights) -> io::Result<Self> {
+        let config: ResNetConfig = config(weights);
+        Ok(Self {
+            input_conv: Conv2d::load(weights, "input_conv", 1)?,
+            input_bn: BatchNorm::load(weights, "input_bn")?,
+            blocks: (0..config.blocks)
+                .map(|i| ResidualBlock::load(weights, &format!("blocks.{}", i), &config))
+                .collect::<io::Result<_>>()?,
+            policy_conv: Conv2d::load(weights, "policy_conv", 0)?,
+            policy_bn: BatchNorm::load(weights, "policy_bn")?,
+            policy_fc: Linear::load(weights, "policy_fc")?,
+            value_conv: Conv2d::load(weights, "value_conv", 0)?,
+            value_bn: BatchNorm::load(weights, "value_bn")?,
+            value_fc1: Linear::load(weights, "value_fc1")?,
+            value_fc2: Linear::load(weights, "value_fc2")?,
+        })
+    }
+
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        let mut layers: Vec<&mut dyn Quantize> = vec![&mut self.input_conv];
+        layers.extend(self.blocks.iter_mut().flat_map(ResidualBlock::layers));
+        layers.extend([
+            &mut self.policy_conv as &mut dyn Quantize,
+            &mut self.policy_fc,
+            &mut self.value_conv,
+            &mut self.value_fc1,
+            &mut self.value_fc2,
+        ]);
+        layers
+    }
+
+    fn forward(&self, xs: &CpuTensor, observer: &mut impl Observer) -> (CpuTensor, CpuTensor) {
+        let mut xs = self.input_bn.forward(self.input_conv.forward_observed(xs, observer)).relu();
+        for block in &self.blocks {
+            xs = block.forward(&xs, observer);
+        }
+
+        let policy = self.policy_bn.forward(self.policy_conv.forward_observed(&xs, observer)).relu();
+        let policy_logits = self.policy_fc.forward_observed(&policy.reshape(vec![2 * 81]), observer);
+
+        let value = self.value_bn.forward(self.value_conv.forward_observed(&xs, observer)).relu();
+        let value = self.value_fc1.forward_observed(&value.reshape(vec![81]), observer).relu();
+        (policy_logits, self.value_fc2.forward_observed(&value, observer))
+    }
+}
+
+/// The CPU version of `two_level::XOTwoLevelAgent`
+#[derive(Clone)]
+struct TwoLevel {
+    board_fc1: Linear,
+    board_fc2: Linear,
+    meta_convs: Vec<(Conv2d, BatchNorm)>,
+    cell_fc: Linear,
+    value_fc1: Linear,
+    value_fc2: Linear,
+}
+
+impl TwoLevel {
+    fn load(weights: &Weights) -> io::Result<Self> {
+        let config: TwoLevelConfig = config(weights);
+        Ok(Self {
+            board_fc1: Linear::load(weights, "board_fc1")?,
+            board_fc2: Linear::load(weights, "board_fc2")?,
+            meta_convs: (0..config.meta_layers)
+                .map(|i| {
+                    Ok((
+                        Conv2d::load(weights, &format!("meta_conv.{}", i), 1)?,
+                        BatchNorm::load(weights, &format!("meta_bn.{}", i))?,
+                    ))
+                })
+                .collect::<io::Result<_>>()?,
+            cell_fc: Linear::load(weights, "cell_fc")?,
+            value_fc1: Linear::load(weights, "value_fc1")?,
+            value_fc2: Linear::load(weights, "value_fc2")?,
+        })
+    }
+
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        let mut layers: Vec<&mut dyn Quantize> = vec![&mut self.board_fc1, &mut self.board_fc2];
+        layers.extend(self.meta_convs.iter_mut().map(|(conv, _)| conv as &mut dyn Quantize));
+        layers.extend([&mut self.cell_fc as &mut dyn Quantize, &mut self.value_fc1, &mut self.value_fc2]);
+        layers
+    }
+
+    fn forward(&self, xs: &CpuTensor, observer: &mut impl Observer) -> (CpuTensor, CpuTensor) {
+        // [C, board row, cell row, board column, cell column] to one row per board
+        let channels = xs.shape[0];
+        let boards = xs
+            .clone()
+            .reshape(vec![channels, 3, 3, 3, 3])
+            .permute(&[1, 3, 0, 2, 4])
+            .reshape(vec![9, channels * 9]);
+        let local = self.board_fc2.forward_observed(&self.board_fc1.forward_observed(&boards, observer).relu(), observer).relu();
+
+        let embedding = local.shape[1];
+        let mut meta = local.clone().reshape(vec![3, 3, embedding]).permute(&[2, 0, 1]);
+        for (conv, bn) in &self.meta_convs {
+            meta = bn.forward(conv.forward_observed(&meta, observer)).relu();
+        }
+
+        let meta_filters = meta.shape[0];
+        let context = meta.permute(&[1, 2, 0]).reshape(vec![9, meta_filters]);
+        let cell_logits = self.cell_fc.forward_observed(&concat_rows(&local, &context), observer);
+        let policy_logits = cell_logits
+            .reshape(vec![3, 3, 3, 3])
+            .permute(&[0, 2, 1, 3])
+            .reshape(vec![81]);
+
+        let value = self.value_fc1.forward_observed(&meta.reshape(vec![9 * meta_filters]), observer).relu();
+        (policy_logits, self.value_fc2.forward_observed(&value, observer))
+    }
+}
+
+/// The CPU version of the transformer's `EncoderLayer`
+#[derive(Clone)]
+struct EncoderLayer {
+    attention_norm: LayerNorm,
+    qkv: Linear,
+    attention_out: Linear,
+    mlp_norm: LayerNorm,
+    mlp_fc1: Linear,
+    mlp_fc2: Linear,
+    heads: usize,
+}
+
+impl EncoderLayer {
+    fn load(weights: &Weights, path: &str, config: &TransformerConfig) -> io::Result<Self> {
+        let dim = config.dim as usize;
+        Ok(Self {
+            attention_norm: LayerNorm::load(weights, &format!("{}.attention_norm", path), dim)?,
+            qkv: Linear::load(weights, &format!("{}.qkv", path))?,
+            attention_out: Linear::load(weights, &format!("{}.attention_out", path))?,
+            mlp_norm: LayerNorm::load(weights, &format!("{}.mlp_norm", path), dim)?,
+            mlp_fc1: Linear::load(weights, &format!("{}.mlp_fc1", path))?,
+            mlp_fc2: Linear::load(weights, &format!("{}.mlp_fc2", path))?,
+            heads: config.heads as usize,
+        })
+    }
+
+    /// The attention itself stays in float
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        vec![&mut self.qkv, &mut self.attention_out, &mut self.mlp_fc1, &mut self.mlp_fc2]
+    }
+
+    /// [tokens, dim] to the same shape
+    fn forward(&self, mut xs: CpuTensor, observer: &mut impl Observer) -> CpuTensor {
+        let (tokens, dim) = (xs.shape[0], xs.shape[1]);
+        let head_dim = dim / self.heads;
+        // Each token's row is its queries, then keys, then values, each split by head
+        let qkv = self.qkv.forward_observed(&self.attention_norm.forward(xs.clone()), observer);
+        let at = |token: usize, part: usize, head: usize| {
+            let start = token * 3 * dim + part * dim + head * head_dim;
+            &qkv.data[start..start + head_dim]
+        };
+        let mut attended = CpuTensor::zeros(vec![tokens, dim]);
+        let mut weights = vec![0.0; tokens];
+        for head in 0..self.heads {
+            for token in 0..tokens {
+                let query = at(token, 0, head);
+                for (other, weight) in weights.iter_mut().enumerate() {
+                    let key = at(other, 1, head);
+                    *weight = query.iter().zip(key).map(|(q, k)| q * k).sum::<f32>() / (head_dim as f32).sqrt();
+                }
+                softmax(&mut weights);
+                let out = &mut attended.data[token * dim + head * head_dim..][..head_dim];
+                for (other, weight) in weights.iter().enumerate() {
+                    for (o, v) in out.iter_mut().zip(at(other, 2, head)) {
+                        *o += weight * v;
+                    }
+                }
+            }
+        }
+        xs += &self.attention_out.forward_observed(&attended, observer);
+
+        let ys = self
+            .mlp_fc2
+            .forward_observed(&self.mlp_fc1.forward_observed(&self.mlp_norm.forward(xs.clone()), observer).gelu(), observer);
+        xs += &ys;
+        xs
+    }
+}
+
+/// The CPU version of `transformer::XOTransformerAgent`
+#[derive(Clone)]
+struct Transformer {
+    input_fc: Linear,
+    /// [81, dim], see `XOTransformerAgent::positions`
+    positions: CpuTensor,
+    class_token: CpuTensor,
+    layers: Vec<EncoderLayer>,
+    final_norm: LayerNorm,
+    policy_fc: Linear,
+    value_fc1: Linear,
+    value_fc2: Linear,
+}
+
+impl Transformer {
+    fn load(weights: &Weights) -> io::Result<Self> {
+        let config: TransformerConfig = config(weights);
+        let dim = config.dim as usize;
+        let board_embedding = weights.get_shaped("board_embedding", &[3, 1, 3, 1, dim])?;
+        let cell_embedding = weights.get_shaped("cell_embedding", &[1, 3, 1, 3, dim])?;
+        let mut positions = CpuTensor::zeros(vec![81, dim]);
+        for (i, position) in positions.data.chunks_exact_mut(dim).enumerate() {
+            let (row, column) = (i / 9, i % 9);
+            let board = &board_embedding.data[(row / 3 * 3 + column / 3) * dim..][..dim];
+            let cell = &cell_embedding.data[(row % 3 * 3 + column % 3) * dim..][..dim];
+            for ((p, b), c) in position.iter_mut().zip(board).zip(cell) {
+                *p = b + c;
+            }
+        }
+        Ok(Self {
+            input_fc: Linear::load(weights, "input_fc")?,
+            positions,
+            class_token: weights.get_shaped("class_token", &[1, 1, dim])?.clone().reshape(vec![1, dim]),
+            layers: (0..config.layers)
+                .map(|i| EncoderLayer::load(weights, &format!("layers.{}", i), &config))
+                .collect::<io::Result<_>>()?,
+            final_norm: LayerNorm::load(weights, "final_norm", dim)?,
+            policy_fc: Linear::load(weights, "policy_fc")?,
+            value_fc1: Linear::load(weights, "value_fc1")?,
+            value_fc2: Linear::load(weights, "value_fc2")?,
+        })
+    }
+
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        let mut layers: Vec<&mut dyn Quantize> = vec![&mut self.input_fc];
+        layers.extend(self.layers.iter_mut().flat_map(EncoderLayer::layers));
+        layers.extend([&mut self.policy_fc as &mut dyn Quantize, &mut self.value_fc1, &mut self.value_fc2]);
+        layers
+    }
+
+    fn forward(&self, xs: &CpuTensor, observer: &mut impl Observer) -> (CpuTensor, CpuTensor) {
+        let channels = xs.shape[0];
+        let mut cells = self
+            .input_fc
+            .forward_observed(&xs.clone().reshape(vec![channels, 81]).permute(&[1, 0]), observer);
+        cells += &self.positions;
+        let mut tokens = self.class_token.data.clone();
+        tokens.extend_from_slice(&cells.data);
+        let mut tokens = CpuTensor::new(vec![82, cells.shape[1]], tokens);
+        for layer in &self.layers {
+            tokens = layer.forward(tokens, observer);
+        }
+        let tokens = self.final_norm.forward(tokens);
+
+        let policy_logits = self.policy_fc.forward_observed(&tokens.narrow(1, 81), observer).reshape(vec![81]);
+        let class_token = tokens.narrow(0, 1);
+        let value = self.value_fc1.forward_observed(&class_token, observer).relu();
+        (policy_logits, self.value_fc2.forward_observed(&value, observer).reshape(vec![self.value_fc2.out_features()]))
+    }
+}
+
+/// Any of the XO networks on the CPU, built for the architecture in the manifest
+#[derive(Clone)]
+enum Network {
+    ResNet(Box<ResNet>),
+    TwoLevel(Box<TwoLevel>),
+    Transformer(Box<Transformer>),
+}
+
+impl Network {
+    /// The layers `XOCpuAgent::quantize` rounds to int8
+    fn layers(&mut self) -> Vec<&mut dyn Quantize> {
+        match self {
+            Network::ResNet(network) => network.layers(),
+            Network::TwoLevel(network) => network.layers(),
+            Network::Transformer(network) => network.layers(),
+        }
+    }
+}
+
+/// Plays with the weights `sigmazero::inference::export_weights` writes for any of the
+/// `XOArchitecture`s, running them in pure Rust on the CPU. Needs no libtorch, so the
+/// engine can be deployed without it. Encodes positions with whichever `XOEncoding`
+/// the manifest records. `quantize` switches it to int8, which `compare` measures the
+/// accuracy cost of; in int8 a position runs in under half the time, see
+/// `bench_int8_forward`.
+#[derive(Clone)]
+pub struct XOCpuAgent {
+    network: Network,
+    manifest: Manifest,
+    encoding: XOEncoding,
+}
+
+impl XOCpuAgent {
+    /// Loads the weights at `path`, failing if they're for features or a network the
+    /// backend doesn't know
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::from_weights(&Weights::load(path)?)
+    }
+
+    pub fn from_weights(weights: &Weights) -> io::Result<Self> {
+        let manifest = &weights.manifest;
+        let encoding = manifest.encoding.parse().map_err(invalid_data)?;
+        match encoding {
+            XOEncoding::Default => manifest.check_features::<DefaultFeatures, XOGame, 81>(),
+            XOEncoding::Rich => manifest.check_features::<XORichFeatures, XOGame, 81>(),
+        }
+        .map_err(invalid_data)?;
+        let network = match manifest.architecture.parse().map_err(invalid_data)? {
+            XOArchitecture::ResNet => Network::ResNet(Box::new(ResNet::load(weights)?)),
+            XOArchitecture::TwoLevel => Network::TwoLevel(Box::new(TwoLevel::load(weights)?)),
+            XOArchitecture::Transformer => Network::Transformer(Box::new(Transformer::load(weights)?)),
+        };
+        let agent = Self { network, manifest: manifest.clone(), encoding };
+        let value_size = if manifest.wdl_head { 3 } else { 1 };
+        if agent.value_fc2().out_features() != value_size {
+            return Err(invalid_data(format!(
+                "value head gives {} numbers, but the manifest expects {}",
+                agent.value_fc2().out_features(),
+                value_size
+            )));
+        }
+        Ok(agent)
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn encoding(&self) -> XOEncoding {
+        self.encoding
+    }
+
+    pub fn architecture(&self) -> XOArchitecture {
+        match self.network {
+            Network::ResNet(_) => XOArchitecture::ResNet,
+            Network::TwoLevel(_) => XOArchitecture::TwoLevel,
+            Network::Transformer(_) => XOArchitecture::Transformer,
+        }
+    }
+
+    /// Rounds the weights of the linear and convolution layers to int8, after running
+    /// the float network over `calibration` to find the range of each layer's inputs.
+    /// Normalization, attention and the output activations stay in float.
+    pub fn quantize(&mut self, calibration: &CalibrationSet) {
+        self.network.layers().into_iter().for_each(Quantize::reset_calibration);
+        let mut ranges = InputRanges::default();
+        for (features, legal_mask) in calibration.iter() {
+            self.forward_observed(features, Some(legal_mask), &mut ranges);
+        }
+        for layer in self.network.layers() {
+            layer.quantize(&ranges);
+        }
+    }
+
+    /// The positions of `replay`, encoded the way this network reads them, to calibrate
+    /// or measure `quantize` on
+    #[cfg_attr(feature = "tch", allow(dead_code))] // Libtorch builds calibrate on replay tensors instead
+    pub fn positions(&self, replay: &ReplayBuffer<XOGame, 81>) -> CalibrationSet {
+        match self.encoding {
+            XOEncoding::Default => CalibrationSet::from_replay::<DefaultFeatures, _, 81>(replay),
+            XOEncoding::Rich => CalibrationSet::from_replay::<XORichFeatures, _, 81>(replay),
+        }
+    }
+
+    /// Whether every layer `quantize` rounds is in int8
+    #[allow(dead_code)] // Checked by the tests and benchmarks, it clones the whole network
+    pub fn is_quantized(&self) -> bool {
+        // `layers` hands them out mutably
+        self.network.clone().layers().iter().all(|layer| layer.is_quantized())
+    }
+
+    /// How far this agent's policy and value are from `float`'s on `positions`, for
+    /// checking what `quantize` cost
+    pub fn compare(&self, float: &Self, positions: &CalibrationSet) -> QuantizationReport {
+        QuantizationReport::measure(positions.iter().map(|(features, legal_mask)| {
+            let (policy, value) = float.forward(features, Some(legal_mask));
+            let (quantized_policy, quantized_value) = self.forward(features, Some(legal_mask));
+            ((policy, value_score(&value)), (quantized_policy, value_score(&quantized_value)))
+        }))
+    }
+
+    fn value_fc2(&self) -> &Linear {
+        match &self.network {
+            Network::ResNet(network) => &network.value_fc2,
+            Network::TwoLevel(network) => &network.value_fc2,
+            Network::Transformer(network) => &network.value_fc2,
+        }
+    }
+
+    /// The policy and the value head's output for the features of one position, in
+    /// the shape of its encoding, like `NNAgent::forward`
+    pub fn forward(&self, features: &[f32], legal_mask: Option<&[bool]>) -> (RawPolicy<81>, Vec<f32>) {
+        self.forward_observed(features, legal_mask, &mut ())
+    }
+
+    /// `forward`, showing the inputs of the layers still in float to `observer`
+    fn forward_observed(
+        &self,
+        features: &[f32],
+        legal_mask: Option<&[bool]>,
+        observer: &mut impl Observer,
+    ) -> (RawPolicy<81>, Vec<f32>) {
+        let shape = self.encoding.shape().iter().map(|&d| d as usize).collect();
+        let xs = CpuTensor::new(shape, features.to_vec());
+        let (policy_logits, value) = match &self.network {
+            Network::ResNet(network) => network.forward(&xs, observer),
+            Network::TwoLevel(network) => network.forward(&xs, observer),
+            Network::Transformer(network) => network.forward(&xs, observer),
+        };
+        let policy = masked_softmax(&policy_logits.data, legal_mask);
+        let value = match self.manifest.wdl_head {
+            true => {
+                let mut value = value.data;
+                softmax(&mut value);
+                value
+            }
+            false => value.tanh().data,
+        };
+        let policy_arr: [f32; 81] = policy.try_into().expect("Policy conversion from vec to array failed!");
+        (RawPolicy::new(policy_arr), value)
+    }
+
+    fn eval_game_raw(&self, game: &XOGame) -> (RawPolicy<81>, Vec<f32>) {
+        let mut features = vec![0.0; self.encoding.size()];
+        self.encoding.encode_into(game, &mut features);
+        self.forward(&features, Some(&game.legal_mask()))
+    }
+}
+
+impl Agent<XOGame, 81> for XOCpuAgent {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        let (policy, value) = self.eval_game_raw(game);
+        (policy, value_score(&value))
+    }
+
+    #[cfg(feature = "tch")]
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        let features = Vec::<f32>::try_from(&features.to_kind(tch::Kind::Float).reshape([-1]))
+            .expect("Features conversion from tensor to vec failed!");
+        let (policy, value) = self.forward(&features, None);
+        (policy, value_score(&value))
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        self.manifest.wdl_head.then(|| {
+            let (policy, value) = self.eval_game_raw(game);
+            (policy, wdl_from_value(&value))
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::policies::RandomAgent;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+    use sigmazero::game::FeatureEncoder;
+    use sigmazero::mcts::self_play;
+    use sigmazero::policy::CachedAgent;
+    use sigmazero::quantize::max_abs;
+    #[cfg(feature = "tch")]
+    use crate::architecture::XOModel;
+    #[cfg(feature = "tch")]
+    use sigmazero::data::ReplayBufferTensorData;
+    #[cfg(feature = "tch")]
+    use sigmazero::policy::NNAgent;
+    #[cfg(feature = "tch")]
+    use tch::nn;
+
+    fn manifest(encoding: &str) -> Manifest {
+        Manifest {
+            architecture: "resnet".to_string(),
+            config: vec![1.0, 4.0, 0.0],
+            encoding: encoding.to_string(),
+            features_shape: vec![3, 9, 9],
+            policy_size: 81,
+            wdl_head: false,
+            generation: 1,
+            parent: None,
+            replay_sources: Vec::new(),
+            losses: None,
+        }
+    }
+
+    /// A small ResNet reading `encoding`, with random weights and batch norm statistics,
+    /// as if exported
+    pub(crate) fn random_resnet(encoding: XOEncoding, wdl_head: bool, seed: u64) -> Weights {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut weights = Weights::new(Manifest {
+            config: vec![1.0, 8.0, 1.0],
+            features_shape: encoding.shape().to_vec(),
+            wdl_head,
+            ..manifest(encoding.name())
+        });
+        let mut add = |name: &str, shape: Vec<usize>, low: f32, high: f32| {
+            let data = (0..shape.iter().product()).map(|_| rng.gen_range(low..high)).collect();
+            weights.insert(name, CpuTensor::new(shape, data));
+        };
+        let mut layer = |path: &str, shape: Vec<usize>| {
+            let bound = 1.0 / (shape[1..].iter().product::<usize>() as f32).sqrt();
+            add(&format!("{}.bias", path), vec![shape[0]], -bound, bound);
+            add(&format!("{}.weight", path), shape, -bound, bound);
+        };
+        layer("input_conv", vec![8, encoding.shape()[0] as usize, 3, 3]);
+        layer("blocks.0.conv1", vec![8, 8, 3, 3]);
+        layer("blocks.0.conv2", vec![8, 8, 3, 3]);
+        layer("blocks.0.se.fc1", vec![2, 8]);
+        layer("blocks.0.se.fc2", vec![8, 2]);
+        layer("policy_conv", vec![2, 8, 1, 1]);
+        layer("policy_fc", vec![81, 162]);
+        layer("value_conv", vec![1, 8, 1, 1]);
+        layer("value_fc1", vec![16, 81]);
+        layer("value_fc2", vec![if wdl_head { 3 } else { 1 }, 16]);
+        for (path, channels) in
+            [("input_bn", 8), ("blocks.0.bn1", 8), ("blocks.0.bn2", 8), ("policy_bn", 2), ("value_bn", 1)]
+        {
+            add(&format!("{}.running_mean", path), vec![channels], -0.5, 0.5);
+            add(&format!("{}.running_var", path), vec![channels], 0.5, 1.5);
+            add(&format!("{}.weight", path), vec![channels], 0.5, 1.5);
+            add(&format!("{}.bias", path), vec![channels], -0.5, 0.5);
+        }
+        weights
+    }
+
+    pub(crate) fn random_positions(games: usize) -> CalibrationSet {
+        let mut agent = RandomAgent { rng: SmallRng::seed_from_u64(games as u64) };
+        CalibrationSet::from_replay::<DefaultFeatures, XOGame, 81>(&self_play(&mut agent, games, 20, 0.0, false))
+    }
+
+    #[cfg(feature = "tch")]
+    fn random_games(seed: u64, moves: usize) -> Vec<XOGame> {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut games = vec![XOGame::default()];
+        for _ in 0..moves {
+            let mut game = *games.last().unwrap();
+            let valid_moves = game.valid_moves();
+            game.take_turn(&valid_moves[rng.gen_range(0..valid_moves.len())]).unwrap();
+            games.push(game);
+        }
+        games
+    }
+
+    #[test]
+    fn test_weights_round_trip() {
+        let mut weights = Weights::new(manifest("default"));
+        weights.insert("fc.weight", CpuTensor::new(vec![2, 3], vec![1.0, -2.0, 0.5, 0.0, 3.25, -1e-3]));
+        weights.insert("fc.bias", CpuTensor::new(vec![2], vec![0.1, -0.1]));
+        let path = std::env::temp_dir().join("xo_weights_test.weights");
+        weights.save(&path).expect("Save failed");
+        assert_eq!(Weights::load(&path).expect("Load failed"), weights);
+
+        std::fs::write(&path, b"not weights at all").unwrap();
+        assert!(Weights::load(&path).is_err());
+        assert!(Weights::load(&std::env::temp_dir().join("no_such_weights.weights")).is_err());
+    }
+
+    #[test]
+    fn test_weights_load_rejects_oversized_lengths() {
+        let mut weights = Weights::new(manifest("default"));
+        weights.insert("fc.weight", CpuTensor::new(vec![2, 3], vec![0.5; 6]));
+        let path = std::env::temp_dir().join("xo_weights_oversized.weights");
+        weights.save(&path).expect("Save failed");
+        let bytes = std::fs::read(&path).unwrap();
+        let load_patched = |at: usize, value: u64| {
+            let mut patched = bytes.clone();
+            patched[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            std::fs::write(&path, patched).unwrap();
+            Weights::load(&path).map(|_| ()).map_err(|e| e.kind())
+        };
+
+        // The manifest's length follows the 8 magic bytes
+        assert_eq!(load_patched(8, u64::MAX), Err(std::io::ErrorKind::InvalidData));
+        // The first dimension follows the name and the number of axes
+        let name = b"fc.weight";
+        let first_dim = bytes.windows(name.len()).position(|w| w == name).unwrap() + name.len() + 8;
+        assert_eq!(load_patched(first_dim, 1 << 40), Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(load_patched(first_dim, u64::MAX / 2), Err(std::io::ErrorKind::InvalidData));
+        assert_eq!(load_patched(first_dim, 2), Ok(()));
+    }
+
+    #[test]
+    fn test_conv_padding() {
+        let mut weights = Weights::new(manifest("default"));
+        weights.insert("conv.weight", CpuTensor::new(vec![1, 1, 3, 3], vec![1.0; 9]));
+        let conv = Conv2d::load(&weights, "conv", 1).unwrap();
+        let ys = conv.forward(&CpuTensor::new(vec![1, 3, 3], vec![1.0; 9]));
+        assert_eq!(ys.shape, vec![1, 3, 3]);
+        assert_eq!(ys.data, vec![4.0, 6.0, 4.0, 6.0, 9.0, 6.0, 4.0, 6.0, 4.0]);
+    }
+
+    #[test]
+    fn test_int8_linear() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut weights = Weights::new(manifest("default"));
+        let mut random = |shape: Vec<usize>| {
+            let data = (0..shape.iter().product()).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            CpuTensor::new(shape, data)
+        };
+        weights.insert("fc.weight", random(vec![8, 32]));
+        weights.insert("fc.bias", random(vec![8]));
+        let inputs = random(vec![16, 32]);
+        let mut linear = Linear::load(&weights, "fc").unwrap();
+        let mut ranges = InputRanges::default();
+        let expected = linear.forward_observed(&inputs, &mut ranges);
+        assert_eq!(ranges.range_of(&linear), Some(max_abs(&inputs.data)));
+        assert_eq!(linear.forward(&inputs), expected);
+        // Ranges are kept by weight path, so they still fit the layer once it's moved
+        let mut moved = Box::new(linear.clone());
+        moved.quantize(&ranges);
+        linear.quantize(&ranges);
+        assert!(linear.is_quantized() && moved.is_quantized());
+        assert_eq!(moved.forward(&inputs), linear.forward(&inputs));
+        let actual = linear.forward(&inputs);
+        assert_eq!(actual.shape, expected.shape);
+        // Inputs and weights are each off by at most half a step of 1 / 127
+        for (x, y) in expected.data.iter().zip(&actual.data) {
+            assert!((x - y).abs() < 32.0 / 127.0, "{} and {} differ", x, y);
+        }
+        let error = expected.data.iter().zip(&actual.data).map(|(x, y)| (x - y).powi(2)).sum::<f32>();
+        assert!(error / (expected.data.len() as f32) < 1e-3);
+
+        // Calibrating again starts from float
+        linear.reset_calibration();
+        assert!(!linear.is_quantized());
+        assert_eq!(linear.forward(&inputs), expected);
+    }
+
+    #[test]
+    fn test_int8_conv() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut weights = Weights::new(manifest("default"));
+        let mut random = |shape: Vec<usize>| {
+            let data = (0..shape.iter().product()).map(|_| rng.gen_range(-1.0..1.0)).collect();
+            CpuTensor::new(shape, data)
+        };
+        weights.insert("conv.weight", random(vec![4, 3, 3, 3]));
+        weights.insert("conv.bias", random(vec![4]));
+        let inputs = random(vec![3, 5, 4]);
+        for padding in [0, 1] {
+            let mut conv = Conv2d::load(&weights, "conv", padding).unwrap();
+            let mut ranges = InputRanges::default();
+            let expected = conv.forward_observed(&inputs, &mut ranges);
+            conv.quantize(&ranges);
+            let actual = conv.forward(&inputs);
+            assert_eq!(actual.shape, expected.shape);
+            // Each of the 27 products is off by at most about 1 / 127
+            for (x, y) in expected.data.iter().zip(&actual.data) {
+                assert!((x - y).abs() < 27.0 / 127.0, "{} and {} differ", x, y);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Layer value_fc missed by the calibration pass")]
+    fn test_quantize_without_range() {
+        let mut weights = Weights::new(manifest("default"));
+        weights.insert("policy_fc.weight", CpuTensor::new(vec![1, 2], vec![1.0; 2]));
+        weights.insert("value_fc.weight", CpuTensor::new(vec![1, 2], vec![1.0; 2]));
+        let policy_fc = Linear::load(&weights, "policy_fc").unwrap();
+        let mut value_fc = Linear::load(&weights, "value_fc").unwrap();
+        // Only the identical policy layer is calibrated
+        let mut ranges = InputRanges::default();
+        policy_fc.forward_observed(&CpuTensor::new(vec![2], vec![1.0; 2]), &mut ranges);
+        value_fc.quantize(&ranges);
+    }
+
+    #[test]
+    fn test_quantized_agent_stays_close() {
+        let calibration = random_positions(4);
+        let test_positions = random_positions(2);
+        for wdl_head in [false, true] {
+            let float_agent = XOCpuAgent::from_weights(&random_resnet(XOEncoding::Default, wdl_head, 1)).unwrap();
+            let mut agent = float_agent.clone();
+            agent.quantize(&calibration);
+            assert!(agent.is_quantized() && !float_agent.is_quantized());
+            // Float passes leave the layers alone, so agents can be shared across threads
+            fn shareable<T: Sync>(_: &T) {}
+            shareable(&agent);
+
+            let report = agent.compare(&float_agent, &test_positions);
+            assert_eq!(report.positions, test_positions.len());
+            assert!(report.policy_kl < 1e-3 && report.value_mse < 1e-3, "{}", report);
+            assert_eq!(float_agent.compare(&float_agent, &test_positions).value_mse, 0.0);
+
+            let game = XOGame::default();
+            let (policy, _) = agent.eval_game(&game);
+            assert!((policy.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+            assert_eq!(agent.eval_game_wdl(&game).is_some(), wdl_head);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_calibration_from_tensor_data() {
+        let mut agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<XOGame, _, 81>(&mut agent, 2, 20, 0.0, false);
+        let positions = CalibrationSet::from_replay::<DefaultFeatures, _, 81>(&replay);
+        let data = ReplayBufferTensorData::encode::<DefaultFeatures, _, 81>(replay);
+        assert_eq!(CalibrationSet::from_tensor_data::<DefaultFeatures, XOGame, 81>(&data).unwrap(), positions);
+        assert!(CalibrationSet::from_tensor_data::<XORichFeatures, XOGame, 81>(&data).is_err());
+    }
+
+    #[test]
+    fn test_cached_agent_forwards_wdl() {
+        let mut game = XOGame::default();
+        game.take_turn(&game.valid_moves()[3]).unwrap();
+        for wdl_head in [false, true] {
+            let agent = XOCpuAgent::from_weights(&random_resnet(XOEncoding::Default, wdl_head, 3)).unwrap();
+            let mut cached = CachedAgent::new(agent.clone());
+            let wdl = cached.eval_game_wdl(&game);
+            assert_eq!(wdl.is_some(), wdl_head);
+            let (policy, value) = cached.eval_game(&game);
+            assert_eq!(cached.len(), 1);
+            if let Some((wdl_policy, wdl)) = wdl {
+                assert_eq!(wdl_policy, policy);
+                assert_eq!(wdl.expected_score(0.0), value);
+            }
+            // Batches agree with single positions, and cache each new position once
+            let mut next = game;
+            next.take_turn(&next.valid_moves()[0]).unwrap();
+            let batch = cached.eval_games(&[game, next, next]);
+            assert_eq!(batch[0], (policy, value));
+            assert_eq!(batch[1], batch[2]);
+            assert_eq!(cached.len(), 2);
+            assert_eq!(cached.eval_game(&next), batch[1]);
+            // The batch had no win/draw/loss chances, so they're filled in when asked for
+            let wdl = cached.eval_game_wdl(&next);
+            assert_eq!(wdl.is_some(), wdl_head);
+            assert_eq!(cached.len(), 2);
+            if let Some((wdl_policy, wdl)) = wdl {
+                assert_eq!(wdl_policy, batch[1].0);
+                assert!((wdl.expected_score(0.0) - batch[1].1).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reads_recorded_encoding() {
+        let mut agent = XOCpuAgent::from_weights(&random_resnet(XOEncoding::Rich, false, 2)).unwrap();
+        assert_eq!(agent.encoding(), XOEncoding::Rich);
+        let mut game = XOGame::default();
+        game.take_turn(&game.valid_moves()[0]).unwrap();
+        let features = XORichFeatures::encode_batch_into(&[game]);
+        let (policy, value) = agent.forward(&features, Some(&game.legal_mask()));
+        assert_eq!(agent.eval_game(&game), (policy, value_score(&value)));
+    }
+
+    #[test]
+    fn test_rejects_other_features() {
+        // The rich encoding has more planes than the manifest says
+        let weights = Weights::new(manifest(XORichFeatures::NAME));
+        assert!(XOCpuAgent::from_weights(&weights).is_err());
+        assert!(XOCpuAgent::from_weights(&Weights::new(manifest("xo-rich-v1"))).is_err());
+        // Default features with no tensors to run them through
+        assert!(XOCpuAgent::from_weights(&Weights::new(manifest("default"))).is_err());
+    }
+
+    #[cfg(feature = "tch")]
+    fn check_matches_tch<const WDL: bool>(architecture: XOArchitecture, config: Vec<f32>) {
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        architecture.store(&vs);
+        match architecture {
+            XOArchitecture::ResNet => ResNetConfig::from_slice(&config).store(&vs),
+            XOArchitecture::TwoLevel => TwoLevelConfig::from_slice(&config).store(&vs),
+            XOArchitecture::Transformer => TransformerConfig::from_slice(&config).store(&vs),
+        }
+        let mut model = XOModel::<DefaultFeatures, WDL>::new(&vs);
+        // Running statistics away from their initial values, so batch norms do something
+        tch::no_grad(|| {
+            for (name, mut tensor) in vs.variables() {
+                if name.ends_with("running_mean") {
+                    tensor.copy_(&(tensor.rand_like() - 0.5));
+                } else if name.ends_with("running_var") {
+                    tensor.copy_(&(tensor.rand_like() + 0.5));
+                }
+            }
+        });
+        let manifest = Manifest::new::<XOModel<DefaultFeatures, WDL>, XOGame, 81>(&vs);
+        let weights = Weights::from_var_store(&vs, &manifest).expect("Export failed");
+        let mut cpu_agent = XOCpuAgent::from_weights(&weights).expect("Load failed");
+        assert_eq!(cpu_agent.architecture(), architecture);
+
+        let close = |(p1, v1): (RawPolicy<81>, f32), (p2, v2): (RawPolicy<81>, f32)| {
+            (v1 - v2).abs() < 1e-4 && p1.iter().zip(p2.iter()).all(|(a, b)| (a - b).abs() < 1e-4)
+        };
+        for game in random_games(0, 8) {
+            assert!(close(model.eval_game(&game), cpu_agent.eval_game(&game)), "{} differs", architecture);
+            match (model.eval_game_wdl(&game), cpu_agent.eval_game_wdl(&game)) {
+                (Some((_, wdl)), Some((_, cpu_wdl))) => assert!((wdl.draw - cpu_wdl.draw).abs() < 1e-4),
+                (None, None) => {}
+                _ => panic!("WDL heads differ"),
+            }
+        }
+        assert_eq!(cpu_agent.encoding(), XOEncoding::Default);
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_matches_tch_networks() {
+        let configs = [
+            (XOArchitecture::ResNet, vec![2.0, 8.0, 1.0]),
+            (XOArchitecture::TwoLevel, vec![8.0, 16.0, 2.0]),
+            (XOArchitecture::Transformer, vec![2.0, 16.0, 2.0, 32.0]),
+        ];
+        for (architecture, config) in configs {
+            check_matches_tch::<false>(architecture, config.clone());
+            check_matches_tch::<true>(architecture, config);
+        }
+    }
+}
@@ -1,97 +1,591 @@
 #![feature(test)]
 extern crate test;
 
+mod architecture;
 mod board;
+mod connect_four;
+mod features;
 mod game;
+mod inference;
+mod nested_board;
+mod nested_game;
 mod policies;
+mod puzzle;
+mod resnet;
+mod rules;
 mod small_board;
+mod stochastic_game;
+mod symmetry;
+mod three_player;
+mod tictactoe;
+#[cfg(feature = "tch")]
+mod torchscript;
+mod transformer;
+mod two_level;
 
+use connect_four::ConnectFour;
 use game::XOGame;
+use nested_game::{Ultimate4Game, UltimateDepth3Game};
+use puzzle::LinePuzzle;
+#[cfg(feature = "tch")]
+use sigmazero::checkpoint::{load_checkpoint, save_checkpoint, Manifest};
+#[cfg(feature = "tch")]
 use sigmazero::data::ReplayBufferTensorData;
+use sigmazero::evaluate::{evaluate_agents_from, evaluate_seats_from};
+#[cfg(feature = "tch")]
 use sigmazero::evaluate::evaluate_agents;
+#[cfg(feature = "tch")]
+use sigmazero::inference::export_weights;
+#[cfg(feature = "tch")]
 use sigmazero::learning::train_on_replay;
-use sigmazero::policy::{Agent, NNAgent};
-use sigmazero::{game::Game, mcts::self_play};
+use sigmazero::policy::{Agent, CachedAgent};
+#[cfg(feature = "tch")]
+use sigmazero::policy::NetworkConfig;
+#[cfg(feature = "tch")]
+use sigmazero::policy::NNAgent;
+#[cfg(feature = "tch")]
+use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+use sigmazero::game::{Game, Player};
+#[cfg(feature = "tch")]
+use sigmazero::mcts::self_play;
+use sigmazero::mcts::self_play_from;
+#[cfg(feature = "tch")]
+use sigmazero::quantize::CalibrationSet;
 use std::path::Path;
-use std::time::Instant;
-use tch::nn::{self, OptimizerConfig};
-use tch::Kind;
+use std::fmt;
+#[cfg(feature = "tch")]
+use std::hash::Hash;
+use std::str::FromStr;
+#[cfg(feature = "tch")]
+use tch::nn;
 
-use policies::{RandomAgent, XONNAgent};
+#[cfg(feature = "tch")]
+use architecture::{XOArchitecture, XOEncodedModel, XOModel};
+#[cfg(feature = "tch")]
+use features::{XOEncoding, XORichFeatures};
+use inference::XOCpuAgent;
+use policies::RandomAgent;
+#[cfg(feature = "tch")]
+use policies::{ConnectFourNNAgent, TicTacToeNNAgent};
+use rules::Ruleset;
+#[cfg(feature = "tch")]
+use torchscript::{export_torchscript, TorchScriptAgent};
+use stochastic_game::StochasticXOGame;
+use three_player::ThreePlayerGame;
+use tictactoe::TicTacToe;
 
-fn generate_new_games() {
-    let device = tch::Device::Cpu;
+/// The value following `--name` on the command line, if it's there
+fn option<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let args: Vec<String> = std::env::args().collect();
+    let flag = format!("--{}", name);
+    let index = args.iter().position(|arg| *arg == flag)?;
+    let value = args.get(index + 1).unwrap_or_else(|| panic!("{} needs a value", flag));
+    Some(value.parse().unwrap_or_else(|e| panic!("{}", e)))
+}
+
+/// The games this binary plays, picked with `--game`. Ultimate XO is the default and
+/// the only one with a full training pipeline, the others try the search on other games.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum GameChoice {
+    #[default]
+    UltimateXO,
+    TicTacToe,
+    ConnectFour,
+    ThreePlayer,
+    Stochastic,
+    Ultimate4,
+    UltimateDepth3,
+    LinePuzzle,
+}
+
+impl GameChoice {
+    const ALL: [GameChoice; 8] = [
+        Self::UltimateXO,
+        Self::TicTacToe,
+        Self::ConnectFour,
+        Self::ThreePlayer,
+        Self::Stochastic,
+        Self::Ultimate4,
+        Self::UltimateDepth3,
+        Self::LinePuzzle,
+    ];
+
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::UltimateXO => "ultimate-xo",
+            Self::TicTacToe => "tictactoe",
+            Self::ConnectFour => "connect-four",
+            Self::ThreePlayer => "three-player",
+            Self::Stochastic => "stochastic",
+            Self::Ultimate4 => "ultimate-4",
+            Self::UltimateDepth3 => "ultimate-depth-3",
+            Self::LinePuzzle => "line-puzzle",
+        }
+    }
+
+    /// Random agents in every seat of the game, for the games without a network. The
+    /// ultimate games are played under `--ruleset`.
+    fn play_random(&self) {
+        let ruleset: Ruleset = option("ruleset").unwrap_or_default();
+        match self {
+            Self::UltimateXO => play_random::<_, 81>(XOGame::with_ruleset(ruleset)),
+            Self::TicTacToe => play_random::<_, 9>(TicTacToe::default()),
+            Self::ConnectFour => play_random::<_, 7>(ConnectFour::default()),
+            Self::ThreePlayer => play_random::<_, 16>(ThreePlayerGame::default()),
+            Self::Stochastic => play_random::<_, 81>(StochasticXOGame::default()),
+            Self::Ultimate4 => play_random::<_, 256>(Ultimate4Game::<3>::with_ruleset(ruleset)),
+            Self::UltimateDepth3 => play_random::<_, 729>(UltimateDepth3Game::with_ruleset(ruleset)),
+            Self::LinePuzzle => play_random::<_, 9>(LinePuzzle::default()),
+        }
+    }
+}
+
+impl FromStr for GameChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|game| game.name() == s)
+            .ok_or_else(|| format!("Unknown game {:?}, expected one of {:?}", s, Self::ALL.map(|g| g.name())))
+    }
+}
+
+/// Plays a random agent for every player of `G` against the others from `start`,
+/// searching with `--draw-contempt`
+fn play_random<G: Game<N>, const N: usize>(start: G) {
+    let mut agents: Vec<_> = G::Player::PLAYERS.iter().map(|_| RandomAgent { rng: rand::thread_rng() }).collect();
+    let mut seats: Vec<&mut dyn Agent<G, N>> = agents.iter_mut().map(|agent| agent as &mut dyn Agent<G, N>).collect();
+    let draw_contempt = option("draw-contempt").unwrap_or(0.0);
+    let results = evaluate_seats_from(start, &mut seats, 20, 200, draw_contempt, false);
+    println!("{:?}", results);
+}
+
+/// Trains a small network `A` on random self-play of `G` and plays it against a random agent
+#[cfg(feature = "tch")]
+fn train_small<A: NNAgent<G, N>, G: Game<N> + Eq + Hash, const N: usize>() {
+    let mut random_agent = RandomAgent { rng: rand::thread_rng() };
+    let replay = self_play::<G, _, N>(&mut random_agent, 200, 200, 0.0, false);
+    let replay_data = ReplayBufferTensorData::encode::<A::Encoder, _, N>(replay.deduplicated().augmented());
+
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    train_on_replay::<A, G, N>(&vs, &replay_data, 64, 30, 0.9);
+    let mut agent = A::new(&vs);
+
+    let draw_contempt = option("draw-contempt").unwrap_or(0.0);
+    let as_first = evaluate_agents(&mut agent, &mut random_agent, 20, 100, draw_contempt, false);
+    println!("Moving first: {:?}", as_first);
+    let as_second = evaluate_agents(&mut random_agent, &mut agent, 20, 100, draw_contempt, false);
+    println!("Moving second: {:?}", as_second);
+}
+
+/// Saves the augmented self-play of random agents under `--ruleset` to `path`, for a
+/// first network to train on
+#[cfg(feature = "tch")]
+fn generate_new_games<E: FeatureEncoder<XOGame, 81>>(path: &Path) {
     // Generate random games for initial data
     let rng = rand::thread_rng();
     let mut agent = RandomAgent { rng };
 
     let n_games = 1000;
+    let start = XOGame::with_ruleset(option("ruleset").unwrap_or_default());
 
-    let replay = self_play(&mut agent, n_games, 800, false);
-    let replay_augmented = replay.augmented();
+    let replay = self_play_from::<XOGame, _, 81>(start, &mut agent, n_games, 800, 0.0, false);
+    let replay_deduplicated = replay.deduplicated();
+    let replay_augmented = replay_deduplicated.augmented();
 
-    println!("Replay augmented from {} to {}", replay.len(), replay_augmented.len());
+    println!("Replay deduplicated from {} to {}", replay.len(), replay_deduplicated.len());
+    println!("Replay augmented from {} to {}", replay_deduplicated.len(), replay_augmented.len());
     // for i in 0..8 {
     //     let print_idx = i+64;
     //     println!("{}\n{}\n{}", replay_augmented.games[print_idx], XOGame::displays(replay_augmented.policies[print_idx].format_to_print()), replay_augmented.values[print_idx]);
     // }
 
-    let replay_data: ReplayBufferTensorData = replay_augmented.into();
-    replay_data.save_to_file(Path::new("random_games_2.ot")).unwrap();
+    let replay_data = ReplayBufferTensorData::encode::<E, _, 81>(replay_augmented);
+    replay_data.save_to_file(path).unwrap();
 }
 
+/// Trains a network reading `--encoding`, the default features unless given, e.g.
+/// `--encoding xo-rich-v2`. Its dataset has to be encoded to match. `--architecture`
+/// picks the network, e.g. `--architecture two-level`, a ResNet unless given. The
+/// network then plays with `--draw-contempt`, 0 unless given, under `--ruleset`, e.g.
+/// `--ruleset play-on,most-boards`, the classic rules unless given. `--game` picks
+/// another game, e.g. `--game tictactoe`.
+#[cfg(feature = "tch")]
 fn main() {
+    match option("game").unwrap_or_default() {
+        GameChoice::UltimateXO => match option("encoding").unwrap_or_default() {
+            XOEncoding::Default => train_and_evaluate::<DefaultFeatures>(),
+            XOEncoding::Rich => train_and_evaluate::<XORichFeatures>(),
+        },
+        GameChoice::TicTacToe => train_small::<TicTacToeNNAgent, TicTacToe, 9>(),
+        GameChoice::ConnectFour => train_small::<ConnectFourNNAgent, ConnectFour, 7>(),
+        game => game.play_random(),
+    }
+}
+
+#[cfg(feature = "tch")]
+fn train_and_evaluate<E: FeatureEncoder<XOGame, 81>>() {
     let device = tch::Device::Cpu;
     
     // Train NN
-    let mut replay_data = ReplayBufferTensorData::load_from_file(Path::new("random_games_2.ot"), device).unwrap();
+    let replay_path = Path::new("random_games_2.ot");
+    if !replay_path.exists() {
+        generate_new_games::<E>(replay_path);
+    }
+    let replay_data = ReplayBufferTensorData::load_from_file::<E, XOGame, 81>(replay_path, device).unwrap();
     println!("Cuda available: {:?}", tch::Cuda::is_available());
     println!("Cudnn available: {}", tch::Cuda::cudnn_is_available());
     
     let batch_size = 32;
     let epochs = 100;
+    let architecture: XOArchitecture = option("architecture").unwrap_or_default();
     let vs = nn::VarStore::new(device);
-    train_on_replay::<XONNAgent, XOGame, 81>(&vs, &replay_data, batch_size, epochs, 0.8);
-    vs.save("model_0.ot".to_string()).expect("Save Failed");
+    architecture.store(&vs);
+    let losses = train_on_replay::<XOModel<E>, XOGame, 81>(&vs, &replay_data, batch_size, epochs, 0.8);
+    let model_path = Path::new("./model_0.ot");
+    let manifest = Manifest {
+        replay_sources: vec![replay_path.display().to_string()],
+        losses: Some(losses),
+        ..Manifest::new::<XOModel<E>, XOGame, 81>(&vs)
+    };
+    save_checkpoint(&vs, &manifest, model_path).expect("Save Failed");
+    let weights_path = Path::new("./model_0.weights");
+    export_weights(&vs, &manifest, weights_path).expect("Export Failed");
+    let (trained, _, _) = load_checkpoint::<XOModel<E>, XOGame, 81>(model_path, device).expect("Model load failed");
+    let script_path = Path::new("./model_0.pt");
+    export_torchscript(&trained, &manifest, script_path, device).expect("Export Failed");
+
+    // What rounding the exported network to int8 costs, on positions it wasn't calibrated on
+    let (calibration_data, rest) = replay_data.random_split(0.02);
+    let (test_data, _) = rest.random_split(0.02);
+    let calibration = CalibrationSet::from_tensor_data::<E, XOGame, 81>(&calibration_data).unwrap();
+    let test_positions = CalibrationSet::from_tensor_data::<E, XOGame, 81>(&test_data).unwrap();
+    let float_agent = XOCpuAgent::load(weights_path).expect("Weights load failed");
+    println!(
+        "Exported the {} network reading {} features, generation {}",
+        float_agent.architecture(),
+        float_agent.encoding(),
+        float_agent.manifest().generation
+    );
+    let mut quantized_agent = float_agent.clone();
+    quantized_agent.quantize(&calibration);
+    println!("Int8 network: {}", quantized_agent.compare(&float_agent, &test_positions));
 
     // evaluation
     let rng = rand::thread_rng();
     let mut agent1 = RandomAgent { rng };
 
-    let mut vs = nn::VarStore::new(device);
-    vs.load(&Path::new("./model_0.ot"))
-        .expect("Model load failed");
-    let mut agent2 = XONNAgent::new(&vs);
+    let (model, _, manifest) = <XOEncodedModel>::load(model_path, device).expect("Model load failed");
+    println!(
+        "Evaluating the {} network reading {} features, generation {}",
+        model.architecture(),
+        model.encoding(),
+        manifest.generation
+    );
+    // Played through the TorchScript module, which has to carry the same manifest
+    let exported = TorchScriptAgent::<E>::load(script_path, device).expect("Module load failed");
+    assert_eq!(exported.manifest(), Some(&manifest));
+    let mut agent2 = CachedAgent::new(exported);
+
+    let draw_contempt = option("draw-contempt").unwrap_or(0.0);
+    let ruleset: Ruleset = option("ruleset").unwrap_or_default();
+    println!("Playing under the {} rules", ruleset);
+    let start = XOGame::with_ruleset(ruleset);
+    let evaluation_results = evaluate_agents_from(start, &mut agent1, &mut agent2, 40, 400, draw_contempt, false);
+    println!("{:?}", evaluation_results);
+}
+
+/// Without libtorch, plays the network a libtorch build exported on the CPU backend,
+/// rounded to int8, with `--draw-contempt` under `--ruleset`. Any other `--game` is
+/// played by random agents.
+#[cfg(not(feature = "tch"))]
+fn main() {
+    match option("game").unwrap_or_default() {
+        GameChoice::UltimateXO => evaluate_cpu_network(),
+        game => game.play_random(),
+    }
+}
+
+#[cfg(not(feature = "tch"))]
+fn evaluate_cpu_network() {
+    let rng = rand::thread_rng();
+    let mut agent1 = RandomAgent { rng };
 
-    let evaluation_results = evaluate_agents(&mut agent1, &mut agent2, 40, 400, false);
+    let model_path = Path::new("./model_0.weights");
+    let model = XOCpuAgent::load(model_path).expect("Model load failed");
+    println!(
+        "Evaluating the {} network reading {} features, generation {}",
+        model.architecture(),
+        model.encoding(),
+        model.manifest().generation
+    );
+
+    // Replay files need libtorch to read, so calibrate on fresh random games
+    let ruleset: Ruleset = option("ruleset").unwrap_or_default();
+    println!("Playing under the {} rules", ruleset);
+    let start = XOGame::with_ruleset(ruleset);
+    let calibration_games = self_play_from::<XOGame, _, 81>(start, &mut agent1, 20, 20, 0.0, false);
+    let test_games = self_play_from::<XOGame, _, 81>(start, &mut agent1, 20, 20, 0.0, false);
+    let calibration = model.positions(&calibration_games);
+    let test_positions = model.positions(&test_games);
+    let mut quantized_model = model.clone();
+    quantized_model.quantize(&calibration);
+    println!("Int8 network: {}", quantized_model.compare(&model, &test_positions));
+    let mut agent2 = CachedAgent::new(quantized_model);
+
+    let draw_contempt = option("draw-contempt").unwrap_or(0.0);
+    let evaluation_results = evaluate_agents_from(start, &mut agent1, &mut agent2, 40, 400, draw_contempt, false);
     println!("{:?}", evaluation_results);
 }
 
 #[cfg(test)]
 mod benchmarks {
-    use crate::board::play_random_game;
+    use crate::board::{MainBoard, XOPlayer};
+    use crate::features::XOEncoding;
+    use crate::game::XOGame;
+    use crate::inference::tests::{random_positions, random_resnet};
+    use crate::inference::XOCpuAgent;
+    use rand::seq::SliceRandom;
+    use sigmazero::game::Game;
+    use sigmazero::policy::RawPolicy;
     use test::Bencher;
 
+    fn play_random_game() -> Option<XOPlayer> {
+        let mut board = MainBoard::default();
+        let mut rng = rand::thread_rng();
+        let mut player = XOPlayer::X;
+
+        loop {
+            match board.valid_moves().choose(&mut rng) {
+                Some(mv) => board.set_cell(mv, player),
+                None => break None,
+            }
+            // println!("{board}");
+            if let Some(winner) = board.winner() {
+                println!("Player {winner} wins!");
+                break Some(winner);
+            }
+            player = player.other_player();
+        }
+    }
+
     #[bench]
     fn bench_play_game(b: &mut Bencher) {
-        b.iter(|| play_random_game());
+        b.iter(play_random_game);
+    }
+
+    #[bench]
+    fn bench_augment_game(b: &mut Bencher) {
+        let mut game = XOGame::default();
+        for _ in 0..20 {
+            let valid_move = game.valid_moves()[0];
+            game.take_turn(&valid_move).unwrap();
+        }
+        let policy = RawPolicy::new([1.0 / 81.0; 81]);
+        b.iter(|| game.augmented_with_raw_policy(&policy));
+    }
+
+    /// A small random ResNet, in float or rounded to int8, and a position to run it on
+    fn cpu_agent(int8: bool) -> (XOCpuAgent, Vec<f32>, [bool; 81]) {
+        let mut agent = XOCpuAgent::from_weights(&random_resnet(XOEncoding::Default, false, 0)).unwrap();
+        let positions = random_positions(4);
+        if int8 {
+            agent.quantize(&positions);
+            assert!(agent.is_quantized());
+        }
+        let (features, legal_mask) = positions.iter().nth(10).unwrap();
+        (agent, features.to_vec(), legal_mask.try_into().unwrap())
+    }
+
+    #[bench]
+    fn bench_float_forward(b: &mut Bencher) {
+        let (agent, features, legal_mask) = cpu_agent(false);
+        b.iter(|| agent.forward(&features, Some(&legal_mask)));
+    }
+
+    #[bench]
+    fn bench_int8_forward(b: &mut Bencher) {
+        let (agent, features, legal_mask) = cpu_agent(true);
+        b.iter(|| agent.forward(&features, Some(&legal_mask)));
     }
 }
 
 #[test]
+#[cfg(feature = "tch")]
 fn test_model_save() {
+    use policies::XONNAgent;
+
     let device = tch::Device::Cpu;
     let game = XOGame::default();
     let vs1 = nn::VarStore::new(device);
-    let mut agent1 = XONNAgent::new(&vs1);
+    let mut agent1 = <XONNAgent>::new(&vs1);
     let eval1 = agent1.eval_game(&game);
-    vs1.save("model_test.ot".to_string()).expect("Save Failed");
+    vs1.save("model_test.ot").expect("Save Failed");
 
     let mut vs2 = nn::VarStore::new(device);
-    vs2.load(&Path::new("./model_test.ot"))
+    let mut agent2 = <XONNAgent>::new(&vs2);
+    vs2.load(Path::new("./model_test.ot"))
         .expect("Model load failed");
-    let mut agent2 = XONNAgent::new(&vs2);
     let eval2 = agent2.eval_game(&game);
 
     assert_eq!(eval1, eval2);
 }
+
+#[test]
+#[cfg(feature = "tch")]
+fn test_checkpoint_keeps_config() {
+    use policies::XONNAgent;
+    use resnet::ResNetConfig;
+    use sigmazero::policy::restore_config;
+
+    let device = tch::Device::Cpu;
+    let game = XOGame::default();
+    let config = ResNetConfig { blocks: 2, filters: 16, squeeze_excitation: true };
+    let vs1 = nn::VarStore::new(device);
+    let mut agent1 = XONNAgent::<DefaultFeatures>::with_config(&vs1, config);
+    let eval1 = agent1.eval_game(&game);
+    let path = std::env::temp_dir().join("resnet_config_test.ot");
+    vs1.save(&path).expect("Save Failed");
+
+    // A fresh store only learns the architecture from the checkpoint
+    let mut vs2 = nn::VarStore::new(device);
+    restore_config(&vs2, &path).expect("Config load failed");
+    assert_eq!(ResNetConfig::from_var_store(&vs2), Some(config));
+    let mut agent2 = <XOModel>::new(&vs2);
+    vs2.load(&path).expect("Model load failed");
+    assert_eq!(agent1.eval_game(&game), eval1);
+    assert_eq!(agent2.eval_game(&game), eval1);
+}
+
+#[test]
+fn test_manifest_round_trip() {
+    use sigmazero::checkpoint::Manifest;
+    use sigmazero::learning::Losses;
+
+    let manifest = Manifest {
+        architecture: "two-level".to_string(),
+        config: vec![32.0, 64.0, 2.0],
+        encoding: "xo-rich-v2".to_string(),
+        features_shape: vec![10, 9, 9],
+        policy_size: 81,
+        wdl_head: true,
+        generation: 3,
+        parent: Some("models/gen_2.ot".to_string()),
+        replay_sources: vec!["replay_a.ot".to_string(), "replay b.ot".to_string()],
+        losses: Some(Losses { train_policy: 2.5, train_value: 0.25, test_policy: 2.75, test_value: 0.5 }),
+    };
+    assert_eq!(manifest.to_string().parse::<Manifest>().unwrap(), manifest);
+
+    let bare = Manifest { config: Vec::new(), parent: None, replay_sources: Vec::new(), losses: None, ..manifest };
+    assert_eq!(bare.to_string().parse::<Manifest>().unwrap(), bare);
+    assert!("architecture: resnet\n".parse::<Manifest>().is_err());
+}
+
+#[test]
+#[cfg(feature = "tch")]
+fn test_checkpoint_builds_recorded_architecture() {
+    use policies::{TicTacToeNNAgent, XONNAgent};
+    use sigmazero::checkpoint::load_checkpoint;
+    use tictactoe::TicTacToe;
+    use two_level::XOTwoLevelAgent;
+
+    let device = tch::Device::Cpu;
+    let game = XOGame::default();
+    let vs = nn::VarStore::new(device);
+    XOArchitecture::TwoLevel.store(&vs);
+    let mut model = <XOModel>::new(&vs);
+    let eval = model.eval_game(&game);
+    let manifest = Manifest {
+        generation: 1,
+        parent: Some("model_0.ot".to_string()),
+        ..Manifest::new::<XOModel, XOGame, 81>(&vs)
+    };
+    assert_eq!(manifest.architecture, "two-level");
+    let path = std::env::temp_dir().join("xo_checkpoint_test.ot");
+    save_checkpoint(&vs, &manifest, &path).expect("Save Failed");
+
+    let (mut loaded, _, loaded_manifest) = load_checkpoint::<XOModel, XOGame, 81>(&path, device).expect("Model load failed");
+    assert_eq!(loaded.architecture(), XOArchitecture::TwoLevel);
+    assert_eq!(loaded_manifest, manifest);
+    assert_eq!(loaded.eval_game(&game), eval);
+    assert!(load_checkpoint::<XOTwoLevelAgent, XOGame, 81>(&path, device).is_ok());
+
+    // Agents that would read it wrongly are turned away before building anything
+    assert!(load_checkpoint::<XONNAgent, XOGame, 81>(&path, device).is_err());
+    assert!(load_checkpoint::<XOModel<XORichFeatures>, XOGame, 81>(&path, device).is_err());
+    assert!(load_checkpoint::<XOModel<DefaultFeatures, true>, XOGame, 81>(&path, device).is_err());
+    assert!(load_checkpoint::<TicTacToeNNAgent, TicTacToe, 9>(&path, device).is_err());
+
+    let bare_path = std::env::temp_dir().join("xo_bare_checkpoint_test.ot");
+    vs.save(&bare_path).expect("Save Failed");
+    assert!(load_checkpoint::<XOModel, XOGame, 81>(&bare_path, device).is_err());
+}
+
+#[test]
+#[cfg(feature = "tch")]
+fn test_checkpoint_builds_recorded_encoding() {
+    let device = tch::Device::Cpu;
+    let game = XOGame::default();
+    let path = std::env::temp_dir().join("xo_encoding_test.ot");
+    for encoding in XOEncoding::ALL {
+        let vs = nn::VarStore::new(device);
+        let (eval, manifest) = match encoding {
+            XOEncoding::Default => {
+                (<XOModel>::new(&vs).eval_game(&game), Manifest::new::<XOModel, XOGame, 81>(&vs))
+            }
+            XOEncoding::Rich => (
+                XOModel::<XORichFeatures>::new(&vs).eval_game(&game),
+                Manifest::new::<XOModel<XORichFeatures>, XOGame, 81>(&vs),
+            ),
+        };
+        save_checkpoint(&vs, &manifest, &path).expect("Save Failed");
+
+        let (mut loaded, _, _) = <XOEncodedModel>::load(&path, device).expect("Model load failed");
+        assert_eq!(loaded.encoding(), encoding);
+        assert_eq!(loaded.eval_game(&game), eval);
+        assert!(XOEncodedModel::<true>::load(&path, device).is_err());
+    }
+}
+
+#[test]
+#[cfg(feature = "tch")]
+fn test_value_reported_matches_forward() {
+    use sigmazero::game::FeatureEncoder;
+
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let mut agent = <XOModel>::new(&vs);
+    let mut game = XOGame::default();
+    for _ in 0..10 {
+        let valid_move = game.valid_moves()[0];
+        game.take_turn(&valid_move).unwrap();
+    }
+    let (_, reported) = agent.eval_game(&game);
+    let features = DefaultFeatures::encode(&game).unsqueeze(0);
+    let legal_mask = game.legal_mask_tensor().unsqueeze(0);
+    let (_, value) = agent.forward(&features, Some(&legal_mask), false);
+    let value = f32::try_from(value.get(0).get(0)).unwrap();
+    assert!((-1.0..=1.0).contains(&value));
+    assert!((reported - value).abs() < 1e-6);
+}
+
+#[test]
+#[cfg(feature = "tch")]
+fn test_wdl_head() {
+    use policies::XONNAgent;
+
+    let vs = nn::VarStore::new(tch::Device::Cpu);
+    let mut agent = XONNAgent::<DefaultFeatures, true>::new(&vs);
+    let game = XOGame::default();
+    let (_, wdl) = agent.eval_game_wdl(&game).unwrap();
+    assert!((wdl.win + wdl.draw + wdl.loss - 1.0).abs() < 1e-5);
+    let (_, value) = agent.eval_game(&game);
+    assert!((value - (wdl.win - wdl.loss)).abs() < 1e-6);
+
+    let mut scalar_agent = <XOModel>::new(&vs);
+    assert!(scalar_agent.eval_game_wdl(&game).is_none());
+}
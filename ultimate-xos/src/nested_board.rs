@@ -0,0 +1,648 @@
+use colored::Colorize;
+use sigmazero::game::{Position, PositionList};
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::rules::{FullBoardRule, Ruleset, WonBoardRule};
+pub use crate::small_board::XOPlayer;
+use crate::symmetry::XOSymmetry;
+
+const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (1, -1)];
+
+/// A cell of a board of `S`x`S` boards nested `D` levels deep
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct NestedPosition<const S: usize, const D: usize> {
+    x: u8,
+    y: u8,
+}
+
+impl<const S: usize, const D: usize> NestedPosition<S, D> {
+    /// Cells along each side of the whole board
+    pub const SIDE: usize = S.pow(D as u32);
+
+    pub fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        Self::from(symmetry.transform_flat((*self).into(), Self::SIDE))
+    }
+}
+
+impl<const S: usize, const D: usize> Position for NestedPosition<S, D> {
+    fn new(x: u8, y: u8) -> Self {
+        Self { x, y }
+    }
+
+    fn is_valid(&self) -> bool {
+        (self.x as usize) < Self::SIDE && (self.y as usize) < Self::SIDE
+    }
+}
+
+impl<const S: usize, const D: usize> From<usize> for NestedPosition<S, D> {
+    fn from(index: usize) -> Self {
+        Self {
+            x: (index % Self::SIDE) as u8,
+            y: (index / Self::SIDE) as u8,
+        }
+    }
+}
+
+impl<const S: usize, const D: usize> From<NestedPosition<S, D>> for usize {
+    fn from(pos: NestedPosition<S, D>) -> Self {
+        pos.x as usize + NestedPosition::<S, D>::SIDE * pos.y as usize
+    }
+}
+
+impl<const S: usize, const D: usize> fmt::Display for NestedPosition<S, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}]", self.x, self.y)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BoardState {
+    Open,
+    Won(XOPlayer),
+    Drawn,
+}
+
+/// The rules core for Ultimate tic-tac-toe of any size and depth: `S`x`S` boards
+/// nested `D` levels deep, where each board is won with `K` in a row of its won
+/// children. `C` must be `S^(2D)`, the number of cells.
+///
+/// A move is sent by dropping the outermost step of its path: in a depth 2 game
+/// the cell within a small board picks the next small board, in a depth 3 game the
+/// last two steps pick the next smallest board. If that board or one of its
+/// ancestors is decided, the move may go anywhere in the deepest open board on
+/// that path. The `Ruleset` decides whether decided boards are closed or played on
+/// until full, and how the whole board is decided once no moves are left. Under
+/// `FullBoardRule::Draw` it is drawn early once neither player can make a line on it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NestedBoard<const S: usize, const K: usize, const D: usize, const C: usize> {
+    cells: [Option<XOPlayer>; C],
+    /// States of the boards at every level, smallest first. Fewer than `C` are used,
+    /// see `board_index`.
+    boards: [BoardState; C],
+    last_move: Option<NestedPosition<S, D>>,
+    ruleset: Ruleset,
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> NestedBoard<S, K, D, C> {
+    pub const SIDE: usize = S.pow(D as u32);
+    const VALID: () = assert!(
+        C == S.pow(2 * D as u32) && 0 < K && K <= S && D > 0,
+        "NestedBoard needs C == S^(2D) and 0 < K <= S"
+    );
+
+    pub fn with_ruleset(ruleset: Ruleset) -> Self {
+        Self {
+            ruleset,
+            ..Default::default()
+        }
+    }
+
+    /// Boards along each side at `level`, with 0 for cells and `D` for the whole board
+    fn per_side(level: usize) -> usize {
+        S.pow((D - level) as u32)
+    }
+
+    fn board_index(level: usize, bx: usize, by: usize) -> usize {
+        let offset: usize = (1..level).map(|l| Self::per_side(l).pow(2)).sum();
+        offset + bx + Self::per_side(level) * by
+    }
+
+    pub fn get_cell(&self, position: &NestedPosition<S, D>) -> Option<XOPlayer> {
+        self.cells[usize::from(*position)]
+    }
+
+    /// The player after whoever made the last move, X on an empty board
+    pub fn next_player(&self) -> XOPlayer {
+        self.last_move
+            .and_then(|last_move| self.get_cell(&last_move))
+            .map_or(XOPlayer::X, |player| player.other_player())
+    }
+
+    /// State of the board at `(nx, ny)` among those of `level`, where taken cells
+    /// count as won and empty cells as open
+    pub fn state(&self, level: usize, nx: usize, ny: usize) -> BoardState {
+        if level == 0 {
+            match self.cells[nx + Self::SIDE * ny] {
+                Some(player) => BoardState::Won(player),
+                None => BoardState::Open,
+            }
+        } else {
+            self.boards[Self::board_index(level, nx, ny)]
+        }
+    }
+
+    fn child_state(&self, level: usize, bx: usize, by: usize, cx: usize, cy: usize) -> BoardState {
+        self.state(level - 1, bx * S + cx, by * S + cy)
+    }
+
+    pub fn winner(&self) -> Option<XOPlayer> {
+        match self.state(D, 0, 0) {
+            BoardState::Won(player) => Some(player),
+            _ => None,
+        }
+    }
+
+    pub fn is_draw(&self) -> bool {
+        self.state(D, 0, 0) == BoardState::Drawn
+    }
+
+    /// Empty, and not inside a closed board below the whole board
+    fn is_playable(&self, x: usize, y: usize) -> bool {
+        match self.ruleset.won_board {
+            WonBoardRule::FreeMove => (0..D).all(|level| {
+                let span = S.pow(level as u32);
+                self.state(level, x / span, y / span) == BoardState::Open
+            }),
+            WonBoardRule::PlayOn => self.cells[x + Self::SIDE * y].is_none(),
+        }
+    }
+
+    /// Whether no more moves can go in the board at `(bx, by)` of `level`
+    fn is_closed(&self, level: usize, bx: usize, by: usize) -> bool {
+        match self.ruleset.won_board {
+            WonBoardRule::FreeMove => self.state(level, bx, by) != BoardState::Open,
+            WonBoardRule::PlayOn => {
+                let span = S.pow(level as u32);
+                (by * span..(by + 1) * span)
+                    .all(|y| (bx * span..(bx + 1) * span).all(|x| self.cells[x + Self::SIDE * y].is_some()))
+            }
+        }
+    }
+
+    /// The board the next move must be played in, as `(level, bx, by)`
+    fn target_board(&self) -> (usize, usize, usize) {
+        let mut target = (D, 0, 0);
+        if let Some(last_move) = self.last_move {
+            let smallest_per_side = Self::per_side(1);
+            let (tx, ty) = (
+                last_move.x as usize % smallest_per_side,
+                last_move.y as usize % smallest_per_side,
+            );
+            for level in (1..D).rev() {
+                let span = S.pow(level as u32 - 1);
+                let (bx, by) = (tx / span, ty / span);
+                if self.is_closed(level, bx, by) {
+                    break;
+                }
+                target = (level, bx, by);
+            }
+        }
+        target
+    }
+
+    pub fn is_valid_move(&self, position: &NestedPosition<S, D>) -> bool {
+        if !position.is_valid() {
+            return false;
+        }
+        let (level, bx, by) = self.target_board();
+        let span = S.pow(level as u32);
+        let (x, y) = (position.x as usize, position.y as usize);
+        x / span == bx && y / span == by && self.is_playable(x, y)
+    }
+
+    pub fn valid_moves(&self) -> PositionList<NestedPosition<S, D>> {
+        let mut cells = Vec::new();
+        let (level, bx, by) = self.target_board();
+        let span = S.pow(level as u32);
+        for y in by * span..(by + 1) * span {
+            for x in bx * span..(bx + 1) * span {
+                if self.is_playable(x, y) {
+                    cells.push(NestedPosition::new(x as u8, y as u8));
+                }
+            }
+        }
+        PositionList::new(cells)
+    }
+
+    pub fn set_cell(&mut self, position: &NestedPosition<S, D>, player: XOPlayer) {
+        let (mut cx, mut cy) = (position.x as usize, position.y as usize);
+        self.cells[cx + Self::SIDE * cy] = Some(player);
+        self.last_move = Some(*position);
+
+        // Carry the change up for as long as it decides boards
+        let mut child_state = BoardState::Won(player);
+        for level in 1..=D {
+            let (bx, by) = (cx / S, cy / S);
+            // A board belongs to whoever decided it first, even if play goes on there
+            if self.state(level, bx, by) != BoardState::Open {
+                break;
+            }
+            let state = match child_state {
+                BoardState::Won(p) if self.completes_line(level, bx, by, cx % S, cy % S, p) => {
+                    BoardState::Won(p)
+                }
+                _ if level < D && self.all_children_decided(level, bx, by) => BoardState::Drawn,
+                _ => break,
+            };
+            self.boards[Self::board_index(level, bx, by)] = state;
+            (child_state, cx, cy) = (state, bx, by);
+        }
+
+        if self.state(D, 0, 0) == BoardState::Open {
+            self.boards[Self::board_index(D, 0, 0)] = self.adjudicated();
+        }
+    }
+
+    /// The whole board's state when nobody has made a line on it. Once no moves are left
+    /// the `FullBoardRule` decides it. Before that it is drawn early when neither player
+    /// can make a line, except under `MostBoards` where won boards can still decide it.
+    fn adjudicated(&self) -> BoardState {
+        let side = Self::SIDE;
+        if !(0..C).any(|i| self.is_playable(i % side, i / side)) {
+            let won = |player| {
+                (0..S * S)
+                    .filter(|i| self.child_state(D, 0, 0, i % S, i / S) == BoardState::Won(player))
+                    .count()
+            };
+            match self.ruleset.full_board {
+                FullBoardRule::Draw => BoardState::Drawn,
+                FullBoardRule::MostBoards => match won(XOPlayer::X).cmp(&won(XOPlayer::O)) {
+                    Ordering::Greater => BoardState::Won(XOPlayer::X),
+                    Ordering::Less => BoardState::Won(XOPlayer::O),
+                    Ordering::Equal => BoardState::Drawn,
+                },
+            }
+        } else if self.ruleset.full_board == FullBoardRule::Draw
+            && !self.can_complete_line(XOPlayer::X)
+            && !self.can_complete_line(XOPlayer::O)
+        {
+            BoardState::Drawn
+        } else {
+            BoardState::Open
+        }
+    }
+
+    /// Whether the child at `(cx, cy)` of board `(bx, by)` now makes `K` in a row for `player`
+    fn completes_line(&self, level: usize, bx: usize, by: usize, cx: usize, cy: usize, player: XOPlayer) -> bool {
+        let owned = |x: isize, y: isize| {
+            (0..S as isize).contains(&x)
+                && (0..S as isize).contains(&y)
+                && self.child_state(level, bx, by, x as usize, y as usize) == BoardState::Won(player)
+        };
+        DIRECTIONS.iter().any(|&(dx, dy)| {
+            let mut count = 1;
+            for sign in [1, -1] {
+                let (mut x, mut y) = (cx as isize + sign * dx, cy as isize + sign * dy);
+                while owned(x, y) {
+                    count += 1;
+                    (x, y) = (x + sign * dx, y + sign * dy);
+                }
+            }
+            count >= K
+        })
+    }
+
+    fn all_children_decided(&self, level: usize, bx: usize, by: usize) -> bool {
+        (0..S).all(|cy| (0..S).all(|cx| self.child_state(level, bx, by, cx, cy) != BoardState::Open))
+    }
+
+    /// Whether `player` can still make a line on the whole board
+    pub fn can_complete_line(&self, player: XOPlayer) -> bool {
+        // Which boards `player` could still win, working up from the cells
+        let mut winnable = [false; C];
+        for level in 1..=D {
+            let per_side = Self::per_side(level);
+            for by in 0..per_side {
+                for bx in 0..per_side {
+                    winnable[Self::board_index(level, bx, by)] = match self.state(level, bx, by) {
+                        BoardState::Won(owner) => owner == player,
+                        BoardState::Drawn => false,
+                        BoardState::Open => Self::any_line(|cx, cy| {
+                            match self.child_state(level, bx, by, cx, cy) {
+                                BoardState::Won(owner) => owner == player,
+                                BoardState::Drawn => false,
+                                BoardState::Open if level == 1 => true,
+                                BoardState::Open => {
+                                    winnable[Self::board_index(level - 1, bx * S + cx, by * S + cy)]
+                                }
+                            }
+                        }),
+                    };
+                }
+            }
+        }
+        winnable[Self::board_index(D, 0, 0)]
+    }
+
+    /// Whether all `K` cells of some line in an `S`x`S` board satisfy `includes`
+    fn any_line(includes: impl Fn(usize, usize) -> bool) -> bool {
+        let in_range = |v: isize| (0..S as isize).contains(&v);
+        DIRECTIONS.iter().any(|&(dx, dy)| {
+            (0..S as isize).any(|sy| {
+                (0..S as isize).any(|sx| {
+                    let (ex, ey) = (sx + dx * (K as isize - 1), sy + dy * (K as isize - 1));
+                    in_range(ex)
+                        && in_range(ey)
+                        && (0..K as isize).all(|i| includes((sx + dx * i) as usize, (sy + dy * i) as usize))
+                })
+            })
+        })
+    }
+
+    pub fn features_for_player(&self, player: XOPlayer) -> Vec<i64> {
+        // [current player, other player, last move]
+        let mut features = vec![0; 3 * C];
+        for (i, cell) in self.cells.iter().enumerate() {
+            match cell {
+                Some(p) if *p == player => features[i] = 1,
+                Some(_) => features[C + i] = 1,
+                None => (),
+            }
+        }
+        if let Some(last_move) = self.last_move {
+            features[2 * C + usize::from(last_move)] = 1
+        }
+        features
+    }
+
+    pub fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        let mut transformed = Self::default();
+        for (i, cell) in self.cells.iter().enumerate() {
+            transformed.cells[symmetry.transform_flat(i, Self::SIDE)] = *cell;
+        }
+        for level in 1..=D {
+            let per_side = Self::per_side(level);
+            for by in 0..per_side {
+                for bx in 0..per_side {
+                    let t = symmetry.transform_flat(bx + per_side * by, per_side);
+                    transformed.boards[Self::board_index(level, t % per_side, t / per_side)] =
+                        self.boards[Self::board_index(level, bx, by)];
+                }
+            }
+        }
+        transformed.last_move = self.last_move.map(|p| p.transformed(symmetry));
+        transformed.ruleset = self.ruleset;
+        transformed
+    }
+
+    fn symmetry_cmp(&self, other: &Self) -> Ordering {
+        self.cells
+            .cmp(&other.cells)
+            .then_with(|| self.last_move.map(usize::from).cmp(&other.last_move.map(usize::from)))
+    }
+
+    /// The smallest of the 8 symmetric images, and the symmetry that maps this board onto it
+    pub fn canonical(&self) -> (Self, XOSymmetry) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transformed(symmetry), symmetry))
+            .min_by(|(a, _), (b, _)| a.symmetry_cmp(b))
+            .unwrap()
+    }
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> Default for NestedBoard<S, K, D, C> {
+    fn default() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID;
+        Self {
+            cells: [None; C],
+            boards: [BoardState::Open; C],
+            last_move: None,
+            ruleset: Ruleset::default(),
+        }
+    }
+}
+
+/// The separator after column or row `i`, which is heavier between larger boards
+fn separator_level<const S: usize, const D: usize>(i: usize) -> usize {
+    (1..D).rev().find(|&level| (i + 1).is_multiple_of(S.pow(level as u32))).unwrap_or(0)
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> fmt::Display for NestedBoard<S, K, D, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items = (0..C)
+            .map(|i| {
+                let pos = NestedPosition::<S, D>::from(i);
+                let last_move_mark = if self.last_move == Some(pos) { "-" } else { " " };
+                let p = match self.get_cell(&pos) {
+                    Some(XOPlayer::X) => "X".red().to_string(),
+                    Some(XOPlayer::O) => "O".green().to_string(),
+                    None => " ".to_string(),
+                };
+                format!("{last_move_mark}{p}{last_move_mark}")
+            })
+            .collect();
+        write!(f, "{}", NestedBoardDisplayer::<S, D>::new(items))
+    }
+}
+
+pub struct NestedBoardDisplayer<const S: usize, const D: usize> {
+    items: Vec<String>,
+}
+
+impl<const S: usize, const D: usize> NestedBoardDisplayer<S, D> {
+    pub fn new(items: Vec<String>) -> Self {
+        let cells = NestedPosition::<S, D>::SIDE.pow(2);
+        if items.len() != cells {
+            panic!("Displayer expects {} items, got {}", cells, items.len())
+        }
+        Self { items }
+    }
+}
+
+impl<const S: usize, const D: usize> fmt::Display for NestedBoardDisplayer<S, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = NestedPosition::<S, D>::SIDE;
+        for y in 0..side {
+            for x in 0..side {
+                write!(f, "{}", self.items[x + side * y])?;
+                if x < side - 1 {
+                    match separator_level::<S, D>(x) {
+                        0 => write!(f, "|")?,
+                        _ => write!(f, "‖")?,
+                    }
+                }
+            }
+            if y < side - 1 {
+                match separator_level::<S, D>(y) {
+                    0 => write!(f, "\n{}", "-".repeat(4 * side - 1))?,
+                    _ => write!(f, "\n{}", "=".repeat(4 * side - 1))?,
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::XOGame;
+    use rand::rngs::SmallRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use sigmazero::game::{Game, GameStatus};
+
+    type Classic = NestedBoard<3, 3, 2, 81>;
+    type Depth3 = NestedBoard<3, 3, 3, 729>;
+    type Ultimate4 = NestedBoard<4, 3, 2, 256>;
+
+    fn play_random<const S: usize, const K: usize, const D: usize, const C: usize>(
+        rng: &mut SmallRng,
+    ) -> NestedBoard<S, K, D, C> {
+        let mut board = NestedBoard::<S, K, D, C>::default();
+        let mut player = XOPlayer::X;
+        while board.winner().is_none() && !board.is_draw() {
+            let mv = *board.valid_moves().choose(rng).unwrap();
+            assert!(board.is_valid_move(&mv));
+            board.set_cell(&mv, player);
+            player = player.other_player();
+        }
+        board
+    }
+
+    const RULESETS: [Ruleset; 4] = [
+        Ruleset { won_board: WonBoardRule::FreeMove, full_board: FullBoardRule::Draw },
+        Ruleset { won_board: WonBoardRule::FreeMove, full_board: FullBoardRule::MostBoards },
+        Ruleset { won_board: WonBoardRule::PlayOn, full_board: FullBoardRule::Draw },
+        Ruleset { won_board: WonBoardRule::PlayOn, full_board: FullBoardRule::MostBoards },
+    ];
+
+    /// `XOGame` plays on the bitboard `MainBoard`, written apart from the nested rules
+    #[test]
+    fn test_matches_classic_rules() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for i in 0..200 {
+            let ruleset = RULESETS[i % RULESETS.len()];
+            let mut game = XOGame::with_ruleset(ruleset);
+            let mut board = Classic::with_ruleset(ruleset);
+            let mut player = XOPlayer::X;
+            loop {
+                match game.status() {
+                    GameStatus::InProgress { .. } => {
+                        assert!(board.winner().is_none() && !board.is_draw())
+                    }
+                    GameStatus::Won { player } => {
+                        assert_eq!(board.winner(), Some(*player));
+                        break;
+                    }
+                    GameStatus::Draw => {
+                        assert!(board.is_draw());
+                        break;
+                    }
+                }
+                let mut game_moves: Vec<usize> = game.valid_moves().iter().map(|&p| p.into()).collect();
+                let mut board_moves: Vec<usize> = board.valid_moves().iter().map(|&p| p.into()).collect();
+                game_moves.sort();
+                board_moves.sort();
+                assert_eq!(game_moves, board_moves);
+
+                let mv = *game_moves.choose(&mut rng).unwrap();
+                game.take_turn(&mv.into()).unwrap();
+                board.set_cell(&mv.into(), player);
+                player = player.other_player();
+            }
+        }
+    }
+
+    #[test]
+    fn test_depth_3_targeting() {
+        let mut board = Depth3::default();
+        assert_eq!(board.valid_moves().len(), 729);
+        // Cell (1, 1) of the smallest board at (2, 0) of the middle board at (0, 2)
+        let first = NestedPosition::new(6 + 1, 18 + 1);
+        board.set_cell(&first, XOPlayer::X);
+        // Sends O to the smallest board at (1, 1) within the middle board at (2, 0)
+        let valid_moves = board.valid_moves();
+        assert_eq!(valid_moves.len(), 9);
+        for mv in valid_moves.iter() {
+            assert_eq!((mv.x / 3, mv.y / 3), (2 * 3 + 1, 1));
+        }
+    }
+
+    #[test]
+    fn test_depth_3_play_on() {
+        let mut board = Depth3::with_ruleset(Ruleset::new(WonBoardRule::PlayOn, FullBoardRule::Draw));
+        // X wins the smallest board at (0, 0)
+        for x in 0..3 {
+            board.set_cell(&NestedPosition::new(x, 0), XOPlayer::X);
+        }
+        assert_eq!(board.state(1, 0, 0), BoardState::Won(XOPlayer::X));
+        // O is sent back into it and has to play there
+        board.set_cell(&NestedPosition::new(0, 9), XOPlayer::O);
+        let valid_moves = board.valid_moves();
+        assert_eq!(valid_moves.len(), 6);
+        assert!(valid_moves.iter().all(|mv| mv.x < 3 && mv.y < 3));
+
+        // Its owner stays X when O completes a line there
+        for x in 0..3 {
+            board.set_cell(&NestedPosition::new(x, 1), XOPlayer::O);
+        }
+        assert_eq!(board.state(1, 0, 0), BoardState::Won(XOPlayer::X));
+    }
+
+    #[test]
+    fn test_no_early_draws_under_most_boards() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let ruleset = Ruleset::new(WonBoardRule::FreeMove, FullBoardRule::MostBoards);
+        for _ in 0..20 {
+            let mut board = Depth3::with_ruleset(ruleset);
+            let mut player = XOPlayer::X;
+            while board.winner().is_none() && !board.is_draw() {
+                let mv = *board.valid_moves().choose(&mut rng).unwrap();
+                board.set_cell(&mv, player);
+                player = player.other_player();
+            }
+            // Nothing is drawn early, won boards can still decide the game
+            if board.is_draw() {
+                assert!(board.valid_moves().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_4x4_k_in_a_row() {
+        let mut board = Ultimate4::default();
+        for x in 0..3 {
+            board.set_cell(&NestedPosition::new(x, 0), XOPlayer::O);
+        }
+        assert_eq!(board.state(1, 0, 0), BoardState::Won(XOPlayer::O));
+        assert_eq!(board.state(1, 1, 0), BoardState::Open);
+    }
+
+    #[test]
+    fn test_random_games_finish() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        for _ in 0..20 {
+            let depth_3 = play_random::<3, 3, 3, 729>(&mut rng);
+            assert!(depth_3.winner().is_some() || depth_3.is_draw());
+            let ultimate_4 = play_random::<4, 3, 2, 256>(&mut rng);
+            assert!(ultimate_4.winner().is_some() || ultimate_4.is_draw());
+            let ultimate_4_k4 = play_random::<4, 4, 2, 256>(&mut rng);
+            assert!(ultimate_4_k4.winner().is_some() || ultimate_4_k4.is_draw());
+        }
+    }
+
+    #[test]
+    fn test_transforms_consistent() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let mut board = Depth3::default();
+        let mut player = XOPlayer::X;
+        for _ in 0..100 {
+            let mv = *board.valid_moves().choose(&mut rng).unwrap();
+            board.set_cell(&mv, player);
+            player = player.other_player();
+        }
+        for symmetry in XOSymmetry::ALL {
+            let transformed = board.transformed(symmetry);
+            for i in 0..729 {
+                let pos = NestedPosition::from(i);
+                assert_eq!(transformed.get_cell(&pos.transformed(symmetry)), board.get_cell(&pos));
+            }
+            let mut expected: Vec<usize> = board
+                .valid_moves()
+                .iter()
+                .map(|p| p.transformed(symmetry).into())
+                .collect();
+            let mut actual: Vec<usize> = transformed.valid_moves().iter().map(|&p| p.into()).collect();
+            expected.sort();
+            actual.sort();
+            assert_eq!(expected, actual);
+            assert!(transformed.canonical().0 == board.canonical().0);
+        }
+    }
+}
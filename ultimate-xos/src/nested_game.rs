@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::nested_board::{NestedBoard, NestedBoardDisplayer, NestedPosition, XOPlayer};
+use crate::rules::Ruleset;
+use crate::symmetry::XOSymmetry;
+use sigmazero::{
+    game::{Game, GameError, GameStatus, PositionList},
+    policy::RawPolicy,
+};
+
+/// Ultimate tic-tac-toe on 4x4 boards of 4x4 boards, won with `K` in a row
+pub type Ultimate4Game<const K: usize> = NestedGame<4, K, 2, 256>;
+
+/// Ultimate tic-tac-toe of Ultimate tic-tac-toe: 3x3 boards nested three deep
+pub type UltimateDepth3Game = NestedGame<3, 3, 3, 729>;
+
+/// A game on a `NestedBoard`, see there for the rules
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NestedGame<const S: usize, const K: usize, const D: usize, const C: usize> {
+    board: NestedBoard<S, K, D, C>,
+    status: GameStatus<XOPlayer>,
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> Default for NestedGame<S, K, D, C> {
+    fn default() -> Self {
+        Self {
+            board: NestedBoard::default(),
+            status: GameStatus::default(),
+        }
+    }
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> NestedGame<S, K, D, C> {
+    pub fn with_ruleset(ruleset: Ruleset) -> Self {
+        Self {
+            board: NestedBoard::with_ruleset(ruleset),
+            status: GameStatus::default(),
+        }
+    }
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> Game<C> for NestedGame<S, K, D, C> {
+    const FEATURES_SHAPE: &'static [i64] = &[
+        3,
+        NestedBoard::<S, K, D, C>::SIDE as i64,
+        NestedBoard::<S, K, D, C>::SIDE as i64,
+    ];
+    const FEATURES_SIZE: i64 = 3 * C as i64;
+
+    type Player = XOPlayer;
+    type Position = NestedPosition<S, D>;
+    type Symmetry = XOSymmetry;
+
+    fn take_turn(
+        &mut self,
+        position: &Self::Position,
+    ) -> Result<GameStatus<XOPlayer>, GameError<Self::Position>> {
+        let current_player = match self.status {
+            GameStatus::InProgress { player } => player,
+            _ => return Err(GameError::GameOver),
+        };
+
+        if !self.board.is_valid_move(position) {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.board.set_cell(position, current_player);
+
+        self.status = if let Some(winner) = self.board.winner() {
+            GameStatus::Won { player: winner }
+        } else if self.board.is_draw() {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress {
+                player: current_player.other_player(),
+            }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> PositionList<Self::Position> {
+        self.board.valid_moves()
+    }
+
+    fn status(&self) -> &GameStatus<XOPlayer> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        NestedBoardDisplayer::<S, D>::new(items)
+    }
+
+    fn perspective(&self) -> XOPlayer {
+        self.to_move().unwrap_or_else(|| self.board.next_player())
+    }
+
+    fn write_features_for(&self, player: XOPlayer, out: &mut [f32]) {
+        for (out, feature) in out.iter_mut().zip(self.board.features_for_player(player)) {
+            *out = feature as f32;
+        }
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<C>) -> (Vec<Self>, Vec<RawPolicy<C>>) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| {
+                (
+                    Self {
+                        board: self.board.transformed(symmetry),
+                        status: self.status,
+                    },
+                    Self::transform_raw_policy(raw_policy, symmetry),
+                )
+            })
+            .unzip()
+    }
+
+    fn canonical(&self) -> (Self, XOSymmetry) {
+        let (board, symmetry) = self.board.canonical();
+        (Self { board, status: self.status }, symmetry)
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<C>, symmetry: XOSymmetry) -> RawPolicy<C> {
+        let side = NestedBoard::<S, K, D, C>::SIDE;
+        let mut transformed = [0.0f32; C];
+        for (i, p) in raw_policy.iter().enumerate() {
+            transformed[symmetry.transform_flat(i, side)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
+}
+
+impl<const S: usize, const K: usize, const D: usize, const C: usize> fmt::Display for NestedGame<S, K, D, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sigmazero::game::Position;
+
+    #[test]
+    fn test_shapes() {
+        assert_eq!(UltimateDepth3Game::FEATURES_SHAPE, &[3, 27, 27]);
+        assert_eq!(UltimateDepth3Game::MAX_ACTIONS, 729);
+        assert_eq!(Ultimate4Game::<3>::FEATURES_SHAPE, &[3, 16, 16]);
+        assert_eq!(Ultimate4Game::<3>::FEATURES_SIZE, 3 * 256);
+    }
+
+    #[test]
+    fn test_take_turn() {
+        let mut game = Ultimate4Game::<4>::default();
+        game.take_turn(&NestedPosition::new(5, 6)).unwrap();
+        assert!(matches!(
+            game.status(),
+            GameStatus::InProgress { player: XOPlayer::O }
+        ));
+        // Sent to the small board at (1, 2)
+        assert!(matches!(
+            game.take_turn(&NestedPosition::new(0, 0)),
+            Err(GameError::InvalidMove { .. })
+        ));
+        game.take_turn(&NestedPosition::new(4, 8)).unwrap();
+
+        let (aug_games, aug_policies) = game.augmented_with_raw_policy(&RawPolicy::new([1.0; 256]));
+        assert_eq!(aug_games.len(), 8);
+        assert_eq!(aug_policies.len(), 8);
+        assert!(aug_games.iter().all(|g| g.canonical().0 == game.canonical().0));
+    }
+}
@@ -1,91 +1,359 @@
-use std::path::Path;
-use std::sync::PoisonError;
+#[cfg(feature = "tch")]
+use std::marker::PhantomData;
 
+#[cfg(feature = "tch")]
+use crate::architecture::XOArchitecture;
+#[cfg(feature = "tch")]
+use crate::connect_four::ConnectFour;
+#[cfg(feature = "tch")]
 use crate::game::XOGame;
+#[cfg(feature = "tch")]
+use crate::resnet::{ResNetConfig, ResidualBlock};
+#[cfg(feature = "tch")]
+use crate::tictactoe::TicTacToe;
 use rand::prelude::*;
 use sigmazero::game::Game;
-use sigmazero::policy::{self, Agent, NNAgent, RawPolicy};
+#[cfg(feature = "tch")]
+use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+use sigmazero::policy::{Agent, RawPolicy, Wdl};
+#[cfg(feature = "tch")]
+use sigmazero::policy::{masked_softmax, NNAgent, NetworkConfig};
+#[cfg(feature = "tch")]
 use tch::{nn, Tensor};
 
 pub struct RandomAgent<R: Rng> {
     pub rng: R,
 }
 
-impl<R: Rng> Agent<XOGame, 81> for RandomAgent<R> {
-    fn eval_game(&mut self, _: &XOGame) -> (RawPolicy<81>, f32) {
+impl<G: Game<N>, R: Rng, const N: usize> Agent<G, N> for RandomAgent<R> {
+    fn eval_game(&mut self, _: &G) -> (RawPolicy<N>, f32) {
         (
-            RawPolicy::new([1.0; XOGame::MAX_ACTIONS]),
+            RawPolicy::new([1.0; N]),
             (self.rng.gen::<f32>() - 0.5) * 0.2,
         )
     }
 
-    fn eval_features(&mut self, _: &Tensor) -> (RawPolicy<81>, f32) {
+    #[cfg(feature = "tch")]
+    fn eval_features(&mut self, _: &Tensor) -> (RawPolicy<N>, f32) {
         (
-            RawPolicy::new([1.0; XOGame::MAX_ACTIONS]),
+            RawPolicy::new([1.0; N]),
             (self.rng.gen::<f32>() - 0.5) * 0.2,
         )
     }
 }
 
-pub struct XONNAgent {
-    conv1: nn::Conv2D,
+/// Reads its input with the encoder `E`: an input convolution, a tower of residual
+/// blocks shaped by `ResNetConfig`, then separate policy and value heads. With `WDL` the
+/// value head gives win, draw and loss probabilities instead of a single score.
+#[cfg(feature = "tch")]
+pub struct XONNAgent<E = DefaultFeatures, const WDL: bool = false> {
+    input_conv: nn::Conv2D,
+    input_bn: nn::BatchNorm,
+    blocks: Vec<ResidualBlock>,
+    policy_conv: nn::Conv2D,
+    policy_bn: nn::BatchNorm,
+    policy_fc: nn::Linear,
+    value_conv: nn::Conv2D,
+    value_bn: nn::BatchNorm,
+    value_fc1: nn::Linear,
+    value_fc2: nn::Linear,
+    device: tch::Device,
+    encoder: PhantomData<E>,
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> NNAgent<XOGame, 81> for XONNAgent<E, WDL> {
+    type Encoder = E;
+    const WDL_HEAD: bool = WDL;
+
+    fn new(vs: &nn::VarStore) -> Self {
+        Self::with_config(vs, ResNetConfig::from_var_store(vs).unwrap_or_default())
+    }
+
+    fn architecture(_vs: &nn::VarStore) -> String {
+        XOArchitecture::ResNet.name().to_string()
+    }
+
+    fn builds_architecture(architecture: &str) -> bool {
+        architecture == XOArchitecture::ResNet.name()
+    }
+
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, train: bool) -> (Tensor, Tensor) {
+        let mut xs = xs
+            .apply(&self.input_conv)
+            .apply_t(&self.input_bn, train)
+            .relu();
+        for block in &self.blocks {
+            xs = block.forward(&xs, train);
+        }
+
+        let policy_logits = xs
+            .apply(&self.policy_conv)
+            .apply_t(&self.policy_bn, train)
+            .relu()
+            .flatten(1, -1)
+            .apply(&self.policy_fc);
+        let policy = masked_softmax(&policy_logits, legal_mask);
+
+        let value = xs
+            .apply(&self.value_conv)
+            .apply_t(&self.value_bn, train)
+            .relu()
+            .flatten(1, -1)
+            .apply(&self.value_fc1)
+            .relu()
+            .apply(&self.value_fc2);
+        let value = if WDL {
+            value.softmax(-1, tch::Kind::Float)
+        } else {
+            // Bounded like the targets, so training and search read the same number
+            value.tanh()
+        };
+        (policy, value)
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> XONNAgent<E, WDL> {
+    const VALUE_SIZE: i64 = if WDL { 3 } else { 1 };
+
+    /// Builds the network described by `config`, which is recorded in `vs`
+    pub fn with_config(vs: &nn::VarStore, config: ResNetConfig) -> Self {
+        XOArchitecture::ResNet.store(vs);
+        config.store(vs);
+        let root = &vs.root();
+        let filters = config.filters;
+        let conv_config = nn::ConvConfig { padding: 1, bias: false, ..Default::default() };
+        let head_config = nn::ConvConfig { bias: false, ..Default::default() };
+        Self {
+            input_conv: nn::conv2d(root / "input_conv", E::SHAPE[0], filters, 3, conv_config),
+            input_bn: nn::batch_norm2d(root / "input_bn", filters, Default::default()),
+            blocks: (0..config.blocks)
+                .map(|i| ResidualBlock::new(root / "blocks" / i, &config))
+                .collect(),
+            policy_conv: nn::conv2d(root / "policy_conv", filters, 2, 1, head_config),
+            policy_bn: nn::batch_norm2d(root / "policy_bn", 2, Default::default()),
+            policy_fc: nn::linear(root / "policy_fc", 2 * 81, 81, Default::default()),
+            value_conv: nn::conv2d(root / "value_conv", filters, 1, 1, head_config),
+            value_bn: nn::batch_norm2d(root / "value_bn", 1, Default::default()),
+            value_fc1: nn::linear(root / "value_fc1", 81, 64, Default::default()),
+            value_fc2: nn::linear(root / "value_fc2", 64, Self::VALUE_SIZE, Default::default()),
+            device: vs.device(),
+            encoder: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> Agent<XOGame, 81> for XONNAgent<E, WDL> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_game(self, self.device, game);
+        (policy, value_score(&value))
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_single(self, features, None);
+        (policy, value_score(&value))
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        WDL.then(|| {
+            let (policy, value) = eval_xo_game(self, self.device, game);
+            (policy, wdl_from_value(&value))
+        })
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        eval_xo_games(self, self.device, games)
+    }
+}
+
+/// The policy and what the value head says about a single position, for any of the
+/// XO networks
+#[cfg(feature = "tch")]
+pub(crate) fn eval_xo_single<A: NNAgent<XOGame, 81>>(
+    agent: &A,
+    features: &Tensor,
+    legal_mask: Option<&Tensor>,
+) -> (RawPolicy<81>, Vec<f32>) {
+    // Reshape into a singleton batch
+    let legal_mask = legal_mask.map(|mask| mask.unsqueeze(0));
+    let (policy, value) = agent.forward(&features.unsqueeze(0), legal_mask.as_ref(), false);
+    let policy: Vec<f32> = policy.get(0).try_into().expect("Policy conversion from tensor to vec failed!");
+    let value: Vec<f32> = value.get(0).try_into().expect("Value conversion from tensor to vec failed!");
+    assert_eq!(policy.len(), 81);
+    let policy_arr: [f32; 81] = policy.try_into().expect("Policy conversion from vec to array failed!");
+    (RawPolicy::new(policy_arr), value)
+}
+
+/// `eval_xo_single` on a game, encoded the way `A` wants and with its legal moves masked
+#[cfg(feature = "tch")]
+pub(crate) fn eval_xo_game<A: NNAgent<XOGame, 81>>(
+    agent: &A,
+    device: tch::Device,
+    game: &XOGame,
+) -> (RawPolicy<81>, Vec<f32>) {
+    let features = A::Encoder::encode(game).to_device(device);
+    let legal_mask = game.legal_mask_tensor().to_device(device);
+    eval_xo_single(agent, &features, Some(&legal_mask))
+}
+
+/// Scores for all `games` from a single forward pass
+#[cfg(feature = "tch")]
+pub(crate) fn eval_xo_games<A: NNAgent<XOGame, 81>>(
+    agent: &A,
+    device: tch::Device,
+    games: &[XOGame],
+) -> Vec<(RawPolicy<81>, f32)> {
+    let features = A::Encoder::encode_batch(games).to_device(device);
+    let legal_mask = XOGame::legal_masks(games).to_device(device);
+    let (policies, values) = agent.forward(&features, Some(&legal_mask), false);
+    let policies: Vec<Vec<f32>> = policies.try_into().expect("Policy conversion from tensor to vec failed!");
+    let values: Vec<Vec<f32>> = values.try_into().expect("Value conversion from tensor to vec failed!");
+    policies
+        .into_iter()
+        .zip(values)
+        .map(|(policy, value)| {
+            let policy_arr: [f32; 81] = policy.try_into().expect("Policy conversion from vec to array failed!");
+            (RawPolicy::new(policy_arr), value_score(&value))
+        })
+        .collect()
+}
+
+/// A value head's output as one score, where draws count as nothing
+pub(crate) fn value_score(value: &[f32]) -> f32 {
+    match value {
+        [win, _, loss] => win - loss,
+        [score] => *score,
+        _ => panic!("Value head gave {} numbers", value.len()),
+    }
+}
+
+pub(crate) fn wdl_from_value(value: &[f32]) -> Wdl {
+    match value {
+        [win, draw, loss] => Wdl { win: *win, draw: *draw, loss: *loss },
+        _ => panic!("Expected win, draw and loss from the value head, got {} numbers", value.len()),
+    }
+}
+
+/// The policy and value of a single position, for the small networks with one score
+/// from their value head
+#[cfg(feature = "tch")]
+fn eval_single<A: NNAgent<G, N>, G: Game<N>, const N: usize>(
+    agent: &A,
+    features: &Tensor,
+    legal_mask: Option<&Tensor>,
+) -> (RawPolicy<N>, f32) {
+    // Reshape into a singleton batch
+    let legal_mask = legal_mask.map(|mask| mask.unsqueeze(0));
+    let (policy, value) = agent.forward(&features.unsqueeze(0), legal_mask.as_ref(), false);
+    let policy: Vec<f32> = policy.get(0).try_into().expect("Policy conversion from tensor to vec failed!");
+    let value = f32::try_from(value.get(0).get(0)).expect("Value cast into f32 failed!");
+    let policy_arr: [f32; N] = policy.try_into().expect("Policy conversion from vec to array failed!");
+    (RawPolicy::new(policy_arr), value)
+}
+
+#[cfg(feature = "tch")]
+pub struct TicTacToeNNAgent {
     fc1: nn::Linear,
     fc2: nn::Linear,
     fc3: nn::Linear,
     device: tch::Device,
 }
 
-impl NNAgent<XOGame, 81> for XONNAgent {
+#[cfg(feature = "tch")]
+impl NNAgent<TicTacToe, 9> for TicTacToeNNAgent {
+    type Encoder = DefaultFeatures;
+
     fn new(vs: &nn::VarStore) -> Self {
-        const OUT_SIZE: i64 = 9 * 9 + 1;
+        const OUT_SIZE: i64 = 9 + 1;
         let root = &vs.root();
-        let conv1 = nn::conv2d(root, 3, 96, 3, nn::ConvConfig {
-            stride: 3, .. Default::default()
-        });
-        let fc1 = nn::linear(root, 96*9, 512, Default::default());
-        let fc2 = nn::linear(root, 512, 512, Default::default());
-        let fc3 = nn::linear(root, 512, OUT_SIZE, Default::default());
-        Self { conv1, fc1, fc2, fc3, device: vs.device() }
+        let fc1 = nn::linear(root / "fc1", TicTacToe::FEATURES_SIZE, 64, Default::default());
+        let fc2 = nn::linear(root / "fc2", 64, 64, Default::default());
+        let fc3 = nn::linear(root / "fc3", 64, OUT_SIZE, Default::default());
+        Self { fc1, fc2, fc3, device: vs.device() }
     }
 
-    fn forward(&self, xs: &Tensor, train: bool) -> (Tensor, Tensor) {
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, _train: bool) -> (Tensor, Tensor) {
         let xs = xs
-            .apply(&self.conv1)
-            .relu()
             .flat_view()
-            .dropout(0.2, train)
             .apply(&self.fc1)
             .relu()
-            .dropout(0.2, train)
             .apply(&self.fc2)
             .relu()
-            .dropout(0.2, train)
             .apply(&self.fc3);
 
-        let mut ts = xs.split_with_sizes(&[81, 1], -1);
-        let value_logits = ts.pop().unwrap();
-        let policy_logits = ts.pop().unwrap().softmax(-1, tch::Kind::Float);
-        (policy_logits, value_logits)
+        let mut ts = xs.split_with_sizes([9, 1], -1);
+        let value = ts.pop().unwrap().tanh();
+        let policy = masked_softmax(&ts.pop().unwrap(), legal_mask);
+        (policy, value)
     }
 }
 
-impl Agent<XOGame, 81> for XONNAgent {
-    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+#[cfg(feature = "tch")]
+impl Agent<TicTacToe, 9> for TicTacToeNNAgent {
+    fn eval_game(&mut self, game: &TicTacToe) -> (RawPolicy<9>, f32) {
         let features = game.features().to_device(self.device);
-        self.eval_features(&features)
+        let legal_mask = game.legal_mask_tensor().to_device(self.device);
+        eval_single(self, &features, Some(&legal_mask))
     }
 
-    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
-        let (policy_logits, value_logits) = self.forward(&features.unsqueeze(0), false); // Reshape into a singleton batch
-        // println!("policy logits: {}", policy_logits);
-        let policy: Vec<f32> = policy_logits.get(0).try_into().expect("Policy conversion from tensor to vec failed!");
-        let value = f32::try_from(value_logits.softmax(-1, None)).expect("Value cast into f32 failed!");
-        assert_eq!(policy.len(), 81);
-        let policy_arr: [f32; 81] = policy.try_into().expect("Policy conversion from vec to array failed!");
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<9>, f32) {
+        eval_single(self, features, None)
+    }
+}
 
-        // debugging
-        // let policy_arr = [1.0; 81];
+#[cfg(feature = "tch")]
+pub struct ConnectFourNNAgent {
+    conv1: nn::Conv2D,
+    conv2: nn::Conv2D,
+    fc1: nn::Linear,
+    fc2: nn::Linear,
+    device: tch::Device,
+}
+
+#[cfg(feature = "tch")]
+impl NNAgent<ConnectFour, 7> for ConnectFourNNAgent {
+    type Encoder = DefaultFeatures;
+
+    fn new(vs: &nn::VarStore) -> Self {
+        const OUT_SIZE: i64 = 7 + 1;
+        let root = &vs.root();
+        let conv_config = nn::ConvConfig { padding: 1, .. Default::default() };
+        let conv1 = nn::conv2d(root / "conv1", ConnectFour::FEATURES_SHAPE[0], 32, 3, conv_config);
+        let conv2 = nn::conv2d(root / "conv2", 32, 32, 3, conv_config);
+        let fc1 = nn::linear(root / "fc1", 32 * 6 * 7, 128, Default::default());
+        let fc2 = nn::linear(root / "fc2", 128, OUT_SIZE, Default::default());
+        Self { conv1, conv2, fc1, fc2, device: vs.device() }
+    }
+
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, _train: bool) -> (Tensor, Tensor) {
+        let xs = xs
+            .apply(&self.conv1)
+            .relu()
+            .apply(&self.conv2)
+            .relu()
+            .flat_view()
+            .apply(&self.fc1)
+            .relu()
+            .apply(&self.fc2);
+
+        let mut ts = xs.split_with_sizes([7, 1], -1);
+        let value = ts.pop().unwrap().tanh();
+        let policy = masked_softmax(&ts.pop().unwrap(), legal_mask);
+        (policy, value)
+    }
+}
+
+#[cfg(feature = "tch")]
+impl Agent<ConnectFour, 7> for ConnectFourNNAgent {
+    fn eval_game(&mut self, game: &ConnectFour) -> (RawPolicy<7>, f32) {
+        let features = game.features().to_device(self.device);
+        let legal_mask = game.legal_mask_tensor().to_device(self.device);
+        eval_single(self, &features, Some(&legal_mask))
+    }
 
-        (RawPolicy::new(policy_arr), value)
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<7>, f32) {
+        eval_single(self, features, None)
     }
 }
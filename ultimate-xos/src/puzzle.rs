@@ -0,0 +1,176 @@
+use colored::Colorize;
+use std::fmt;
+
+use crate::nested_board::{NestedBoardDisplayer, NestedPosition};
+use sigmazero::{
+    game::{Game, GameError, GameStatus, Player, Position, PositionList},
+    policy::RawPolicy,
+};
+
+const SIDE: usize = 3;
+const CELLS: usize = SIDE * SIDE;
+/// Stones the solver gets to place
+const STONES: u8 = 3;
+/// The centre is blocked, so only the edge lines can be made
+const BLOCKED: [bool; CELLS] = [false, false, false, false, true, false, false, false, false];
+
+type Position3 = NestedPosition<SIDE, 1>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Solver;
+
+impl Player for Solver {
+    const PLAYERS: &'static [Self] = &[Solver];
+}
+
+impl fmt::Display for Solver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", "S".red())
+    }
+}
+
+/// A one-player puzzle: make three in a row on a 3x3 board with a blocked centre, with
+/// only three stones. Solving it is a win, running out of stones a draw.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct LinePuzzle {
+    stones: [bool; CELLS],
+    status: GameStatus<Solver>,
+}
+
+impl LinePuzzle {
+    fn is_solved(&self) -> bool {
+        let taken = |x: usize, y: usize| self.stones[x + SIDE * y];
+        (0..SIDE).any(|i| (0..SIDE).all(|j| taken(i, j)) || (0..SIDE).all(|j| taken(j, i)))
+    }
+}
+
+impl Game<9> for LinePuzzle {
+    const FEATURES_SHAPE: &'static [i64] = &[2, SIDE as i64, SIDE as i64];
+    const FEATURES_SIZE: i64 = 2 * CELLS as i64;
+
+    type Player = Solver;
+    type Position = Position3;
+    type Symmetry = ();
+
+    fn take_turn(&mut self, position: &Position3) -> Result<GameStatus<Solver>, GameError<Position3>> {
+        if self.to_move().is_none() {
+            return Err(GameError::GameOver);
+        }
+
+        let index = usize::from(*position);
+        if !position.is_valid() || self.stones[index] || BLOCKED[index] {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.stones[index] = true;
+
+        let placed = self.stones.iter().filter(|&&stone| stone).count();
+        self.status = if self.is_solved() {
+            GameStatus::Won { player: Solver }
+        } else if placed == STONES as usize {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress { player: Solver }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> PositionList<Position3> {
+        PositionList::new(
+            (0..CELLS)
+                .filter(|&i| !self.stones[i] && !BLOCKED[i])
+                .map(Position3::from)
+                .collect(),
+        )
+    }
+
+    fn status(&self) -> &GameStatus<Solver> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        NestedBoardDisplayer::<SIDE, 1>::new(items)
+    }
+
+    fn write_features_for(&self, _: Solver, out: &mut [f32]) {
+        // [stones, blocked cells]
+        let (stones, blocked) = out.split_at_mut(CELLS);
+        for (out, &stone) in stones.iter_mut().zip(&self.stones) {
+            *out = stone as u8 as f32;
+        }
+        for (out, &blocked) in blocked.iter_mut().zip(&BLOCKED) {
+            *out = blocked as u8 as f32;
+        }
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<9>) -> (Vec<Self>, Vec<RawPolicy<9>>) {
+        (vec![*self], vec![raw_policy.clone()])
+    }
+}
+
+impl fmt::Display for LinePuzzle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items = (0..CELLS)
+            .map(|i| match (self.stones[i], BLOCKED[i]) {
+                (true, _) => format!(" {} ", Solver),
+                (false, true) => " # ".to_string(),
+                (false, false) => "   ".to_string(),
+            })
+            .collect();
+        write!(f, "{}", NestedBoardDisplayer::<SIDE, 1>::new(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::RandomAgent;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use sigmazero::evaluate::evaluate_seats;
+    use sigmazero::mcts::MCTS;
+    use sigmazero::policy::Agent;
+
+    #[test]
+    fn test_single_player_values() {
+        assert_eq!(Solver.zero_sum_values(0.5), vec![0.5]);
+        let mut puzzle = LinePuzzle::default();
+        for x in 0..3 {
+            puzzle.take_turn(&Position3::new(x, 0)).unwrap();
+        }
+        assert!(matches!(puzzle.status(), GameStatus::Won { player: Solver }));
+        assert_eq!(puzzle.outcomes(), vec![1.0]);
+        assert_eq!(puzzle.perspective(), Solver);
+    }
+
+    #[test]
+    fn test_search_keeps_puzzle_solvable() {
+        // Two stones left after a corner: only the cells on its two lines can still solve it
+        let mut puzzle = LinePuzzle::default();
+        puzzle.take_turn(&Position3::new(0, 0)).unwrap();
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let mut mcts = MCTS::<LinePuzzle, _, 9>::from_root_game_state(puzzle, &mut random_agent);
+        for _ in 0..300 {
+            let node_chain = mcts.select();
+            let evaluation = mcts.expand(*node_chain.last().unwrap());
+            mcts.backup(node_chain, evaluation);
+        }
+        let (best_child, _) = mcts.select_best_child();
+        let solvable = [(1, 0), (2, 0), (0, 1), (0, 2)];
+        assert!(solvable
+            .iter()
+            .any(|&(x, y)| best_child.game_state.stones[usize::from(Position3::new(x, y))]));
+    }
+
+    #[test]
+    fn test_evaluate_one_seat() {
+        let mut agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let mut seats: Vec<&mut dyn Agent<LinePuzzle, 9>> = vec![&mut agent];
+        let results = evaluate_seats(&mut seats, 6, 20, 0.0, false);
+        assert_eq!(results.wins.len(), 1);
+        assert_eq!(results.wins[0] + results.draws, 6);
+    }
+}
@@ -0,0 +1,112 @@
+use sigmazero::policy::NetworkConfig;
+#[cfg(feature = "tch")]
+use tch::{nn, Tensor};
+
+/// Shape of an AlphaZero style residual tower. Stored in the `VarStore` next to the
+/// weights, so a checkpoint knows which graph it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResNetConfig {
+    pub blocks: usize,
+    pub filters: i64,
+    pub squeeze_excitation: bool,
+}
+
+impl Default for ResNetConfig {
+    fn default() -> Self {
+        Self {
+            blocks: 6,
+            filters: 64,
+            squeeze_excitation: false,
+        }
+    }
+}
+
+impl NetworkConfig for ResNetConfig {
+    fn to_vec(&self) -> Vec<f32> {
+        vec![self.blocks as f32, self.filters as f32, self.squeeze_excitation as u8 as f32]
+    }
+
+    fn from_slice(values: &[f32]) -> Self {
+        match values {
+            [blocks, filters, squeeze_excitation] => Self {
+                blocks: *blocks as usize,
+                filters: *filters as i64,
+                squeeze_excitation: *squeeze_excitation != 0.0,
+            },
+            _ => panic!("Expected a residual tower config of 3 numbers, got {:?}", values),
+        }
+    }
+}
+
+/// Rescales the channels by weights computed from their global averages
+#[cfg(feature = "tch")]
+#[derive(Debug)]
+struct SqueezeExcitation {
+    fc1: nn::Linear,
+    fc2: nn::Linear,
+}
+
+#[cfg(feature = "tch")]
+impl SqueezeExcitation {
+    fn new(path: nn::Path, filters: i64) -> Self {
+        let squeezed = (filters / 4).max(1);
+        Self {
+            fc1: nn::linear(&path / "fc1", filters, squeezed, Default::default()),
+            fc2: nn::linear(&path / "fc2", squeezed, filters, Default::default()),
+        }
+    }
+
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        let weights = xs
+            .adaptive_avg_pool2d([1, 1])
+            .flatten(1, -1)
+            .apply(&self.fc1)
+            .relu()
+            .apply(&self.fc2)
+            .sigmoid()
+            .unsqueeze(-1)
+            .unsqueeze(-1);
+        xs * weights
+    }
+}
+
+/// Two 3x3 convolutions with batch norm around a skip connection
+#[cfg(feature = "tch")]
+#[derive(Debug)]
+pub struct ResidualBlock {
+    conv1: nn::Conv2D,
+    bn1: nn::BatchNorm,
+    conv2: nn::Conv2D,
+    bn2: nn::BatchNorm,
+    se: Option<SqueezeExcitation>,
+}
+
+#[cfg(feature = "tch")]
+impl ResidualBlock {
+    pub fn new(path: nn::Path, config: &ResNetConfig) -> Self {
+        let filters = config.filters;
+        let conv_config = nn::ConvConfig { padding: 1, bias: false, ..Default::default() };
+        Self {
+            conv1: nn::conv2d(&path / "conv1", filters, filters, 3, conv_config),
+            bn1: nn::batch_norm2d(&path / "bn1", filters, Default::default()),
+            conv2: nn::conv2d(&path / "conv2", filters, filters, 3, conv_config),
+            bn2: nn::batch_norm2d(&path / "bn2", filters, Default::default()),
+            se: config
+                .squeeze_excitation
+                .then(|| SqueezeExcitation::new(&path / "se", filters)),
+        }
+    }
+
+    pub fn forward(&self, xs: &Tensor, train: bool) -> Tensor {
+        let mut ys = xs
+            .apply(&self.conv1)
+            .apply_t(&self.bn1, train)
+            .relu()
+            .apply(&self.conv2)
+            .apply_t(&self.bn2, train);
+        if let Some(se) = &self.se {
+            ys = se.forward(&ys);
+        }
+        (ys + xs).relu()
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// What happens when a player is sent to a small board that has already been won.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum WonBoardRule {
+    /// Won boards are closed: being sent to one gives a free move anywhere.
+    #[default]
+    FreeMove,
+    /// Won boards stay open until full: being sent to one means playing there.
+    PlayOn,
+}
+
+impl WonBoardRule {
+    pub const ALL: [WonBoardRule; 2] = [Self::FreeMove, Self::PlayOn];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::FreeMove => "free-move",
+            Self::PlayOn => "play-on",
+        }
+    }
+}
+
+/// How a game is decided once no moves are left and no meta-board line was made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FullBoardRule {
+    /// The game is a draw.
+    #[default]
+    Draw,
+    /// The player who won more small boards wins, equal counts are a draw.
+    MostBoards,
+}
+
+impl FullBoardRule {
+    pub const ALL: [FullBoardRule; 2] = [Self::Draw, Self::MostBoards];
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Draw => "draw",
+            Self::MostBoards => "most-boards",
+        }
+    }
+}
+
+/// Written and parsed as the two rule names joined by a comma, e.g. `play-on,most-boards`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Ruleset {
+    pub won_board: WonBoardRule,
+    pub full_board: FullBoardRule,
+}
+
+impl Ruleset {
+    pub fn new(won_board: WonBoardRule, full_board: FullBoardRule) -> Self {
+        Self {
+            won_board,
+            full_board,
+        }
+    }
+}
+
+impl fmt::Display for Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.won_board.name(), self.full_board.name())
+    }
+}
+
+impl FromStr for Ruleset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (won_board, full_board) = s
+            .split_once(',')
+            .ok_or_else(|| format!("Expected a ruleset like \"free-move,draw\", got {:?}", s))?;
+        let won_board = WonBoardRule::ALL
+            .into_iter()
+            .find(|rule| rule.name() == won_board)
+            .ok_or_else(|| format!("Unknown won board rule {:?}, expected one of {:?}", won_board, WonBoardRule::ALL.map(|r| r.name())))?;
+        let full_board = FullBoardRule::ALL
+            .into_iter()
+            .find(|rule| rule.name() == full_board)
+            .ok_or_else(|| format!("Unknown full board rule {:?}, expected one of {:?}", full_board, FullBoardRule::ALL.map(|r| r.name())))?;
+        Ok(Self::new(won_board, full_board))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset_round_trips_through_its_name() {
+        for won_board in WonBoardRule::ALL {
+            for full_board in FullBoardRule::ALL {
+                let ruleset = Ruleset::new(won_board, full_board);
+                assert_eq!(ruleset.to_string().parse::<Ruleset>(), Ok(ruleset));
+            }
+        }
+        assert_eq!("free-move,draw".parse::<Ruleset>(), Ok(Ruleset::default()));
+        assert!("play-on".parse::<Ruleset>().is_err());
+        assert!("play-on,sudden-death".parse::<Ruleset>().is_err());
+    }
+}
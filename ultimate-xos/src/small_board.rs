@@ -1,14 +1,15 @@
-use sigmazero::game::Player;
+use crate::symmetry::XOSymmetry;
+use sigmazero::game::{Player, Position};
 use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct Board {
     bitboards: [u16; 2], // X: player 0, O: player 1, and last move
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
 pub enum XOPlayer {
     #[default]
     X = 0,
@@ -16,7 +17,7 @@ pub enum XOPlayer {
 }
 
 impl XOPlayer {
-    const PLAYERS: [Self; 2] = [XOPlayer::X, XOPlayer::O];
+    pub const PLAYERS: [Self; 2] = [XOPlayer::X, XOPlayer::O];
 
     pub fn other_player(&self) -> XOPlayer {
         match self {
@@ -27,13 +28,14 @@ impl XOPlayer {
 }
 
 impl Player for XOPlayer {
-    const PLAYERS: [Self; 2] = [XOPlayer::X, XOPlayer::O];
+    const PLAYERS: &'static [Self] = &XOPlayer::PLAYERS;
 
-    fn other_player(&self) -> XOPlayer {
-        match self {
-            XOPlayer::X => XOPlayer::O,
-            XOPlayer::O => XOPlayer::X,
-        }
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    fn next_player(&self) -> XOPlayer {
+        self.other_player()
     }
 }
 
@@ -50,19 +52,19 @@ impl fmt::Display for XOPlayer {
     }
 }
 
-const WINNING: [u16; 8] = [73, 73 << 1, 73 << 2, 7, 7 << 3, 7 << 6, 273, 84];
+pub const WINNING: [u16; 8] = [73, 73 << 1, 73 << 2, 7, 7 << 3, 7 << 6, 273, 84];
 
 impl Board {
     pub fn set_cell(&mut self, position: &Position3, player: XOPlayer) {
         // Need to update next move
-        let mask = (1 as u16) << (position.y * 3 + position.x);
+        let mask = 1u16 << (position.y * 3 + position.x);
         self.bitboards[player as usize] |= mask;
         self.bitboards[player.other_player() as usize] &= !mask;
     }
 
     pub fn get_cell(&self, position: &Position3) -> Option<XOPlayer> {
         let offset = position.y * 3 + position.x;
-        let mask = (1 as u16) << offset;
+        let mask = 1u16 << offset;
         let is_player_x = mask & self.bitboards[XOPlayer::X as usize] != 0;
         let is_player_o = mask & self.bitboards[XOPlayer::O as usize] != 0;
         // println!("[{}, {}], board X: {:#018b}, mask: {:#018b}", bits[0], bits[1], self.bitboards[1], mask);
@@ -88,7 +90,26 @@ impl Board {
         None
     }
 
-    fn count_player(&self, player: XOPlayer) -> u32 {
+    /// Whether `player` can still complete a line on an undecided board
+    pub fn can_still_win(&self, player: XOPlayer) -> bool {
+        if self.winner().is_some() {
+            return false;
+        }
+        let opponent_bits = self.bitboards[player.other_player() as usize];
+        WINNING.iter().any(|win_case| opponent_bits & win_case == 0)
+    }
+
+    pub fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        Self {
+            bitboards: self.bitboards.map(|bits| symmetry.permute_bits(bits)),
+        }
+    }
+
+    pub fn bitboards(&self) -> [u16; 2] {
+        self.bitboards
+    }
+
+    pub fn count_player(&self, player: XOPlayer) -> u32 {
         (self.bitboards[player as usize] << 7).count_ones()
     }
 
@@ -98,23 +119,22 @@ impl Board {
     }
 
     pub fn valid_moves(&self) -> Vec<Position3> {
-        let valid_bits = !(self.bitboards[0] | self.bitboards[1]);
-        let mut valid_moves: Vec<Position3> = Vec::new();
         if self.winner().is_some() {
-            return valid_moves;
+            return Vec::new();
         }
+        self.empty_cells()
+    }
+
+    /// Empty cells, whether or not the board has already been won
+    pub fn empty_cells(&self) -> Vec<Position3> {
+        let empty_bits = !(self.bitboards[0] | self.bitboards[1]);
+        let mut empty_cells: Vec<Position3> = Vec::new();
         for i in 0..9 {
-            if 1 & (valid_bits >> i) == 1 {
-                valid_moves.push(Position3::new(i % 3, i / 3))
+            if 1 & (empty_bits >> i) == 1 {
+                empty_cells.push(Position3::new(i % 3, i / 3))
             }
         }
-        valid_moves
-    }
-}
-
-impl Default for Board {
-    fn default() -> Self {
-        Self { bitboards: [0, 0] }
+        empty_cells
     }
 }
 
@@ -137,7 +157,7 @@ impl fmt::Display for Board {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Position3 {
     pub x: u8,
     pub y: u8,
@@ -145,7 +165,7 @@ pub struct Position3 {
 
 impl Position3 {
     pub fn new(x: u8, y: u8) -> Self {
-        Self { x: x, y: y }
+        Self { x, y }
     }
 
     pub fn from_vec(vec: Vec<u32>) -> Result<Self, String> {
@@ -177,6 +197,35 @@ impl Position3 {
     }
 }
 
+impl Position for Position3 {
+    fn new(x: u8, y: u8) -> Self {
+        Self { x, y }
+    }
+
+    fn is_valid(&self) -> bool {
+        Position3::is_valid(self)
+    }
+}
+
+impl From<usize> for Position3 {
+    fn from(index: usize) -> Self {
+        Self::from_flat(index as u8)
+    }
+}
+
+impl From<Position3> for usize {
+    fn from(pos: Position3) -> Self {
+        pos.flat() as usize
+    }
+}
+
+impl fmt::Display for Position3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}]", self.x, self.y)?;
+        Ok(())
+    }
+}
+
 impl FromStr for Position3 {
     type Err = String;
 
@@ -284,6 +333,32 @@ mod tests {
         assert_eq!(b.valid_moves().len(), 3);
     }
 
+    #[test]
+    fn test_empty_cells_after_win() {
+        let mut b = Board::default();
+        b.set_cell(&Position3::new(0, 0), XOPlayer::X);
+        b.set_cell(&Position3::new(1, 0), XOPlayer::X);
+        b.set_cell(&Position3::new(2, 0), XOPlayer::X);
+        assert!(b.valid_moves().is_empty());
+        assert_eq!(b.empty_cells().len(), 6);
+    }
+
+    #[test]
+    fn test_can_still_win() {
+        let mut b = Board::default();
+        assert!(b.can_still_win(XOPlayer::X));
+        b.set_cell(&Position3::new(1, 1), XOPlayer::O);
+        b.set_cell(&Position3::new(0, 0), XOPlayer::O);
+        b.set_cell(&Position3::new(0, 2), XOPlayer::X);
+        b.set_cell(&Position3::new(1, 0), XOPlayer::X);
+        b.set_cell(&Position3::new(1, 2), XOPlayer::O);
+        // Only the right column is left for X
+        assert!(b.can_still_win(XOPlayer::X));
+        b.set_cell(&Position3::new(2, 1), XOPlayer::O);
+        assert!(!b.can_still_win(XOPlayer::X));
+        assert!(b.can_still_win(XOPlayer::O));
+    }
+
     #[test]
     fn test_position_parse() {
         let _: Position3 = "1,2".parse().unwrap();
@@ -0,0 +1,239 @@
+use std::fmt;
+
+use crate::board::{BoardDisplayer, MainBoard, XOPlayer, XOPosition, XOPositionList, XOSymmetry};
+use crate::small_board::Position3;
+use sigmazero::{
+    game::{Game, GameError, GameStatus},
+    policy::RawPolicy,
+};
+
+/// Ultimate tic-tac-toe where the small board to play in is drawn at random from the
+/// open ones before every move, instead of following the last move
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct StochasticXOGame {
+    board: MainBoard,
+    status: GameStatus<XOPlayer>,
+    /// None while waiting for chance to pick the board
+    target: Option<Position3>,
+}
+
+impl StochasticXOGame {
+    pub fn target(&self) -> Option<Position3> {
+        self.target
+    }
+
+    fn open_boards(&self) -> Vec<Position3> {
+        (0..9)
+            .map(Position3::from)
+            .filter(|large_pos| !self.board.open_cells(large_pos).is_empty())
+            .collect()
+    }
+
+    fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        Self {
+            board: self.board.transformed(symmetry),
+            status: self.status,
+            target: self
+                .target
+                .map(|target| Position3::from(symmetry.permute_small_cell(target.into()))),
+        }
+    }
+}
+
+impl Game<81> for StochasticXOGame {
+    const FEATURES_SHAPE: &'static [i64] = &[3, 9, 9];
+    const FEATURES_SIZE: i64 = 3 * 9 * 9;
+
+    type Player = XOPlayer;
+    type Position = XOPosition;
+    type Symmetry = XOSymmetry;
+
+    fn take_turn(
+        &mut self,
+        position: &XOPosition,
+    ) -> Result<GameStatus<XOPlayer>, GameError<XOPosition>> {
+        let current_player = match self.status {
+            GameStatus::InProgress { player } => player,
+            _ => return Err(GameError::GameOver),
+        };
+
+        if !self.valid_moves().contains(position) {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.board.set_cell(position, current_player);
+        self.target = None;
+
+        self.status = if let Some(winner) = self.board.winner() {
+            GameStatus::Won { player: winner }
+        } else if self.board.is_draw() {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress {
+                player: current_player.other_player(),
+            }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> XOPositionList {
+        let cells = match self.target {
+            Some(target) => self
+                .board
+                .open_cells(&target)
+                .into_iter()
+                .map(|small_pos| XOPosition::from_subpos(target, small_pos))
+                .collect(),
+            None => Vec::new(),
+        };
+        XOPositionList::new(cells)
+    }
+
+    fn status(&self) -> &GameStatus<XOPlayer> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        BoardDisplayer::new(items)
+    }
+
+    fn perspective(&self) -> XOPlayer {
+        self.to_move().unwrap_or_else(|| self.board.next_player())
+    }
+
+    fn write_features_for(&self, player: XOPlayer, out: &mut [f32]) {
+        // [player, other player, cells of the target board]
+        let mut features = self.board.features_for_player(player);
+        features[2] = [[0; 9]; 9];
+        for position in self.valid_moves().iter() {
+            let index = usize::from(*position);
+            features[2][index / 9][index % 9] = 1;
+        }
+        for (out, feature) in out.iter_mut().zip(features.as_flattened().as_flattened()) {
+            *out = *feature as f32;
+        }
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<81>) -> (Vec<Self>, Vec<RawPolicy<81>>) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transformed(symmetry), Self::transform_raw_policy(raw_policy, symmetry)))
+            .unzip()
+    }
+
+    fn canonical(&self) -> (Self, XOSymmetry) {
+        let (_, symmetry) = self.board.canonical();
+        (self.transformed(symmetry), symmetry)
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<81>, symmetry: XOSymmetry) -> RawPolicy<81> {
+        let mut transformed = [0.0f32; 81];
+        for (i, p) in raw_policy.iter().enumerate() {
+            transformed[symmetry.permute_cell(i)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
+
+    fn chance_outcomes(&self) -> Option<Vec<(Self, f32)>> {
+        if self.target.is_some() || !matches!(self.status, GameStatus::InProgress { .. }) {
+            return None;
+        }
+        let open_boards = self.open_boards();
+        let probability = 1.0 / open_boards.len() as f32;
+        Some(
+            open_boards
+                .into_iter()
+                .map(|target| (Self { target: Some(target), ..*self }, probability))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for StochasticXOGame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.board)?;
+        match self.target() {
+            Some(target) => write!(f, "\nTarget board: {}", target),
+            None => write!(f, "\nTarget board: to be drawn"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::RandomAgent;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use sigmazero::evaluate::evaluate_agents;
+    use sigmazero::game::Position;
+    use sigmazero::mcts::{resolve_chance, self_play, MCTS};
+
+    #[test]
+    fn test_chance_outcomes() {
+        let game = StochasticXOGame::default();
+        assert!(game.valid_moves().is_empty());
+        let outcomes = game.chance_outcomes().unwrap();
+        assert_eq!(outcomes.len(), 9);
+        assert!((outcomes.iter().map(|(_, p)| p).sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // Moves are confined to the drawn board, and the next board is drawn afterwards
+        let (mut game, _) = outcomes[4];
+        assert!(game.chance_outcomes().is_none());
+        assert!(game.valid_moves().iter().all(|p| p.large_pos() == Position3::new(1, 1)));
+        assert!(matches!(
+            game.take_turn(&XOPosition::new(0, 0)),
+            Err(GameError::InvalidMove { .. })
+        ));
+        game.take_turn(&XOPosition::new(4, 4)).unwrap();
+        assert!(game.target().is_none());
+        assert_eq!(game.chance_outcomes().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn test_full_boards_not_drawn() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut game = StochasticXOGame::default();
+        // Close the centre board, X takes it with the bottom row
+        for (x, y) in [(3, 3), (4, 4), (5, 5), (4, 3), (5, 4), (3, 4), (3, 5), (5, 3), (4, 5)] {
+            game.target = Some(Position3::new(1, 1));
+            game.take_turn(&XOPosition::new(x, y)).unwrap();
+        }
+        let outcomes = game.chance_outcomes().unwrap();
+        assert_eq!(outcomes.len(), 8);
+        for _ in 0..20 {
+            assert_ne!(resolve_chance(&game, &mut rng).target(), Some(Position3::new(1, 1)));
+        }
+    }
+
+    #[test]
+    fn test_search_through_chance_nodes() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let root = resolve_chance(&StochasticXOGame::default(), &mut rng);
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(1) };
+        let mut mcts = MCTS::<StochasticXOGame, _, 81>::from_root_game_state(root, &mut random_agent);
+        for _ in 0..400 {
+            let node_chain = mcts.select();
+            let evaluation = mcts.expand(*node_chain.last().unwrap());
+            mcts.backup(node_chain, evaluation);
+        }
+        let (best_child, policy) = mcts.select_best_child();
+        assert!(best_child.game_state.target().is_none());
+        assert!((policy.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_self_play_and_evaluate() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<StochasticXOGame, _, 81>(&mut random_agent, 2, 20, 0.0, false);
+        // Only decisions are kept for training
+        assert!(replay.games.iter().all(|game| game.target().is_some()));
+
+        let mut other_agent = RandomAgent { rng: SmallRng::seed_from_u64(1) };
+        let results = evaluate_agents::<StochasticXOGame, 81, _, _>(&mut random_agent, &mut other_agent, 2, 20, 0.0, false);
+        assert_eq!(results.agent1_wins + results.agent2_wins + results.draws, 2);
+    }
+}
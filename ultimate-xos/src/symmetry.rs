@@ -0,0 +1,180 @@
+use sigmazero::game::Symmetry;
+
+/// A reflection in the vertical axis (if `reflected`) followed by `rotations`
+/// clockwise quarter turns
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct XOSymmetry {
+    reflected: bool,
+    rotations: u8,
+}
+
+impl XOSymmetry {
+    /// In the same order as `MainBoard::augmented`
+    pub const ALL: [Self; 8] = [
+        Self { reflected: false, rotations: 0 },
+        Self { reflected: false, rotations: 1 },
+        Self { reflected: false, rotations: 2 },
+        Self { reflected: false, rotations: 3 },
+        Self { reflected: true, rotations: 0 },
+        Self { reflected: true, rotations: 1 },
+        Self { reflected: true, rotations: 2 },
+        Self { reflected: true, rotations: 3 },
+    ];
+
+    /// Position in `ALL`
+    const fn index(&self) -> usize {
+        4 * self.reflected as usize + self.rotations as usize
+    }
+
+    /// Where the cell at flat index `x + size * y` of a `size`x`size` grid ends up
+    pub const fn transform_flat(&self, flat: usize, size: usize) -> usize {
+        let (mut x, mut y) = (flat % size, flat / size);
+        if self.reflected {
+            x = size - 1 - x;
+        }
+        let mut r = 0;
+        while r < self.rotations {
+            (x, y) = (size - 1 - y, x);
+            r += 1;
+        }
+        x + size * y
+    }
+
+    /// Permutes a flat index into the 9x9 board
+    pub fn permute_cell(&self, index: usize) -> usize {
+        CELL_PERMUTATIONS[self.index()][index] as usize
+    }
+
+    /// Permutes a flat index into a 3x3 board
+    pub fn permute_small_cell(&self, index: usize) -> usize {
+        SMALL_CELL_PERMUTATIONS[self.index()][index] as usize
+    }
+
+    /// Permutes the bits of a 3x3 bitboard
+    pub fn permute_bits(&self, bits: u16) -> u16 {
+        BIT_PERMUTATIONS[self.index()][(bits & 0x1ff) as usize]
+    }
+}
+
+impl Symmetry for XOSymmetry {
+    const IDENTITY: Self = Self { reflected: false, rotations: 0 };
+
+    fn inverse(&self) -> Self {
+        if self.reflected {
+            // Reflections are their own inverse
+            *self
+        } else {
+            Self { reflected: false, rotations: (4 - self.rotations) % 4 }
+        }
+    }
+}
+
+const fn cell_permutations<const C: usize>(size: usize) -> [[u8; C]; 8] {
+    let mut tables = [[0; C]; 8];
+    let mut s = 0;
+    while s < 8 {
+        let mut i = 0;
+        while i < C {
+            tables[s][i] = XOSymmetry::ALL[s].transform_flat(i, size) as u8;
+            i += 1;
+        }
+        s += 1;
+    }
+    tables
+}
+
+const fn bit_permutations() -> [[u16; 512]; 8] {
+    let mut tables = [[0; 512]; 8];
+    let mut s = 0;
+    while s < 8 {
+        let mut bits = 0;
+        while bits < 512 {
+            let mut i = 0;
+            while i < 9 {
+                if (bits >> i) & 1 == 1 {
+                    tables[s][bits] |= 1 << SMALL_CELL_PERMUTATIONS[s][i];
+                }
+                i += 1;
+            }
+            bits += 1;
+        }
+        s += 1;
+    }
+    tables
+}
+
+static CELL_PERMUTATIONS: [[u8; 81]; 8] = cell_permutations(9);
+const SMALL_CELL_PERMUTATIONS: [[u8; 9]; 8] = cell_permutations(3);
+static BIT_PERMUTATIONS: [[u16; 512]; 8] = bit_permutations();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transforms by composing single reflections and quarter turns
+    fn transform_slowly(x: usize, y: usize, size: usize, symmetry: XOSymmetry) -> (usize, usize) {
+        let (mut x, mut y) = (x, y);
+        if symmetry.reflected {
+            x = size - 1 - x;
+        }
+        for _ in 0..symmetry.rotations {
+            (x, y) = (size - 1 - y, x);
+        }
+        (x, y)
+    }
+
+    #[test]
+    fn test_cell_permutations() {
+        for symmetry in XOSymmetry::ALL {
+            let mut seen = [false; 81];
+            for y in 0..9 {
+                for x in 0..9 {
+                    let (tx, ty) = transform_slowly(x, y, 9, symmetry);
+                    let permuted = symmetry.permute_cell(x + 9 * y);
+                    assert_eq!(permuted, tx + 9 * ty);
+                    seen[permuted] = true;
+                }
+            }
+            assert!(seen.iter().all(|&s| s), "{symmetry:?} is not a permutation");
+        }
+    }
+
+    #[test]
+    fn test_cell_permutation_splits_into_small_boards() {
+        // The same 3x3 symmetry acts on which small board and on the cell within it
+        for symmetry in XOSymmetry::ALL {
+            for large in 0..9 {
+                for small in 0..9 {
+                    let (lx, ly) = (large % 3, large / 3);
+                    let (sx, sy) = (small % 3, small / 3);
+                    let cell = (3 * lx + sx) + 9 * (3 * ly + sy);
+                    let large_t = symmetry.permute_small_cell(large);
+                    let small_t = symmetry.permute_small_cell(small);
+                    let expected =
+                        (3 * (large_t % 3) + small_t % 3) + 9 * (3 * (large_t / 3) + small_t / 3);
+                    assert_eq!(symmetry.permute_cell(cell), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bit_permutations() {
+        for symmetry in XOSymmetry::ALL {
+            for i in 0..9 {
+                assert_eq!(symmetry.permute_bits(1 << i), 1 << symmetry.permute_small_cell(i));
+            }
+            assert_eq!(symmetry.permute_bits(0x1ff), 0x1ff);
+            assert_eq!(symmetry.inverse().permute_bits(symmetry.permute_bits(0b101_100_011)), 0b101_100_011);
+        }
+    }
+
+    #[test]
+    fn test_inverse() {
+        for symmetry in XOSymmetry::ALL {
+            for i in 0..81 {
+                assert_eq!(symmetry.inverse().permute_cell(symmetry.permute_cell(i)), i);
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use colored::Colorize;
+use std::fmt;
+
+use crate::nested_board::{NestedBoardDisplayer, NestedPosition};
+use crate::symmetry::XOSymmetry;
+use sigmazero::{
+    game::{Game, GameError, GameStatus, Player, Position, PositionList},
+    policy::RawPolicy,
+};
+
+const SIDE: usize = 4;
+const CELLS: usize = SIDE * SIDE;
+const IN_A_ROW: usize = 3;
+
+type Position4 = NestedPosition<SIDE, 1>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TriPlayer {
+    #[default]
+    A,
+    B,
+    C,
+}
+
+impl Player for TriPlayer {
+    const PLAYERS: &'static [Self] = &[TriPlayer::A, TriPlayer::B, TriPlayer::C];
+}
+
+impl fmt::Display for TriPlayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriPlayer::A => write!(f, "{}", "A".red()),
+            TriPlayer::B => write!(f, "{}", "B".green()),
+            TriPlayer::C => write!(f, "{}", "C".blue()),
+        }
+    }
+}
+
+/// Three players take turns on a 4x4 board, the first to get three in a row wins
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ThreePlayerGame {
+    cells: [Option<TriPlayer>; CELLS],
+    status: GameStatus<TriPlayer>,
+}
+
+impl ThreePlayerGame {
+    fn completes_line(&self, x: usize, y: usize, player: TriPlayer) -> bool {
+        let owned = |x: isize, y: isize| {
+            (0..SIDE as isize).contains(&x)
+                && (0..SIDE as isize).contains(&y)
+                && self.cells[x as usize + SIDE * y as usize] == Some(player)
+        };
+        [(1, 0), (0, 1), (1, 1), (1, -1)].iter().any(|&(dx, dy)| {
+            // Count along the line in both directions from the new stone
+            let count_from = |sign: isize| {
+                (1..IN_A_ROW as isize)
+                    .take_while(|&i| owned(x as isize + sign * i * dx, y as isize + sign * i * dy))
+                    .count()
+            };
+            1 + count_from(1) + count_from(-1) >= IN_A_ROW
+        })
+    }
+
+    fn transformed(&self, symmetry: XOSymmetry) -> Self {
+        let mut cells = [None; CELLS];
+        for (i, cell) in self.cells.iter().enumerate() {
+            cells[symmetry.transform_flat(i, SIDE)] = *cell;
+        }
+        Self { cells, status: self.status }
+    }
+}
+
+impl Game<16> for ThreePlayerGame {
+    const FEATURES_SHAPE: &'static [i64] = &[3, SIDE as i64, SIDE as i64];
+    const FEATURES_SIZE: i64 = 3 * CELLS as i64;
+
+    type Player = TriPlayer;
+    type Position = Position4;
+    type Symmetry = XOSymmetry;
+
+    fn take_turn(
+        &mut self,
+        position: &Position4,
+    ) -> Result<GameStatus<TriPlayer>, GameError<Position4>> {
+        let current_player = match self.status {
+            GameStatus::InProgress { player } => player,
+            _ => return Err(GameError::GameOver),
+        };
+
+        let index = usize::from(*position);
+        if !position.is_valid() || self.cells[index].is_some() {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.cells[index] = Some(current_player);
+
+        self.status = if self.completes_line(index % SIDE, index / SIDE, current_player) {
+            GameStatus::Won { player: current_player }
+        } else if self.cells.iter().all(|cell| cell.is_some()) {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress {
+                player: current_player.next_player(),
+            }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> PositionList<Position4> {
+        PositionList::new(
+            (0..CELLS)
+                .filter(|&i| self.cells[i].is_none())
+                .map(Position4::from)
+                .collect(),
+        )
+    }
+
+    fn status(&self) -> &GameStatus<TriPlayer> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        NestedBoardDisplayer::<SIDE, 1>::new(items)
+    }
+
+    /// Turns go round with every stone, which a draw on the full board doesn't follow
+    fn perspective(&self) -> TriPlayer {
+        let stones = self.cells.iter().filter(|cell| cell.is_some()).count();
+        TriPlayer::PLAYERS[stones % TriPlayer::PLAYERS.len()]
+    }
+
+    fn write_features_for(&self, perspective: TriPlayer, out: &mut [f32]) {
+        // [perspective player, next player, the one after]
+        out.fill(0.0);
+        for (i, cell) in self.cells.iter().enumerate() {
+            if let Some(player) = cell {
+                let plane = (player.index() + 3 - perspective.index()) % 3;
+                out[plane * CELLS + i] = 1.0;
+            }
+        }
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<16>) -> (Vec<Self>, Vec<RawPolicy<16>>) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transformed(symmetry), Self::transform_raw_policy(raw_policy, symmetry)))
+            .unzip()
+    }
+
+    fn canonical(&self) -> (Self, XOSymmetry) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| (self.transformed(symmetry), symmetry))
+            .min_by_key(|(game, _)| game.cells.map(|cell| cell.map(|p| p.index())))
+            .unwrap()
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<16>, symmetry: XOSymmetry) -> RawPolicy<16> {
+        let mut transformed = [0.0f32; CELLS];
+        for (i, p) in raw_policy.iter().enumerate() {
+            transformed[symmetry.transform_flat(i, SIDE)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
+}
+
+impl fmt::Display for ThreePlayerGame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items = self
+            .cells
+            .iter()
+            .map(|cell| match cell {
+                Some(player) => format!(" {} ", player),
+                None => "   ".to_string(),
+            })
+            .collect();
+        write!(f, "{}", NestedBoardDisplayer::<SIDE, 1>::new(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::RandomAgent;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use sigmazero::evaluate::evaluate_seats;
+    use sigmazero::mcts::MCTS;
+    use sigmazero::policy::Agent;
+
+    fn play(moves: &[(u8, u8)]) -> ThreePlayerGame {
+        let mut game = ThreePlayerGame::default();
+        for &(x, y) in moves {
+            game.take_turn(&Position4::new(x, y)).unwrap();
+        }
+        game
+    }
+
+    #[test]
+    fn test_turn_order_and_win() {
+        let mut game = play(&[(0, 0), (1, 1), (3, 3), (1, 0), (2, 1), (0, 3)]);
+        assert!(matches!(game.status(), GameStatus::InProgress { player: TriPlayer::A }));
+        let status = game.take_turn(&Position4::new(3, 0)).unwrap();
+        assert!(matches!(status, GameStatus::InProgress { player: TriPlayer::B }));
+        let status = game.take_turn(&Position4::new(3, 1)).unwrap();
+        assert!(matches!(status, GameStatus::Won { player: TriPlayer::B }));
+        assert_eq!(game.outcomes(), vec![-1.0, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn test_search_takes_winning_move() {
+        // A to move and can finish the top row, while B threatens the second one
+        let game = play(&[(0, 0), (0, 1), (3, 3), (1, 0), (1, 1), (3, 2)]);
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let mut mcts = MCTS::<ThreePlayerGame, _, 16>::from_root_game_state(game, &mut random_agent);
+        for _ in 0..300 {
+            let node_chain = mcts.select();
+            let evaluation = mcts.expand(*node_chain.last().unwrap());
+            mcts.backup(node_chain, evaluation);
+        }
+        let (best_child, _) = mcts.select_best_child();
+        assert!(matches!(
+            best_child.game_state.status(),
+            GameStatus::Won { player: TriPlayer::A }
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_three_seats() {
+        let mut agents: Vec<RandomAgent<SmallRng>> =
+            (0..3).map(|seed| RandomAgent { rng: SmallRng::seed_from_u64(seed) }).collect();
+        let mut seats: Vec<&mut dyn Agent<ThreePlayerGame, 16>> =
+            agents.iter_mut().map(|agent| agent as &mut dyn Agent<ThreePlayerGame, 16>).collect();
+        let results = evaluate_seats(&mut seats, 6, 20, 0.0, false);
+        assert_eq!(results.wins.len(), 3);
+        assert_eq!(results.wins.iter().sum::<usize>() + results.draws, 6);
+    }
+
+    #[test]
+    fn test_augmented_consistent() {
+        let game = play(&[(0, 0), (1, 0), (3, 1)]);
+        let (aug_games, _) = game.augmented_with_raw_policy(&RawPolicy::new([1.0; 16]));
+        assert_eq!(aug_games.len(), 8);
+        assert!(aug_games.iter().all(|g| g.canonical().0 == game.canonical().0));
+    }
+}
@@ -0,0 +1,412 @@
+use std::fmt;
+
+use crate::small_board::{Board as SmallBoard, Position3, XOPlayer};
+use crate::symmetry::XOSymmetry;
+use sigmazero::{
+    game::{Game, GameError, GameStatus, PositionList},
+    policy::RawPolicy,
+};
+
+/// Plain 3x3 tic-tac-toe, mostly as a small reference and smoke test for sigmazero
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TicTacToe {
+    board: SmallBoard,
+    status: GameStatus<XOPlayer>,
+}
+
+impl Game<9> for TicTacToe {
+    const FEATURES_SHAPE: &'static [i64] = &[2, 3, 3];
+    const FEATURES_SIZE: i64 = 2 * 3 * 3;
+
+    type Player = XOPlayer;
+    type Position = Position3;
+    type Symmetry = XOSymmetry;
+
+    fn take_turn(
+        &mut self,
+        position: &Position3,
+    ) -> Result<GameStatus<XOPlayer>, GameError<Position3>> {
+        let current_player = match self.status {
+            GameStatus::InProgress { player } => player,
+            _ => return Err(GameError::GameOver),
+        };
+
+        if !position.is_valid() || self.board.get_cell(position).is_some() {
+            return Err(GameError::InvalidMove {
+                position: *position,
+            });
+        }
+
+        self.board.set_cell(position, current_player);
+
+        self.status = if let Some(winner) = self.board.winner() {
+            GameStatus::Won { player: winner }
+        } else if self.board.is_full() {
+            GameStatus::Draw
+        } else {
+            GameStatus::InProgress {
+                player: current_player.other_player(),
+            }
+        };
+
+        Ok(self.status)
+    }
+
+    fn valid_moves(&self) -> PositionList<Position3> {
+        PositionList::new(self.board.valid_moves())
+    }
+
+    fn status(&self) -> &GameStatus<XOPlayer> {
+        &self.status
+    }
+
+    fn displays(items: Vec<String>) -> impl fmt::Display {
+        TicTacToeDisplayer::new(items)
+    }
+
+    /// X moves last on a full board, so it's O's side after a draw
+    fn perspective(&self) -> XOPlayer {
+        match self.status {
+            GameStatus::InProgress { player } => player,
+            _ if self.board.count_player(XOPlayer::X) > self.board.count_player(XOPlayer::O) => XOPlayer::O,
+            _ => XOPlayer::X,
+        }
+    }
+
+    fn write_features_for(&self, player: XOPlayer, out: &mut [f32]) {
+        // [player, other player]
+        let bitboards = self.board.bitboards();
+        let features = [player, player.other_player()]
+            .into_iter()
+            .flat_map(|player| (0..9).map(move |i| ((bitboards[player as usize] >> i) & 1) as f32));
+        for (out, feature) in out.iter_mut().zip(features) {
+            *out = feature;
+        }
+    }
+
+    fn augmented_with_raw_policy(&self, raw_policy: &RawPolicy<9>) -> (Vec<Self>, Vec<RawPolicy<9>>) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| {
+                (
+                    Self {
+                        board: self.board.transformed(symmetry),
+                        status: self.status,
+                    },
+                    Self::transform_raw_policy(raw_policy, symmetry),
+                )
+            })
+            .unzip()
+    }
+
+    fn canonical(&self) -> (Self, XOSymmetry) {
+        XOSymmetry::ALL
+            .iter()
+            .map(|&symmetry| {
+                (
+                    Self {
+                        board: self.board.transformed(symmetry),
+                        status: self.status,
+                    },
+                    symmetry,
+                )
+            })
+            .min_by_key(|(game, _)| game.board.bitboards())
+            .unwrap()
+    }
+
+    fn transform_raw_policy(raw_policy: &RawPolicy<9>, symmetry: XOSymmetry) -> RawPolicy<9> {
+        let mut transformed = [0.0f32; 9];
+        for (i, p) in raw_policy.iter().enumerate() {
+            transformed[symmetry.permute_small_cell(i)] = *p;
+        }
+        RawPolicy::new(transformed)
+    }
+}
+
+impl fmt::Display for TicTacToe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.board)
+    }
+}
+
+pub struct TicTacToeDisplayer {
+    items: Vec<String>,
+}
+
+impl TicTacToeDisplayer {
+    pub fn new(items: Vec<String>) -> Self {
+        if items.len() != 9 {
+            panic!("Displayer expects 9 items, got {}", items.len())
+        }
+        Self { items }
+    }
+}
+
+impl fmt::Display for TicTacToeDisplayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..3 {
+            for x in 0..3 {
+                write!(f, "{}", self.items[x + 3 * y])?;
+                if x < 2 {
+                    write!(f, "|")?;
+                }
+            }
+            if y < 2 {
+                write!(f, "\n{}", "-".repeat(11))?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policies::RandomAgent;
+    #[cfg(feature = "tch")]
+    use crate::policies::TicTacToeNNAgent;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    #[cfg(feature = "tch")]
+    use sigmazero::data::ReplayBufferTensorData;
+    #[cfg(feature = "tch")]
+    use sigmazero::evaluate::evaluate_agents;
+    #[cfg(feature = "tch")]
+    use sigmazero::learning::train_on_replay;
+    use itertools::Itertools;
+    use sigmazero::mcts::{self_play, MCTS};
+    #[cfg(feature = "tch")]
+    use sigmazero::policy::NNAgent;
+    use sigmazero::policy::{Agent, Wdl};
+    #[cfg(feature = "tch")]
+    use tch::nn;
+
+    #[test]
+    fn test_perspective_of_finished_games() {
+        let mut game = TicTacToe::default();
+        for (x, y) in [(0, 0), (1, 0), (2, 0), (1, 1), (0, 1), (2, 1), (1, 2), (0, 2), (2, 2)] {
+            assert_eq!(Some(game.perspective()), game.to_move());
+            game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        assert!(matches!(game.status(), GameStatus::Draw));
+        assert_eq!(game.perspective(), XOPlayer::O);
+
+        let mut game = TicTacToe::default();
+        for (x, y) in [(0, 0), (1, 0), (1, 1), (2, 0), (2, 2)] {
+            game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        assert_eq!(game.perspective(), XOPlayer::O);
+        // Defined for the final position like any other
+        let mut features = [0.0; 18];
+        game.write_features_for(game.perspective(), &mut features);
+    }
+
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_forward_masks_illegal_moves() {
+        let mut game = TicTacToe::default();
+        for (x, y) in [(0, 0), (1, 1), (2, 2)] {
+            game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        let agent = TicTacToeNNAgent::new(&vs);
+        let legal_mask = game.legal_mask_tensor().unsqueeze(0);
+        let (policy, _) = agent.forward(&game.features().unsqueeze(0), Some(&legal_mask), false);
+        let policy: Vec<f32> = policy.get(0).try_into().unwrap();
+        for (p, legal) in policy.iter().zip(game.legal_mask()) {
+            assert_eq!(*p > 0.0, legal);
+        }
+        assert!((policy.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+
+        // And a policy with nothing on the legal moves falls back to uniform
+        let masked = RawPolicy::new([0.0; 9]).mask_policy(&game);
+        assert!(masked.into_iter().all(|(_, p)| (p - 1.0 / 6.0).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_take_turn() {
+        let mut game = TicTacToe::default();
+        for (x, y) in [(0, 0), (1, 0), (1, 1), (2, 0)] {
+            game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        assert!(matches!(
+            game.take_turn(&Position3::new(1, 1)),
+            Err(GameError::InvalidMove { .. })
+        ));
+        assert_eq!(game.valid_moves().len(), 5);
+        let status = game.take_turn(&Position3::new(2, 2)).unwrap();
+        assert!(matches!(status, GameStatus::Won { player: XOPlayer::X }));
+        assert!(matches!(
+            game.take_turn(&Position3::new(0, 2)),
+            Err(GameError::GameOver)
+        ));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut game = TicTacToe::default();
+        let mut status = *game.status();
+        for (x, y) in [(0, 0), (1, 1), (2, 2), (1, 0), (1, 2), (0, 2), (2, 0), (2, 1), (0, 1)] {
+            status = game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        assert!(matches!(status, GameStatus::Draw));
+    }
+
+    #[test]
+    fn test_augmented_consistent() {
+        let mut game = TicTacToe::default();
+        game.take_turn(&Position3::new(1, 0)).unwrap();
+        game.take_turn(&Position3::new(2, 2)).unwrap();
+        let mut policy = [0.0; 9];
+        for (i, p) in policy.iter_mut().enumerate() {
+            *p = i as f32;
+        }
+        let (aug_games, aug_policies) = game.augmented_with_raw_policy(&RawPolicy::new(policy));
+        for ((symmetry, aug_game), aug_policy) in XOSymmetry::ALL.into_iter().zip(aug_games).zip(aug_policies) {
+            for (i, p) in policy.iter().enumerate() {
+                let position = Position3::from(i);
+                let transformed = Position3::from(symmetry.permute_small_cell(i));
+                assert_eq!(aug_game.board.get_cell(&transformed), game.board.get_cell(&position));
+                assert_eq!(aug_policy[usize::from(transformed)], *p);
+            }
+            assert!(aug_game.canonical().0 == game.canonical().0);
+        }
+    }
+
+    #[test]
+    fn test_search_takes_winning_move() {
+        // X to move with a row to complete and O threatening another
+        let mut game = TicTacToe::default();
+        for (x, y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            game.take_turn(&Position3::new(x, y)).unwrap();
+        }
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let mut mcts = MCTS::<TicTacToe, _, 9>::from_root_game_state(game, &mut random_agent);
+        for _ in 0..200 {
+            let node_chain = mcts.select();
+            let value = mcts.expand(*node_chain.last().unwrap());
+            mcts.backup(node_chain, value);
+        }
+        let (best_child, _) = mcts.select_best_child();
+        assert!(matches!(
+            best_child.game_state.status(),
+            GameStatus::Won { player: XOPlayer::X }
+        ));
+    }
+
+    #[test]
+    fn test_self_play_values_for_player_to_move() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(1) };
+        let replay = self_play::<TicTacToe, _, 9>(&mut random_agent, 1, 50, 0.0, false);
+        // The last mover can't have lost, and the other player sees the opposite result
+        assert!(*replay.values.last().unwrap() >= 0.0);
+        for ((game, value), (next_game, next_value)) in replay.games.iter().zip(&replay.values).tuple_windows() {
+            assert_ne!(game.to_move(), next_game.to_move());
+            assert_eq!(*value, -next_value);
+        }
+    }
+
+    #[test]
+    fn test_self_play_records_outcomes() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(2) };
+        let replay = self_play::<TicTacToe, _, 9>(&mut random_agent, 3, 50, 0.0, false);
+        assert_eq!(replay.outcomes.len(), replay.len());
+        for (outcome, value) in replay.outcomes.iter().zip(&replay.values) {
+            assert_eq!(outcome.win + outcome.draw + outcome.loss, 1.0);
+            assert_eq!(outcome.expected_score(0.0), *value);
+        }
+    }
+
+    #[test]
+    fn test_draw_contempt() {
+        let wdl = Wdl { win: 0.2, draw: 0.5, loss: 0.3 };
+        assert!((wdl.expected_score(0.0) + 0.1).abs() < 1e-6);
+        assert!((wdl.expected_score(0.4) + 0.3).abs() < 1e-6);
+        let values = wdl.values_for(XOPlayer::O);
+        assert!((values[XOPlayer::O as usize] + 0.1).abs() < 1e-6);
+        assert!((values[XOPlayer::X as usize] - 0.1).abs() < 1e-6);
+    }
+
+    /// Sees a dead draw once X holds the top left corner, and otherwise a game X is
+    /// slightly behind in
+    struct CornerAgent;
+
+    impl Agent<TicTacToe, 9> for CornerAgent {
+        fn eval_game(&mut self, game: &TicTacToe) -> (RawPolicy<9>, f32) {
+            let (policy, wdl) = self.eval_game_wdl(game).unwrap();
+            (policy, wdl.expected_score(0.0))
+        }
+
+        #[cfg(feature = "tch")]
+        fn eval_features(&mut self, _: &tch::Tensor) -> (RawPolicy<9>, f32) {
+            unimplemented!("CornerAgent reads games, not features")
+        }
+
+        fn eval_game_wdl(&mut self, game: &TicTacToe) -> Option<(RawPolicy<9>, Wdl)> {
+            let wdl = if game.board.get_cell(&Position3::new(0, 0)) == Some(XOPlayer::X) {
+                Wdl { win: 0.0, draw: 1.0, loss: 0.0 }
+            } else if game.to_move() == Some(XOPlayer::X) {
+                Wdl { win: 0.45, draw: 0.0, loss: 0.55 }
+            } else {
+                Wdl { win: 0.55, draw: 0.0, loss: 0.45 }
+            };
+            Some((RawPolicy::new([1.0; 9]), wdl))
+        }
+    }
+
+    fn corner_search(draw_contempt: f32) -> TicTacToe {
+        let mut agent = CornerAgent;
+        let mut mcts = MCTS::<TicTacToe, _, 9>::from_root_game_state(TicTacToe::default(), &mut agent)
+            .with_draw_contempt(draw_contempt);
+        for _ in 0..100 {
+            let node_chain = mcts.select();
+            let evaluation = mcts.expand(*node_chain.last().unwrap());
+            mcts.backup(node_chain, evaluation);
+        }
+        mcts.select_best_child().0.game_state
+    }
+
+    #[test]
+    fn test_draw_contempt_changes_move() {
+        let corner = Position3::new(0, 0);
+        // A sure draw beats a slightly losing game, until draws cost enough
+        assert_eq!(corner_search(0.0).board.get_cell(&corner), Some(XOPlayer::X));
+        assert_eq!(corner_search(0.5).board.get_cell(&corner), None);
+
+        // Only the side searching pays for draws
+        let mut game = TicTacToe::default();
+        game.take_turn(&corner).unwrap();
+        let mut agent = CornerAgent;
+        let mut mcts = MCTS::<TicTacToe, _, 9>::from_root_game_state(game, &mut agent).with_draw_contempt(0.5);
+        let evaluation = mcts.expand(mcts.select()[0]);
+        assert_eq!(evaluation.value_for(XOPlayer::O), -0.5);
+        assert_eq!(evaluation.value_for(XOPlayer::X), 0.0);
+    }
+
+    /// Self-play, train and evaluate end to end: the trained agent should never lose to
+    /// a random one, whichever side it plays.
+    #[test]
+    #[cfg(feature = "tch")]
+    fn test_learns_to_never_lose() {
+        let device = tch::Device::Cpu;
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<TicTacToe, _, 9>(&mut random_agent, 200, 200, 0.0, false);
+        let replay_data: ReplayBufferTensorData = replay.deduplicated().augmented().into();
+
+        let vs = nn::VarStore::new(device);
+        train_on_replay::<TicTacToeNNAgent, TicTacToe, 9>(&vs, &replay_data, 64, 30, 0.9);
+        let path = std::env::temp_dir().join("tictactoe_never_lose.ot");
+        vs.save(&path).expect("Save Failed");
+
+        let mut vs = nn::VarStore::new(device);
+        let mut agent = TicTacToeNNAgent::new(&vs);
+        vs.load(&path).expect("Model load failed");
+
+        let as_first = evaluate_agents(&mut agent, &mut random_agent, 20, 100, 0.0, false);
+        assert_eq!(as_first.agent2_wins, 0, "{:?}", as_first);
+        let as_second = evaluate_agents(&mut random_agent, &mut agent, 20, 100, 0.0, false);
+        assert_eq!(as_second.agent1_wins, 0, "{:?}", as_second);
+    }
+}
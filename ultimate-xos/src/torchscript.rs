@@ -0,0 +1,279 @@
+use std::marker::PhantomData;
+use std::path::Path;
+
+use crate::game::XOGame;
+use crate::policies::{value_score, wdl_from_value};
+use sigmazero::checkpoint::Manifest;
+use sigmazero::game::{DefaultFeatures, FeatureEncoder, Game};
+use sigmazero::policy::{masked_softmax, Agent, NNAgent, RawPolicy, Wdl};
+use tch::{CModule, IValue, Kind, TchError, Tensor};
+
+/// Runs a TorchScript network, e.g. one written and trained in PyTorch, as an XO agent.
+///
+/// The module's `forward` takes one float tensor of features, `[B, ..E::SHAPE]`, which
+/// is `[B, 3, 9, 9]` for `XOGame::features`. It returns a tuple that starts with
+/// - `[B, 81]` policy logits in `XOPosition` order, which the agent masks to the legal
+///   moves and softmaxes
+/// - `[B, 1]` values in [-1, 1], or `[B, 3]` win, draw and loss probabilities, for the
+///   player the features are seen from
+///
+/// A third output of UTF-8 bytes is read as the network's `Manifest`, which
+/// `export_torchscript` adds. Anything after that is ignored.
+pub struct TorchScriptAgent<E = DefaultFeatures> {
+    module: CModule,
+    manifest: Option<Manifest>,
+    /// Whether the values are win, draw and loss probabilities rather than one score
+    wdl_head: bool,
+    device: tch::Device,
+    encoder: PhantomData<E>,
+}
+
+impl<E: FeatureEncoder<XOGame, 81>> TorchScriptAgent<E> {
+    /// Loads the module at `path`, failing if it carries a manifest for other features
+    pub fn load(path: &Path, device: tch::Device) -> Result<Self, TchError> {
+        let mut module = CModule::load_on_device(path, device)?;
+        module.set_eval();
+        let example = E::encode_batch(&[XOGame::default()]).to_device(device);
+        let output = tch::no_grad(|| module.forward_is(&[IValue::Tensor(example)]))?;
+        let manifest = read_manifest(&output)?;
+        if let Some(manifest) = &manifest {
+            manifest.check_features::<E, XOGame, 81>()?;
+        }
+        let (_, value) = read_outputs(output, 1)?;
+        Ok(Self {
+            module,
+            manifest,
+            wdl_head: value.size()[1] == 3,
+            device,
+            encoder: PhantomData,
+        })
+    }
+
+    /// What the module says about itself, if it was exported with a manifest
+    pub fn manifest(&self) -> Option<&Manifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Policies and values for a batch of features, failing if the module doesn't
+    /// keep to the contract
+    pub fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>) -> Result<(Tensor, Tensor), TchError> {
+        let output = tch::no_grad(|| self.module.forward_is(&[IValue::Tensor(xs.to_kind(Kind::Float))]))?;
+        let (logits, value) = read_outputs(output, xs.size()[0])?;
+        Ok((masked_softmax(&logits, legal_mask), value))
+    }
+
+    fn eval_batch(&self, xs: &Tensor, legal_mask: Option<&Tensor>) -> Vec<(RawPolicy<81>, Vec<f32>)> {
+        let (policies, values) = self
+            .forward(&xs.to_device(self.device), legal_mask.map(|mask| mask.to_device(self.device)).as_ref())
+            .expect("TorchScript module broke the agent contract");
+        let policies: Vec<Vec<f32>> = policies.try_into().expect("Policy conversion from tensor to vec failed!");
+        let values: Vec<Vec<f32>> = values.try_into().expect("Value conversion from tensor to vec failed!");
+        policies
+            .into_iter()
+            .zip(values)
+            .map(|(policy, value)| {
+                let policy_arr: [f32; 81] = policy.try_into().expect("Policy conversion from vec to array failed!");
+                (RawPolicy::new(policy_arr), value)
+            })
+            .collect()
+    }
+
+    fn eval_game_raw(&self, game: &XOGame) -> (RawPolicy<81>, Vec<f32>) {
+        let games = [*game];
+        self.eval_batch(&E::encode_batch(&games), Some(&XOGame::legal_masks(&games)))
+            .pop()
+            .unwrap()
+    }
+}
+
+/// The manifest after the policy logits and values of `output`, if there is one
+fn read_manifest(output: &IValue) -> Result<Option<Manifest>, TchError> {
+    match output {
+        IValue::Tuple(outputs) | IValue::GenericList(outputs) => match outputs.get(2) {
+            Some(IValue::Tensor(bytes)) => String::from_utf8(Vec::<u8>::try_from(bytes)?)
+                .map_err(|e| TchError::Convert(e.to_string()))?
+                .parse()
+                .map(Some)
+                .map_err(TchError::from),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Checks that `output` starts with policy logits and values for `batch_size` positions
+fn read_outputs(output: IValue, batch_size: i64) -> Result<(Tensor, Tensor), TchError> {
+    let outputs = match output {
+        IValue::Tuple(outputs) | IValue::GenericList(outputs) => outputs,
+        other => {
+            return Err(TchError::Convert(format!(
+                "TorchScript module returned {:?}, expected a tuple of policy logits and values",
+                other
+            )))
+        }
+    };
+    let mut outputs = outputs.into_iter();
+    match (outputs.next(), outputs.next()) {
+        (Some(IValue::Tensor(logits)), Some(IValue::Tensor(value))) => {
+            if logits.size() != [batch_size, 81] {
+                return Err(TchError::Shape(format!(
+                    "policy logits have shape {:?}, expected [{}, 81]",
+                    logits.size(),
+                    batch_size
+                )));
+            }
+            if value.size() != [batch_size, 1] && value.size() != [batch_size, 3] {
+                return Err(TchError::Shape(format!(
+                    "values have shape {:?}, expected [{}, 1] or [{}, 3]",
+                    value.size(),
+                    batch_size,
+                    batch_size
+                )));
+            }
+            Ok((logits.to_kind(Kind::Float), value.to_kind(Kind::Float)))
+        }
+        _ => Err(TchError::Convert(
+            "TorchScript module should return policy logits and values as its first two outputs".to_string(),
+        )),
+    }
+}
+
+impl<E: FeatureEncoder<XOGame, 81>> Agent<XOGame, 81> for TorchScriptAgent<E> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        let (policy, value) = self.eval_game_raw(game);
+        (policy, value_score(&value))
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        let (policy, value) = self.eval_batch(&features.unsqueeze(0), None).pop().unwrap();
+        (policy, value_score(&value))
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        self.wdl_head.then(|| {
+            let (policy, value) = self.eval_game_raw(game);
+            (policy, wdl_from_value(&value))
+        })
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        self.eval_batch(&E::encode_batch(games), Some(&XOGame::legal_masks(games)))
+            .into_iter()
+            .map(|(policy, value)| (policy, value_score(&value)))
+            .collect()
+    }
+}
+
+/// Traces `agent` into a TorchScript module at `path` that `TorchScriptAgent` and
+/// tools outside this repository can run. The module keeps to the `TorchScriptAgent`
+/// contract, with `manifest` as its third output so the file describes its features
+/// and where the weights came from.
+pub fn export_torchscript<A: NNAgent<XOGame, 81>>(
+    agent: &A,
+    manifest: &Manifest,
+    path: &Path,
+    device: tch::Device,
+) -> Result<(), TchError> {
+    manifest.check::<A, XOGame, 81>()?;
+    let example = A::Encoder::encode_batch(&[XOGame::default()]).to_device(device);
+    let manifest_bytes = Tensor::from_slice(manifest.to_string().as_bytes());
+    let module = tch::no_grad(|| {
+        CModule::create_by_tracing("XONetwork", "forward", &[example], &mut |inputs| {
+            let (policy, value) = agent.forward(&inputs[0], None, false);
+            // Log-probabilities are logits that softmax back to the same policy, and
+            // to the same masked one
+            let policy_logits = policy.clamp_min(f64::from(f32::MIN_POSITIVE)).log();
+            // Tracing only keeps outputs that come from the inputs, so the manifest is
+            // offset by a zero that does
+            let zero = inputs[0].zeros_like().sum(Kind::Uint8);
+            vec![policy_logits, value, &manifest_bytes + zero]
+        })
+    })?;
+    module.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::XORichFeatures;
+    use crate::policies::XONNAgent;
+    use crate::resnet::ResNetConfig;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use tch::nn;
+
+    fn outputs(policy_shape: [i64; 2], value_shape: [i64; 2]) -> IValue {
+        let options = (Kind::Float, tch::Device::Cpu);
+        IValue::Tuple(vec![
+            IValue::Tensor(Tensor::zeros(policy_shape, options)),
+            IValue::Tensor(Tensor::zeros(value_shape, options)),
+        ])
+    }
+
+    #[test]
+    fn test_output_contract() {
+        assert!(read_outputs(outputs([2, 81], [2, 1]), 2).is_ok());
+        assert!(read_outputs(outputs([2, 81], [2, 3]), 2).is_ok());
+        assert!(read_outputs(outputs([2, 9], [2, 1]), 2).is_err());
+        assert!(read_outputs(outputs([2, 81], [2, 2]), 2).is_err());
+        assert!(read_outputs(outputs([1, 81], [1, 1]), 2).is_err());
+        assert!(read_outputs(IValue::Tensor(Tensor::zeros([2, 81], (Kind::Float, tch::Device::Cpu))), 2).is_err());
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let device = tch::Device::Cpu;
+        let vs = nn::VarStore::new(device);
+        let config = ResNetConfig { blocks: 2, filters: 16, squeeze_excitation: true };
+        let mut agent = XONNAgent::<DefaultFeatures, true>::with_config(&vs, config);
+        let manifest = Manifest { generation: 2, ..Manifest::new::<XONNAgent<DefaultFeatures, true>, XOGame, 81>(&vs) };
+        let path = std::env::temp_dir().join("xo_export_test.pt");
+        export_torchscript(&agent, &manifest, &path, device).expect("Export failed");
+
+        let mut exported = TorchScriptAgent::<DefaultFeatures>::load(&path, device).expect("Module load failed");
+        assert_eq!(exported.manifest(), Some(&manifest));
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut games = vec![XOGame::default()];
+        for _ in 0..6 {
+            let mut game = *games.last().unwrap();
+            let moves = game.valid_moves();
+            game.take_turn(&moves[rng.gen_range(0..moves.len())]).unwrap();
+            games.push(game);
+        }
+        let close = |(p1, v1): (RawPolicy<81>, f32), (p2, v2): (RawPolicy<81>, f32)| {
+            (v1 - v2).abs() < 1e-5 && p1.iter().zip(p2.iter()).all(|(a, b)| (a - b).abs() < 1e-5)
+        };
+        for game in &games {
+            assert!(close(agent.eval_game(game), exported.eval_game(game)));
+            let (_, wdl) = agent.eval_game_wdl(game).unwrap();
+            let (_, exported_wdl) = exported.eval_game_wdl(game).unwrap();
+            assert!((wdl.draw - exported_wdl.draw).abs() < 1e-5);
+        }
+        // Tracing leaves the batch size free
+        for (expected, actual) in agent.eval_games(&games).into_iter().zip(exported.eval_games(&games)) {
+            assert!(close(expected, actual));
+        }
+
+        // The manifest keeps the module from being fed other features
+        assert!(TorchScriptAgent::<XORichFeatures>::load(&path, device).is_err());
+    }
+
+    #[test]
+    fn test_scalar_head_has_no_wdl() {
+        let device = tch::Device::Cpu;
+        let vs = nn::VarStore::new(device);
+        let agent = XONNAgent::<DefaultFeatures>::new(&vs);
+        let manifest = Manifest::new::<XONNAgent, XOGame, 81>(&vs);
+        let path = std::env::temp_dir().join("xo_export_scalar_test.pt");
+        export_torchscript(&agent, &manifest, &path, device).expect("Export failed");
+
+        let mut exported = TorchScriptAgent::<DefaultFeatures>::load(&path, device).expect("Module load failed");
+        assert!(exported.eval_game_wdl(&XOGame::default()).is_none());
+    }
+
+    #[test]
+    fn test_load_missing_module() {
+        let path = std::env::temp_dir().join("no_such_module.pt");
+        assert!(TorchScriptAgent::<DefaultFeatures>::load(&path, tch::Device::Cpu).is_err());
+    }
+}
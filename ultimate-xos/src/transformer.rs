@@ -0,0 +1,301 @@
+#[cfg(feature = "tch")]
+use std::marker::PhantomData;
+
+#[cfg(feature = "tch")]
+use crate::architecture::XOArchitecture;
+#[cfg(feature = "tch")]
+use crate::game::XOGame;
+#[cfg(feature = "tch")]
+use crate::policies::{eval_xo_game, eval_xo_games, eval_xo_single, value_score, wdl_from_value};
+#[cfg(feature = "tch")]
+use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+use sigmazero::policy::NetworkConfig;
+#[cfg(feature = "tch")]
+use sigmazero::policy::{masked_softmax, Agent, NNAgent, RawPolicy, Wdl};
+#[cfg(feature = "tch")]
+use tch::{nn, Tensor};
+
+/// The class token followed by one token per cell
+#[cfg(feature = "tch")]
+const TOKENS: i64 = 82;
+
+/// Sizes of the transformer encoder, stored in the `VarStore` like `ResNetConfig`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransformerConfig {
+    pub layers: usize,
+    /// Width of every token, split evenly between the heads
+    pub dim: i64,
+    pub heads: i64,
+    pub mlp_dim: i64,
+}
+
+impl Default for TransformerConfig {
+    fn default() -> Self {
+        Self {
+            layers: 4,
+            dim: 64,
+            heads: 4,
+            mlp_dim: 128,
+        }
+    }
+}
+
+impl NetworkConfig for TransformerConfig {
+    fn to_vec(&self) -> Vec<f32> {
+        vec![self.layers as f32, self.dim as f32, self.heads as f32, self.mlp_dim as f32]
+    }
+
+    fn from_slice(values: &[f32]) -> Self {
+        match values {
+            [layers, dim, heads, mlp_dim] => Self {
+                layers: *layers as usize,
+                dim: *dim as i64,
+                heads: *heads as i64,
+                mlp_dim: *mlp_dim as i64,
+            },
+            _ => panic!("Expected a transformer config of 4 numbers, got {:?}", values),
+        }
+    }
+}
+
+/// Pre-norm self-attention followed by a pre-norm MLP, each around a skip connection
+#[cfg(feature = "tch")]
+#[derive(Debug)]
+struct EncoderLayer {
+    attention_norm: nn::LayerNorm,
+    qkv: nn::Linear,
+    attention_out: nn::Linear,
+    mlp_norm: nn::LayerNorm,
+    mlp_fc1: nn::Linear,
+    mlp_fc2: nn::Linear,
+    dim: i64,
+    heads: i64,
+}
+
+#[cfg(feature = "tch")]
+impl EncoderLayer {
+    fn new(path: nn::Path, config: &TransformerConfig) -> Self {
+        let dim = config.dim;
+        Self {
+            attention_norm: nn::layer_norm(&path / "attention_norm", vec![dim], Default::default()),
+            qkv: nn::linear(&path / "qkv", dim, 3 * dim, Default::default()),
+            attention_out: nn::linear(&path / "attention_out", dim, dim, Default::default()),
+            mlp_norm: nn::layer_norm(&path / "mlp_norm", vec![dim], Default::default()),
+            mlp_fc1: nn::linear(&path / "mlp_fc1", dim, config.mlp_dim, Default::default()),
+            mlp_fc2: nn::linear(&path / "mlp_fc2", config.mlp_dim, dim, Default::default()),
+            dim,
+            heads: config.heads,
+        }
+    }
+
+    /// [B, TOKENS, dim] to the same shape
+    fn forward(&self, xs: &Tensor) -> Tensor {
+        let head_dim = self.dim / self.heads;
+        // [3, B, heads, TOKENS, head_dim]
+        let qkv = xs
+            .apply(&self.attention_norm)
+            .apply(&self.qkv)
+            .reshape([-1, TOKENS, 3, self.heads, head_dim])
+            .permute([2, 0, 3, 1, 4]);
+        let (queries, keys, values) = (qkv.get(0), qkv.get(1), qkv.get(2));
+        let weights = (queries.matmul(&keys.transpose(-2, -1)) / (head_dim as f64).sqrt())
+            .softmax(-1, tch::Kind::Float);
+        let attended = weights
+            .matmul(&values)
+            .transpose(1, 2)
+            .reshape([-1, TOKENS, self.dim])
+            .apply(&self.attention_out);
+        let xs = xs + attended;
+
+        let ys = xs
+            .apply(&self.mlp_norm)
+            .apply(&self.mlp_fc1)
+            .gelu("none")
+            .apply(&self.mlp_fc2);
+        xs + ys
+    }
+}
+
+/// A transformer encoder over the 81 cells as tokens. Each cell's position is the sum
+/// of a learned embedding of its small board and one of its cell within that board,
+/// and an extra class token gathers what the value head reads.
+#[cfg(feature = "tch")]
+pub struct XOTransformerAgent<E = DefaultFeatures, const WDL: bool = false> {
+    input_fc: nn::Linear,
+    board_embedding: Tensor,
+    cell_embedding: Tensor,
+    class_token: Tensor,
+    layers: Vec<EncoderLayer>,
+    final_norm: nn::LayerNorm,
+    policy_fc: nn::Linear,
+    value_fc1: nn::Linear,
+    value_fc2: nn::Linear,
+    dim: i64,
+    device: tch::Device,
+    encoder: PhantomData<E>,
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> XOTransformerAgent<E, WDL> {
+    const VALUE_SIZE: i64 = if WDL { 3 } else { 1 };
+
+    /// Builds the network described by `config`, which is recorded in `vs`
+    pub fn with_config(vs: &nn::VarStore, config: TransformerConfig) -> Self {
+        assert_eq!(config.dim % config.heads, 0, "{:?} doesn't split evenly between its heads", config);
+        XOArchitecture::Transformer.store(vs);
+        config.store(vs);
+        let root = &vs.root();
+        let dim = config.dim;
+        Self {
+            input_fc: nn::linear(root / "input_fc", E::SHAPE[0], dim, Default::default()),
+            board_embedding: root.randn("board_embedding", &[3, 1, 3, 1, dim], 0.0, 0.02),
+            cell_embedding: root.randn("cell_embedding", &[1, 3, 1, 3, dim], 0.0, 0.02),
+            class_token: root.randn("class_token", &[1, 1, dim], 0.0, 0.02),
+            layers: (0..config.layers)
+                .map(|i| EncoderLayer::new(root / "layers" / i, &config))
+                .collect(),
+            final_norm: nn::layer_norm(root / "final_norm", vec![dim], Default::default()),
+            policy_fc: nn::linear(root / "policy_fc", dim, 1, Default::default()),
+            value_fc1: nn::linear(root / "value_fc1", dim, 64, Default::default()),
+            value_fc2: nn::linear(root / "value_fc2", 64, Self::VALUE_SIZE, Default::default()),
+            dim,
+            device: vs.device(),
+            encoder: PhantomData,
+        }
+    }
+
+    /// [81, dim] positional embeddings in `XOPosition` order, where the board and cell
+    /// embeddings broadcast over [board row, cell row, board column, cell column]
+    fn positions(&self) -> Tensor {
+        (&self.board_embedding + &self.cell_embedding).reshape([81, self.dim])
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> NNAgent<XOGame, 81> for XOTransformerAgent<E, WDL> {
+    type Encoder = E;
+    const WDL_HEAD: bool = WDL;
+
+    fn new(vs: &nn::VarStore) -> Self {
+        Self::with_config(vs, TransformerConfig::from_var_store(vs).unwrap_or_default())
+    }
+
+    fn architecture(_vs: &nn::VarStore) -> String {
+        XOArchitecture::Transformer.name().to_string()
+    }
+
+    fn builds_architecture(architecture: &str) -> bool {
+        architecture == XOArchitecture::Transformer.name()
+    }
+
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, _train: bool) -> (Tensor, Tensor) {
+        let cells = xs
+            .reshape([-1, E::SHAPE[0], 81])
+            .permute([0, 2, 1])
+            .apply(&self.input_fc)
+            + self.positions();
+        // Broadcast from a slice of the input rather than its size, which tracing would
+        // record as a constant
+        let class_token = cells.narrow(1, 0, 1).zeros_like() + &self.class_token;
+        let mut tokens = Tensor::cat(&[&class_token, &cells], 1);
+        for layer in &self.layers {
+            tokens = layer.forward(&tokens);
+        }
+        let tokens = tokens.apply(&self.final_norm);
+
+        let policy_logits = tokens
+            .narrow(1, 1, 81)
+            .apply(&self.policy_fc)
+            .reshape([-1, 81]);
+        let policy = masked_softmax(&policy_logits, legal_mask);
+
+        let value = tokens
+            .select(1, 0)
+            .apply(&self.value_fc1)
+            .relu()
+            .apply(&self.value_fc2);
+        let value = if WDL {
+            value.softmax(-1, tch::Kind::Float)
+        } else {
+            value.tanh()
+        };
+        (policy, value)
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> Agent<XOGame, 81> for XOTransformerAgent<E, WDL> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_game(self, self.device, game);
+        (policy, value_score(&value))
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_single(self, features, None);
+        (policy, value_score(&value))
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        WDL.then(|| {
+            let (policy, value) = eval_xo_game(self, self.device, game);
+            (policy, wdl_from_value(&value))
+        })
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        eval_xo_games(self, self.device, games)
+    }
+}
+
+#[cfg(all(test, feature = "tch"))]
+mod tests {
+    use super::*;
+    use crate::board::XOPosition;
+    use crate::policies::RandomAgent;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use sigmazero::data::ReplayBufferTensorData;
+    use sigmazero::game::{Game, Position};
+    use sigmazero::learning::train_on_replay;
+    use sigmazero::mcts::self_play;
+
+    #[test]
+    fn test_positions_share_board_and_cell() {
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        let agent = <XOTransformerAgent>::new(&vs);
+        let positions = agent.positions();
+        let position = |x, y| positions.get(usize::from(XOPosition::new(x, y)) as i64);
+        // Same cell of two boards, minus two cells of the same board, leaves nothing
+        let difference = (position(0, 0) - position(3, 0)) - (position(1, 1) - position(4, 1));
+        assert!(difference.abs().max().double_value(&[]) < 1e-6);
+        assert!(!position(0, 0).equal(&position(3, 0)));
+    }
+
+    #[test]
+    fn test_forward() {
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        let agent = <XOTransformerAgent<DefaultFeatures, true>>::new(&vs);
+        let mut game = XOGame::default();
+        game.take_turn(&XOPosition::new(4, 4)).unwrap();
+        let games = [XOGame::default(), game];
+        let features = DefaultFeatures::encode_batch(&games);
+        let legal_mask = XOGame::legal_masks(&games);
+        let (policy, value) = agent.forward(&features, Some(&legal_mask), false);
+        assert_eq!(policy.size(), [2, 81]);
+        assert_eq!(value.size(), [2, 3]);
+        let illegal = policy.masked_select(&legal_mask.logical_not());
+        assert_eq!(illegal.sum(tch::Kind::Float).double_value(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_trains_on_replay() {
+        let mut random_agent = RandomAgent { rng: SmallRng::seed_from_u64(0) };
+        let replay = self_play::<XOGame, _, 81>(&mut random_agent, 2, 10, 0.0, false);
+        let replay_data = ReplayBufferTensorData::encode::<DefaultFeatures, _, 81>(replay);
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        let config = TransformerConfig { layers: 1, dim: 16, heads: 2, mlp_dim: 32 };
+        config.store(&vs);
+        train_on_replay::<XOTransformerAgent, XOGame, 81>(&vs, &replay_data, 8, 1, 0.8);
+        assert_eq!(TransformerConfig::from_var_store(&vs), Some(config));
+    }
+}
@@ -0,0 +1,243 @@
+#[cfg(feature = "tch")]
+use std::marker::PhantomData;
+
+#[cfg(feature = "tch")]
+use crate::architecture::XOArchitecture;
+#[cfg(feature = "tch")]
+use crate::game::XOGame;
+#[cfg(feature = "tch")]
+use crate::policies::{eval_xo_game, eval_xo_games, eval_xo_single, value_score, wdl_from_value};
+#[cfg(feature = "tch")]
+use sigmazero::game::{DefaultFeatures, FeatureEncoder};
+use sigmazero::policy::NetworkConfig;
+#[cfg(feature = "tch")]
+use sigmazero::policy::{masked_softmax, Agent, NNAgent, RawPolicy, Wdl};
+#[cfg(feature = "tch")]
+use tch::{nn, Tensor};
+
+/// Sizes of the two-level network, stored in the `VarStore` like `ResNetConfig`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TwoLevelConfig {
+    /// Size of the embedding each small board is encoded to
+    pub embedding: i64,
+    pub meta_filters: i64,
+    pub meta_layers: usize,
+}
+
+impl Default for TwoLevelConfig {
+    fn default() -> Self {
+        Self {
+            embedding: 32,
+            meta_filters: 64,
+            meta_layers: 2,
+        }
+    }
+}
+
+impl NetworkConfig for TwoLevelConfig {
+    fn to_vec(&self) -> Vec<f32> {
+        vec![self.embedding as f32, self.meta_filters as f32, self.meta_layers as f32]
+    }
+
+    fn from_slice(values: &[f32]) -> Self {
+        match values {
+            [embedding, meta_filters, meta_layers] => Self {
+                embedding: *embedding as i64,
+                meta_filters: *meta_filters as i64,
+                meta_layers: *meta_layers as usize,
+            },
+            _ => panic!("Expected a two-level config of 3 numbers, got {:?}", values),
+        }
+    }
+}
+
+/// Follows the structure of the game rather than the flat 9x9 grid: every small board
+/// is embedded by the same sub-network, convolutions reason over the 3x3 meta board
+/// of embeddings, and each cell's logit is decoded from its own board's embedding and
+/// that board's meta context. The value is read off the whole meta board.
+#[cfg(feature = "tch")]
+pub struct XOTwoLevelAgent<E = DefaultFeatures, const WDL: bool = false> {
+    board_fc1: nn::Linear,
+    board_fc2: nn::Linear,
+    meta_convs: Vec<(nn::Conv2D, nn::BatchNorm)>,
+    cell_fc: nn::Linear,
+    value_fc1: nn::Linear,
+    value_fc2: nn::Linear,
+    embedding: i64,
+    meta_filters: i64,
+    device: tch::Device,
+    encoder: PhantomData<E>,
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> XOTwoLevelAgent<E, WDL> {
+    const VALUE_SIZE: i64 = if WDL { 3 } else { 1 };
+
+    /// Builds the network described by `config`, which is recorded in `vs`
+    pub fn with_config(vs: &nn::VarStore, config: TwoLevelConfig) -> Self {
+        XOArchitecture::TwoLevel.store(vs);
+        config.store(vs);
+        let root = &vs.root();
+        let board_features = E::SHAPE[0] * 9;
+        let conv_config = nn::ConvConfig { padding: 1, bias: false, ..Default::default() };
+        let meta_convs = (0..config.meta_layers)
+            .map(|i| {
+                let in_channels = if i == 0 { config.embedding } else { config.meta_filters };
+                (
+                    nn::conv2d(root / "meta_conv" / i, in_channels, config.meta_filters, 3, conv_config),
+                    nn::batch_norm2d(root / "meta_bn" / i, config.meta_filters, Default::default()),
+                )
+            })
+            .collect();
+        // Without meta layers the context is the embedding itself
+        let meta_filters = if config.meta_layers == 0 { config.embedding } else { config.meta_filters };
+        Self {
+            board_fc1: nn::linear(root / "board_fc1", board_features, config.embedding, Default::default()),
+            board_fc2: nn::linear(root / "board_fc2", config.embedding, config.embedding, Default::default()),
+            meta_convs,
+            cell_fc: nn::linear(root / "cell_fc", config.embedding + meta_filters, 9, Default::default()),
+            value_fc1: nn::linear(root / "value_fc1", 9 * meta_filters, 64, Default::default()),
+            value_fc2: nn::linear(root / "value_fc2", 64, Self::VALUE_SIZE, Default::default()),
+            embedding: config.embedding,
+            meta_filters,
+            device: vs.device(),
+            encoder: PhantomData,
+        }
+    }
+}
+
+/// [B, C, 9, 9] planes to one row of C * 9 features per small board, [B * 9, C * 9],
+/// with the boards of each game in row-major order
+#[cfg(feature = "tch")]
+fn split_small_boards(xs: &Tensor, channels: i64) -> Tensor {
+    xs.reshape([-1, channels, 3, 3, 3, 3])
+        .permute([0, 2, 4, 1, 3, 5])
+        .reshape([-1, channels * 9])
+}
+
+/// Inverse of `split_small_boards` for one number per cell: [B * 9, 9] to [B, 81] in
+/// `XOPosition` order
+#[cfg(feature = "tch")]
+fn join_small_boards(cells: &Tensor) -> Tensor {
+    cells
+        .reshape([-1, 3, 3, 3, 3])
+        .permute([0, 1, 3, 2, 4])
+        .reshape([-1, 81])
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> NNAgent<XOGame, 81> for XOTwoLevelAgent<E, WDL> {
+    type Encoder = E;
+    const WDL_HEAD: bool = WDL;
+
+    fn new(vs: &nn::VarStore) -> Self {
+        Self::with_config(vs, TwoLevelConfig::from_var_store(vs).unwrap_or_default())
+    }
+
+    fn architecture(_vs: &nn::VarStore) -> String {
+        XOArchitecture::TwoLevel.name().to_string()
+    }
+
+    fn builds_architecture(architecture: &str) -> bool {
+        architecture == XOArchitecture::TwoLevel.name()
+    }
+
+    fn forward(&self, xs: &Tensor, legal_mask: Option<&Tensor>, train: bool) -> (Tensor, Tensor) {
+        let local = split_small_boards(xs, E::SHAPE[0])
+            .apply(&self.board_fc1)
+            .relu()
+            .apply(&self.board_fc2)
+            .relu();
+
+        let mut meta = local
+            .reshape([-1, 3, 3, self.embedding])
+            .permute([0, 3, 1, 2]);
+        for (conv, bn) in &self.meta_convs {
+            meta = meta.apply(conv).apply_t(bn, train).relu();
+        }
+
+        let context = meta.permute([0, 2, 3, 1]).reshape([-1, self.meta_filters]);
+        let cell_logits = Tensor::cat(&[&local, &context], 1).apply(&self.cell_fc);
+        let policy = masked_softmax(&join_small_boards(&cell_logits), legal_mask);
+
+        let value = meta
+            .flatten(1, -1)
+            .apply(&self.value_fc1)
+            .relu()
+            .apply(&self.value_fc2);
+        let value = if WDL {
+            value.softmax(-1, tch::Kind::Float)
+        } else {
+            value.tanh()
+        };
+        (policy, value)
+    }
+}
+
+#[cfg(feature = "tch")]
+impl<E: FeatureEncoder<XOGame, 81>, const WDL: bool> Agent<XOGame, 81> for XOTwoLevelAgent<E, WDL> {
+    fn eval_game(&mut self, game: &XOGame) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_game(self, self.device, game);
+        (policy, value_score(&value))
+    }
+
+    fn eval_features(&mut self, features: &Tensor) -> (RawPolicy<81>, f32) {
+        let (policy, value) = eval_xo_single(self, features, None);
+        (policy, value_score(&value))
+    }
+
+    fn eval_game_wdl(&mut self, game: &XOGame) -> Option<(RawPolicy<81>, Wdl)> {
+        WDL.then(|| {
+            let (policy, value) = eval_xo_game(self, self.device, game);
+            (policy, wdl_from_value(&value))
+        })
+    }
+
+    fn eval_games(&mut self, games: &[XOGame]) -> Vec<(RawPolicy<81>, f32)> {
+        eval_xo_games(self, self.device, games)
+    }
+}
+
+#[cfg(all(test, feature = "tch"))]
+mod tests {
+    use super::*;
+    use crate::board::XOPosition;
+    use crate::policies::XONNAgent;
+    use sigmazero::game::{Game, Position};
+    use sigmazero::policy::parameter_count;
+
+    #[test]
+    fn test_small_boards_round_trip() {
+        let xs = Tensor::arange(81, (tch::Kind::Float, tch::Device::Cpu)).reshape([1, 1, 9, 9]);
+        let boards = split_small_boards(&xs, 1);
+        // The centre board's first cell is at (3, 3)
+        let centre = usize::from(XOPosition::new(3, 3)) as f64;
+        assert_eq!(boards.double_value(&[4, 0]), centre);
+        assert!(join_small_boards(&boards).equal(&xs.reshape([1, 81])));
+    }
+
+    #[test]
+    fn test_forward() {
+        let vs = nn::VarStore::new(tch::Device::Cpu);
+        let agent = <XOTwoLevelAgent>::new(&vs);
+        let mut game = XOGame::default();
+        game.take_turn(&XOPosition::new(4, 4)).unwrap();
+        let games = [XOGame::default(), game];
+        let features = DefaultFeatures::encode_batch(&games);
+        let legal_mask = XOGame::legal_masks(&games);
+        let (policy, value) = agent.forward(&features, Some(&legal_mask), false);
+        assert_eq!(policy.size(), [2, 81]);
+        assert_eq!(value.size(), [2, 1]);
+        let illegal = policy.masked_select(&legal_mask.logical_not());
+        assert_eq!(illegal.sum(tch::Kind::Float).double_value(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_fewer_parameters_than_resnet() {
+        let vs_two_level = nn::VarStore::new(tch::Device::Cpu);
+        let _ = <XOTwoLevelAgent>::new(&vs_two_level);
+        let vs_resnet = nn::VarStore::new(tch::Device::Cpu);
+        let _ = <XONNAgent>::new(&vs_resnet);
+        assert!(parameter_count(&vs_two_level) < parameter_count(&vs_resnet));
+    }
+}